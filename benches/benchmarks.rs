@@ -3,10 +3,10 @@ extern crate criterion;
 
 use criterion::Criterion;
 
-use criterion::{Bencher, Benchmark, Fun};
+use criterion::BenchmarkId;
 use image::DynamicImage;
 use rustface::math::{abs, square, vector_add, vector_inner_product, vector_sub};
-use rustface::{Detector, ImageData};
+use rustface::{Detector, DetectorConfig, ImageData};
 use std::time::Duration;
 
 fn get_test_image() -> DynamicImage {
@@ -24,21 +24,23 @@ fn get_test_image() -> DynamicImage {
 
 fn get_default_detector() -> Box<dyn Detector> {
     let model_path = "./model/seeta_fd_frontal_v1.0.bin";
-    let mut detector = match rustface::create_detector(model_path) {
-        Ok(detector) => detector,
+    let model = match rustface::load_model(model_path) {
+        Ok(model) => model,
         Err(error) => {
-            println!("Failed to create detector: {}", error);
+            println!("Failed to read model: {}", error);
             std::process::exit(1)
         }
     };
 
     // configure detector
-    detector.set_min_face_size(20);
-    detector.set_score_thresh(2.0);
-    detector.set_pyramid_scale_factor(0.8);
-    detector.set_slide_window_step(4, 4);
-
-    detector
+    let config = DetectorConfig::builder()
+        .min_face_size(40)
+        .score_thresh(2.0)
+        .pyramid_scale_factor(0.8)
+        .slide_window_step(4, 4)
+        .build()
+        .unwrap();
+    rustface::create_detector_with_config(model, config).unwrap()
 }
 
 fn detect_single_image(c: &mut Criterion) {
@@ -50,17 +52,15 @@ fn detect_single_image(c: &mut Criterion) {
 
     let target_runtime = Duration::new(100, 0);
 
-    c.bench(
-        "detect_single_image",
-        Benchmark::new("detect", move |b| {
-            let test_image = ImageData::new(&img, width, height);
-            b.iter(|| detector.detect(&test_image))
-        })
-        // Limit the measurement time and the sample size
-        // to make sure the benchmark finishes in a feasible amount of time.
-        .measurement_time(target_runtime)
-        .sample_size(20),
-    );
+    let mut group = c.benchmark_group("detect_single_image");
+    // Limit the measurement time and the sample size
+    // to make sure the benchmark finishes in a feasible amount of time.
+    group.measurement_time(target_runtime).sample_size(20);
+    group.bench_function("detect", move |b| {
+        let test_image = ImageData::new(&img, width, height);
+        b.iter(|| detector.detect(&test_image))
+    });
+    group.finish();
 }
 
 fn bench_square(c: &mut Criterion) {
@@ -121,71 +121,95 @@ fn bench_vector_inner_product(c: &mut Criterion) {
 }
 
 fn bench_square_compare(c: &mut Criterion) {
-    let naive = Fun::new("naive", |b: &mut Bencher<'_>, input: &Vec<i32>| {
-        let mut target: Vec<i32> = vec![0; input.len()];
-        b.iter(|| {
-            for (i, e) in input.iter().enumerate() {
-                target[i] = e.pow(2);
-            }
-        })
-    });
-
-    let naive_iterator = Fun::new("naive_iterator", |b: &mut Bencher<'_>, input: &Vec<i32>| {
-        b.iter(|| {
-            let _target: Vec<i32> = input.iter().map(|a| a.pow(2)).collect();
-        })
-    });
-
-    let notsafe = Fun::new("unsafe", |b, input: &Vec<i32>| {
-        let mut target: Vec<u32> = vec![0; input.len()];
-        b.iter(|| {
-            square(input, &mut target[..input.len()]);
-        })
-    });
-
     let testvec_size: usize = 1000;
     let mut testvec = Vec::<i32>::with_capacity(testvec_size);
     for i in 0..testvec_size {
         testvec.push(i as i32);
     }
 
-    let functions = vec![naive, naive_iterator, notsafe];
-
-    c.bench_functions("square_comparison", functions, testvec);
-}
+    let mut group = c.benchmark_group("square_comparison");
+
+    group.bench_with_input(
+        BenchmarkId::new("naive", testvec_size),
+        &testvec,
+        |b, input| {
+            let mut target: Vec<i32> = vec![0; input.len()];
+            b.iter(|| {
+                for (i, e) in input.iter().enumerate() {
+                    target[i] = e.pow(2);
+                }
+            })
+        },
+    );
 
-fn bench_abs_compare(c: &mut Criterion) {
-    let naive = Fun::new("naive", |b: &mut Bencher<'_>, input: &Vec<i32>| {
-        let mut target: Vec<i32> = vec![0; input.len()];
-        b.iter(|| {
-            for (i, e) in input.iter().enumerate() {
-                target[i] = e.abs();
-            }
-        })
-    });
+    group.bench_with_input(
+        BenchmarkId::new("naive_iterator", testvec_size),
+        &testvec,
+        |b, input| {
+            b.iter(|| {
+                let _target: Vec<i32> = input.iter().map(|a| a.pow(2)).collect();
+            })
+        },
+    );
 
-    let naive_iterator = Fun::new("naive_iterator", |b: &mut Bencher<'_>, input: &Vec<i32>| {
-        b.iter(|| {
-            let _target: Vec<i32> = input.iter().map(|a| a.abs()).collect();
-        })
-    });
+    group.bench_with_input(
+        BenchmarkId::new("unsafe", testvec_size),
+        &testvec,
+        |b, input| {
+            let mut target: Vec<u32> = vec![0; input.len()];
+            b.iter(|| {
+                square(input, &mut target[..input.len()]);
+            })
+        },
+    );
 
-    let notsafe = Fun::new("unsafe", |b, input: &Vec<i32>| {
-        let mut target: Vec<i32> = vec![0; input.len()];
-        b.iter(|| {
-            unsafe { abs(input.as_ptr(), target.as_mut_ptr(), input.len()) };
-        })
-    });
+    group.finish();
+}
 
+fn bench_abs_compare(c: &mut Criterion) {
     let testvec_size: usize = 1000;
     let mut testvec = Vec::<i32>::with_capacity(testvec_size);
     for i in 0..testvec_size {
         testvec.push(i as i32);
     }
 
-    let functions = vec![naive, naive_iterator, notsafe];
+    let mut group = c.benchmark_group("abs_comparison");
+
+    group.bench_with_input(
+        BenchmarkId::new("naive", testvec_size),
+        &testvec,
+        |b, input| {
+            let mut target: Vec<i32> = vec![0; input.len()];
+            b.iter(|| {
+                for (i, e) in input.iter().enumerate() {
+                    target[i] = e.abs();
+                }
+            })
+        },
+    );
+
+    group.bench_with_input(
+        BenchmarkId::new("naive_iterator", testvec_size),
+        &testvec,
+        |b, input| {
+            b.iter(|| {
+                let _target: Vec<i32> = input.iter().map(|a| a.abs()).collect();
+            })
+        },
+    );
+
+    group.bench_with_input(
+        BenchmarkId::new("unsafe", testvec_size),
+        &testvec,
+        |b, input| {
+            let mut target: Vec<i32> = vec![0; input.len()];
+            b.iter(|| {
+                unsafe { abs(input.as_ptr(), target.as_mut_ptr(), input.len()) };
+            })
+        },
+    );
 
-    c.bench_functions("abs_comparison", functions, testvec);
+    group.finish();
 }
 
 criterion_group!(detection_perf, detect_single_image);
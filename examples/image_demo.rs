@@ -19,11 +19,11 @@
 use std::env::Args;
 use std::time::{Duration, Instant};
 
-use image::{DynamicImage, GrayImage, Rgb};
+use image::{DynamicImage, Rgb, RgbImage};
 use imageproc::drawing::draw_hollow_rect_mut;
 use imageproc::rect::Rect;
 
-use rustface::{Detector, FaceInfo, ImageData};
+use rustface::{Detector, DetectorConfig, FaceInfo, ImageData};
 
 const OUTPUT_FILE: &str = "test.png";
 
@@ -36,7 +36,22 @@ fn main() {
         }
     };
 
-    let mut detector = match rustface::create_detector(options.model_path()) {
+    let model = match rustface::load_model(options.model_path()) {
+        Ok(model) => model,
+        Err(error) => {
+            println!("Failed to read model: {}", error);
+            std::process::exit(1)
+        }
+    };
+
+    let config = DetectorConfig::builder()
+        .min_face_size(40)
+        .score_thresh(2.0)
+        .pyramid_scale_factor(0.8)
+        .slide_window_step(4, 4)
+        .build()
+        .expect("Illegal detector configuration");
+    let mut detector = match rustface::create_detector_with_config(model, config) {
         Ok(detector) => detector,
         Err(error) => {
             println!("Failed to create detector: {}", error);
@@ -44,11 +59,6 @@ fn main() {
         }
     };
 
-    detector.set_min_face_size(20);
-    detector.set_score_thresh(2.0);
-    detector.set_pyramid_scale_factor(0.8);
-    detector.set_slide_window_step(4, 4);
-
     let image: DynamicImage = match image::open(options.image_path()) {
         Ok(image) => image,
         Err(message) => {
@@ -58,7 +68,7 @@ fn main() {
     };
 
     let mut rgb = image.to_rgb8();
-    let faces = detect_faces(&mut *detector, &image.to_luma8());
+    let faces = detect_faces(&mut *detector, &rgb);
 
     for face in faces {
         let bbox = face.bbox();
@@ -73,9 +83,9 @@ fn main() {
     }
 }
 
-fn detect_faces(detector: &mut dyn Detector, gray: &GrayImage) -> Vec<FaceInfo> {
-    let (width, height) = gray.dimensions();
-    let image = ImageData::new(gray, width, height);
+fn detect_faces(detector: &mut dyn Detector, rgb: &RgbImage) -> Vec<FaceInfo> {
+    let (width, height) = rgb.dimensions();
+    let image = ImageData::from_rgb(rgb, width, height);
     let now = Instant::now();
     let faces = detector.detect(&image);
     println!(
@@ -0,0 +1,509 @@
+//! Localization of facial landmarks in detected faces.
+//!
+//! The landmarks are located from coarse to fine, similar to the auto-encoder networks (CFAN)
+//! of SeetaFace Alignment: the face is cropped from the image with some context and normalized
+//! to a square patch, a global network predicts the landmarks from the pixels of the whole patch,
+//! and local networks refine them from SIFT descriptors around the current landmarks.
+//!
+//! This is not a port of SeetaFace Alignment. The model format and the geometry of the face
+//! patch are specific to rustface, so SeetaFace Alignment models (`seeta_fa_v1.1.bin`) can not
+//! be loaded.
+
+mod sift;
+
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+
+use crate::common::{sample_bilinear, FaceInfo, ImageData, RotatedRectangle};
+use crate::math;
+use crate::model::{ModelError, ModelErrorKind, ModelReader, MAX_LAYER_WEIGHTS};
+use crate::Error;
+
+/// Maximum side of the normalized face patch, which keeps the number of its pixels
+/// within `u32`.
+const MAX_PATCH_SIZE: u32 = 4096;
+
+/// Model for localizing facial landmarks.
+///
+/// The model is read from a little-endian binary stream in a format specific to rustface:
+///
+/// ```text
+/// i32 num_points
+/// i32 patch_size                     side of the normalized face patch in pixels, at most 4096
+/// f32 mean_shape[2 * num_points]     x and y of each landmark in patch coordinates
+/// i32 num_stages
+/// num_stages times:
+///     i32 descriptor_size            0 if the stage reads the pixels of the whole patch,
+///                                    otherwise the side of the window around each landmark,
+///                                    from which a 128-dimensional SIFT descriptor is computed
+///     i32 num_layers
+///     i32 input_dim
+///     num_layers times:
+///         i32 output_dim
+///         f32 weights[output_dim * input_dim]    row-major
+///         f32 biases[output_dim]
+/// ```
+///
+/// Hidden layers use the sigmoid activation, the last layer of each stage is linear and
+/// predicts the offsets of the landmarks from their current positions.
+#[derive(Clone, Debug)]
+pub struct AlignmentModel {
+    num_points: usize,
+    patch_size: u32,
+    mean_shape: Vec<f32>,
+    stages: Vec<Stage>,
+}
+
+#[derive(Clone, Debug)]
+struct Stage {
+    descriptor_size: u32,
+    input_dim: usize,
+    layers: Vec<Layer>,
+}
+
+#[derive(Clone, Debug)]
+struct Layer {
+    input_dim: usize,
+    output_dim: usize,
+    weights: Vec<f32>,
+    biases: Vec<f32>,
+}
+
+impl Layer {
+    fn compute(&self, input: &[f32], output: &mut Vec<f32>, is_last: bool) {
+        output.clear();
+        output.extend(
+            self.weights
+                .chunks_exact(self.input_dim)
+                .zip(&self.biases)
+                .map(|(weights, bias)| {
+                    let value = math::vector_inner_product(weights, input) + bias;
+                    if is_last {
+                        value
+                    } else {
+                        1.0 / (1.0 + (-value).exp())
+                    }
+                }),
+        );
+    }
+}
+
+/// Landmarks of a face in image coordinates.
+///
+/// Five-point models locate the landmarks in the order of SeetaFace Alignment:
+/// left eye, right eye, nose tip, left mouth corner and right mouth corner,
+/// where left and right refer to the sides of the image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Landmarks {
+    points: Vec<(f32, f32)>,
+}
+
+impl Landmarks {
+    /// Create landmarks from points in image coordinates, e.g. located by other means.
+    #[inline]
+    pub fn new(points: Vec<(f32, f32)>) -> Self {
+        Landmarks { points }
+    }
+
+    #[inline]
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    /// Center of the left eye, if located by a five-point model.
+    #[inline]
+    pub fn left_eye(&self) -> Option<(f32, f32)> {
+        self.five_point(0)
+    }
+
+    /// Center of the right eye, if located by a five-point model.
+    #[inline]
+    pub fn right_eye(&self) -> Option<(f32, f32)> {
+        self.five_point(1)
+    }
+
+    /// Tip of the nose, if located by a five-point model.
+    #[inline]
+    pub fn nose(&self) -> Option<(f32, f32)> {
+        self.five_point(2)
+    }
+
+    /// Left corner of the mouth, if located by a five-point model.
+    #[inline]
+    pub fn mouth_left(&self) -> Option<(f32, f32)> {
+        self.five_point(3)
+    }
+
+    /// Right corner of the mouth, if located by a five-point model.
+    #[inline]
+    pub fn mouth_right(&self) -> Option<(f32, f32)> {
+        self.five_point(4)
+    }
+
+    fn five_point(&self, index: usize) -> Option<(f32, f32)> {
+        if self.points.len() == 5 {
+            Some(self.points[index])
+        } else {
+            None
+        }
+    }
+}
+
+/// Mapping between the normalized face patch and the image.
+///
+/// The patch covers the bounding box of the face, extended by 10% of its size on the left
+/// and right, 5% on the top and 15% on the bottom, and rotated by the roll of the face.
+/// Models have to be trained on patches of this geometry.
+struct PatchFrame {
+    origin: (f32, f32),
+    scale_x: f32,
+    scale_y: f32,
+    cos: f32,
+    sin: f32,
+}
+
+impl PatchFrame {
+    fn new(face: &RotatedRectangle, patch_size: u32) -> Self {
+        let (width, height) = (face.width() as f32, face.height() as f32);
+        let (sin, cos) = face.angle().to_radians().sin_cos();
+        // top left corner of the extended box relative to the center of the face
+        let (left, top) = (-0.6 * width, -0.55 * height);
+        let (center_x, center_y) = face.center();
+        PatchFrame {
+            origin: (
+                center_x + left * cos - top * sin,
+                center_y + left * sin + top * cos,
+            ),
+            scale_x: 1.2 * width / patch_size as f32,
+            scale_y: 1.2 * height / patch_size as f32,
+            cos,
+            sin,
+        }
+    }
+
+    #[inline]
+    fn to_image(&self, x: f32, y: f32) -> (f32, f32) {
+        let (x, y) = (x * self.scale_x, y * self.scale_y);
+        (
+            self.origin.0 + x * self.cos - y * self.sin,
+            self.origin.1 + x * self.sin + y * self.cos,
+        )
+    }
+}
+
+impl AlignmentModel {
+    /// Number of landmarks located by the model.
+    #[inline]
+    pub fn num_points(&self) -> usize {
+        self.num_points
+    }
+
+    /// Locate the landmarks of a detected face.
+    ///
+    /// Color images are converted to gray-scale. Faces found with a roll angle, see
+    /// [`FaceInfo::rotated_bbox`], are aligned in their rotated frame. Parts of the face
+    /// box outside of the image repeat the edges of the image.
+    pub fn locate(&self, image: &ImageData, face: &FaceInfo) -> Result<Landmarks, Error> {
+        if image.width() == 0 || image.height() == 0 {
+            return Err(Error::IllegalImage {
+                width: image.width(),
+                height: image.height(),
+                num_channels: image.num_channels(),
+            });
+        }
+
+        let mut gray_buf = Vec::new();
+        let image = image.to_gray(&mut gray_buf);
+
+        let frame = PatchFrame::new(&face.rotated_bbox(), self.patch_size);
+        let mut patch_buf = Vec::with_capacity((self.patch_size * self.patch_size) as usize);
+        for v in 0..self.patch_size {
+            for u in 0..self.patch_size {
+                let (x, y) = frame.to_image(u as f32 + 0.5, v as f32 + 0.5);
+                patch_buf.push((sample_bilinear(&image, x, y) + 0.5) as u8);
+            }
+        }
+        let patch = ImageData::new(&patch_buf, self.patch_size, self.patch_size);
+
+        let mut shape = self.mean_shape.clone();
+        let mut input = Vec::new();
+        let mut output = Vec::new();
+        for stage in &self.stages {
+            input.clear();
+            if stage.descriptor_size == 0 {
+                input.extend(patch_buf.iter().map(|&p| f32::from(p) / 255.0));
+            } else {
+                for point in shape.chunks_exact(2) {
+                    sift::describe(
+                        &patch,
+                        point[0],
+                        point[1],
+                        stage.descriptor_size,
+                        &mut input,
+                    );
+                }
+            }
+
+            for (i, layer) in stage.layers.iter().enumerate() {
+                layer.compute(&input, &mut output, i == stage.layers.len() - 1);
+                std::mem::swap(&mut input, &mut output);
+            }
+            for (value, offset) in shape.iter_mut().zip(&input) {
+                *value += offset;
+            }
+        }
+
+        let points = shape
+            .chunks_exact(2)
+            .map(|point| frame.to_image(point[0], point[1]))
+            .collect();
+        Ok(Landmarks { points })
+    }
+
+    fn validate_structure(&self) -> Result<(), String> {
+        if self.num_points == 0 {
+            return Err("model has no landmarks".to_string());
+        }
+        if self.patch_size == 0 {
+            return Err("patch size is zero".to_string());
+        }
+        if self.patch_size > MAX_PATCH_SIZE {
+            return Err(format!(
+                "patch size {} is greater than {}",
+                self.patch_size, MAX_PATCH_SIZE
+            ));
+        }
+        for (i, stage) in self.stages.iter().enumerate() {
+            if let Some(j) = stage.layers.iter().position(|layer| layer.output_dim == 0) {
+                return Err(format!("layer {} of stage {} has no outputs", j, i));
+            }
+            let expected_input_dim = if stage.descriptor_size == 0 {
+                (self.patch_size * self.patch_size) as usize
+            } else if stage.descriptor_size < sift::MIN_WINDOW_SIZE {
+                return Err(format!(
+                    "stage {} has a descriptor size of {}, which is less than {}",
+                    i,
+                    stage.descriptor_size,
+                    sift::MIN_WINDOW_SIZE
+                ));
+            } else {
+                self.num_points * sift::DESCRIPTOR_LEN
+            };
+            if stage.input_dim != expected_input_dim {
+                return Err(format!(
+                    "stage {} has input dimension {}, expected {}",
+                    i, stage.input_dim, expected_input_dim
+                ));
+            }
+            let output_dim = stage.layers.last().map_or(0, |layer| layer.output_dim);
+            if output_dim != 2 * self.num_points {
+                return Err(format!(
+                    "stage {} has output dimension {}, expected {}",
+                    i,
+                    output_dim,
+                    2 * self.num_points
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Load alignment model from a file.
+#[inline]
+pub fn load_alignment_model(path: &str) -> Result<AlignmentModel, ModelError> {
+    let file = File::open(path).map_err(|e| ModelError::new(ModelErrorKind::Io(e), None))?;
+    read_alignment_model(BufReader::new(file))
+}
+
+/// Load alignment model from any stream or buffer.
+pub fn read_alignment_model<R: io::Read>(buf: R) -> Result<AlignmentModel, ModelError> {
+    let mut reader = ModelReader::new(buf);
+
+    let num_points = reader.read_count("number of landmarks")?;
+    let patch_size = reader.read_count("patch size")? as u32;
+    let mean_shape = reader.read_f32s("mean shape length", 2 * num_points, 2 * num_points)?;
+
+    let num_stages = reader.read_count("number of stages")?;
+    let mut stages = Vec::new();
+    for _ in 0..num_stages {
+        let descriptor_size = reader.read_count("descriptor size")? as u32;
+        let num_layers = reader.read_count("number of layers")?;
+        let input_dim = reader.read_count("layer input dimension")?;
+
+        let mut layers = Vec::new();
+        let mut layer_input_dim = input_dim;
+        for _ in 0..num_layers {
+            let output_dim = reader.read_count("layer output dimension")?;
+            let weights = reader.read_f32s(
+                "number of layer weights",
+                layer_input_dim * output_dim,
+                MAX_LAYER_WEIGHTS,
+            )?;
+            let biases = reader.read_f32s("number of layer biases", output_dim, output_dim)?;
+            layers.push(Layer {
+                input_dim: layer_input_dim,
+                output_dim,
+                weights,
+                biases,
+            });
+            layer_input_dim = output_dim;
+        }
+
+        stages.push(Stage {
+            descriptor_size,
+            input_dim,
+            layers,
+        });
+    }
+    reader.read_end()?;
+
+    let model = AlignmentModel {
+        num_points,
+        patch_size,
+        mean_shape,
+        stages,
+    };
+    model
+        .validate_structure()
+        .map_err(|message| ModelError::new(ModelErrorKind::InvalidStructure(message), None))?;
+    Ok(model)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_alignment_model, AlignmentModel};
+    use crate::common::{FaceInfo, ImageData, Rectangle, RotatedRectangle};
+    use crate::model::ModelErrorKind;
+
+    fn push_i32(data: &mut Vec<u8>, value: i32) {
+        data.extend(value.to_le_bytes());
+    }
+
+    fn push_f32s(data: &mut Vec<u8>, value: f32, count: usize) {
+        for _ in 0..count {
+            data.extend(value.to_le_bytes());
+        }
+    }
+
+    /// A five-point model with a 10 x 10 patch, a global stage, which keeps the mean shape,
+    /// and a local stage, which moves all landmarks by one pixel right and down.
+    fn model_data() -> Vec<u8> {
+        let mut data = Vec::new();
+
+        push_i32(&mut data, 5);
+        push_i32(&mut data, 10);
+        push_f32s(&mut data, 5.0, 10);
+        push_i32(&mut data, 2);
+
+        // global stage
+        push_i32(&mut data, 0);
+        push_i32(&mut data, 1);
+        push_i32(&mut data, 100);
+        push_i32(&mut data, 10);
+        push_f32s(&mut data, 0.0, 100 * 10);
+        push_f32s(&mut data, 0.0, 10);
+
+        // local stage with a hidden layer
+        push_i32(&mut data, 4);
+        push_i32(&mut data, 2);
+        push_i32(&mut data, 5 * 128);
+        push_i32(&mut data, 3);
+        push_f32s(&mut data, 0.0, 5 * 128 * 3);
+        push_f32s(&mut data, 0.0, 3);
+        push_i32(&mut data, 10);
+        push_f32s(&mut data, 0.0, 3 * 10);
+        push_f32s(&mut data, 1.0, 10);
+
+        data
+    }
+
+    fn face(bbox: Rectangle) -> FaceInfo {
+        let mut face = FaceInfo::new();
+        *face.bbox_mut() = bbox;
+        face
+    }
+
+    fn assert_points_eq(expected: (f32, f32), actual: Option<(f32, f32)>) {
+        let actual = actual.unwrap();
+        assert!(
+            (expected.0 - actual.0).abs() < 1e-3 && (expected.1 - actual.1).abs() < 1e-3,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_locate() {
+        let model: AlignmentModel = read_alignment_model(&model_data()[..]).unwrap();
+        assert_eq!(5, model.num_points());
+
+        let data = [100u8; 80 * 80];
+        let image = ImageData::new(&data, 80, 80);
+
+        // the patch covers 48 x 48 pixels from (6, 18), so a patch pixel is 4.8 image pixels
+        let landmarks = model
+            .locate(&image, &face(Rectangle::new(10, 20, 40, 40)))
+            .unwrap();
+        assert_eq!(5, landmarks.points().len());
+        assert_points_eq((6.0 + 6.0 * 4.8, 18.0 + 6.0 * 4.8), landmarks.left_eye());
+        assert_points_eq((34.8, 46.8), landmarks.mouth_right());
+
+        // rotated by 90 degrees clockwise about the center (30, 40) of the face
+        let mut rotated = face(Rectangle::new(0, 0, 0, 0));
+        rotated.set_rotated_bbox(RotatedRectangle::new((30.0, 40.0), 40, 40, 90.0));
+        let landmarks = model.locate(&image, &rotated).unwrap();
+        assert_points_eq((30.0 - 6.8, 40.0 + 4.8), landmarks.nose());
+    }
+
+    #[test]
+    fn test_read_truncated_model() {
+        let data = model_data();
+        let err = read_alignment_model(&data[..data.len() - 1]).err().unwrap();
+        assert!(matches!(err.kind(), ModelErrorKind::Truncated));
+    }
+
+    #[test]
+    fn test_read_model_with_small_descriptor() {
+        let mut data = model_data();
+        // the local stage follows the header (13 values) and the global stage (4 + 1010 values)
+        let offset = 4 * (13 + 4 + 1010);
+        assert_eq!(4, data[offset]);
+        data[offset] = 2;
+        assert_invalid_structure(&data, "descriptor size");
+    }
+
+    fn assert_invalid_structure(data: &[u8], expected: &str) {
+        let err = read_alignment_model(data).err().unwrap();
+        match err.kind() {
+            ModelErrorKind::InvalidStructure(message) => {
+                assert!(message.contains(expected), "{}", message)
+            }
+            kind => panic!("Unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_read_model_with_huge_patch() {
+        let mut data = model_data();
+        // the number of pixels of the patch would overflow u32
+        data[4..8].copy_from_slice(&65536i32.to_le_bytes());
+        assert_invalid_structure(&data, "patch size");
+    }
+
+    #[test]
+    fn test_read_model_with_empty_hidden_layer() {
+        let mut data = model_data();
+        // replace the local stage by one, whose hidden layer has no outputs
+        data.truncate(4 * (13 + 4 + 1010));
+        push_i32(&mut data, 4);
+        push_i32(&mut data, 2);
+        push_i32(&mut data, 5 * 128);
+        push_i32(&mut data, 0);
+        push_i32(&mut data, 10);
+        push_f32s(&mut data, 1.0, 10);
+        assert_invalid_structure(&data, "no outputs");
+    }
+}
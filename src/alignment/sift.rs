@@ -0,0 +1,102 @@
+use std::f32::consts::PI;
+
+use crate::common::{sample_bilinear, ImageData};
+
+/// Number of spatial cells along each side of the descriptor window.
+const NUM_CELLS: usize = 4;
+/// Number of orientation bins of each cell.
+const NUM_BINS: usize = 8;
+/// Length of a descriptor.
+pub(super) const DESCRIPTOR_LEN: usize = NUM_CELLS * NUM_CELLS * NUM_BINS;
+/// Smallest supported side of the descriptor window, one pixel per cell.
+pub(super) const MIN_WINDOW_SIZE: u32 = NUM_CELLS as u32;
+
+/// Append the SIFT descriptor of the square window of side `size` centered at `(x, y)`
+/// to `descriptor`.
+///
+/// Gradients are weighted by a Gaussian window and accumulated into histograms of 4 x 4 cells
+/// with 8 orientation bins each. The descriptor is normalized to unit length, clipped at 0.2
+/// and normalized again, as in Lowe's SIFT.
+pub(super) fn describe(image: &ImageData, x: f32, y: f32, size: u32, descriptor: &mut Vec<f32>) {
+    let start = descriptor.len();
+    descriptor.resize(start + DESCRIPTOR_LEN, 0.0);
+    let histogram = &mut descriptor[start..];
+
+    let half_size = size as f32 / 2.0;
+    let sigma_sq_2 = 2.0 * half_size * half_size;
+
+    for j in 0..size {
+        let dy = j as f32 + 0.5 - half_size;
+        let cell_y = j as usize * NUM_CELLS / size as usize;
+        for i in 0..size {
+            let dx = i as f32 + 0.5 - half_size;
+            let cell_x = i as usize * NUM_CELLS / size as usize;
+
+            let (px, py) = (x + dx, y + dy);
+            let gx = sample_bilinear(image, px + 1.0, py) - sample_bilinear(image, px - 1.0, py);
+            let gy = sample_bilinear(image, px, py + 1.0) - sample_bilinear(image, px, py - 1.0);
+            let magnitude = (gx * gx + gy * gy).sqrt();
+            if magnitude == 0.0 {
+                continue;
+            }
+            let weight = (-(dx * dx + dy * dy) / sigma_sq_2).exp();
+
+            // orientation in [0, NUM_BINS), split between the two nearest bins
+            let orientation = gy.atan2(gx).rem_euclid(2.0 * PI) / (2.0 * PI) * NUM_BINS as f32;
+            let bin = (orientation.floor() as usize).min(NUM_BINS - 1);
+            let frac = orientation - bin as f32;
+
+            let cell = (cell_y * NUM_CELLS + cell_x) * NUM_BINS;
+            histogram[cell + bin] += magnitude * weight * (1.0 - frac);
+            histogram[cell + (bin + 1) % NUM_BINS] += magnitude * weight * frac;
+        }
+    }
+
+    normalize(histogram);
+    for value in histogram.iter_mut() {
+        *value = value.min(0.2);
+    }
+    normalize(histogram);
+}
+
+fn normalize(values: &mut [f32]) {
+    let norm = values.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        for value in values {
+            *value /= norm;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, DESCRIPTOR_LEN, NUM_BINS};
+    use crate::common::ImageData;
+
+    #[test]
+    fn test_describe_flat_image() {
+        let data = [128; 16 * 16];
+        let mut descriptor = vec![1.0];
+        describe(&ImageData::new(&data, 16, 16), 8.0, 8.0, 8, &mut descriptor);
+        assert_eq!(1 + DESCRIPTOR_LEN, descriptor.len());
+        assert!(descriptor[1..].iter().all(|&v| v == 0.0));
+    }
+
+    #[test]
+    fn test_describe_vertical_edge() {
+        // dark left half, bright right half: the gradient points along the x axis
+        let data: Vec<u8> = (0..16 * 16)
+            .map(|i| if i % 16 < 8 { 0 } else { 255 })
+            .collect();
+        let mut descriptor = Vec::new();
+        describe(&ImageData::new(&data, 16, 16), 8.0, 8.0, 8, &mut descriptor);
+
+        let norm = descriptor.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4);
+        for (i, &value) in descriptor.iter().enumerate() {
+            if i % NUM_BINS != 0 {
+                assert_eq!(0.0, value);
+            }
+        }
+    }
+}
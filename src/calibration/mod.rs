@@ -0,0 +1,470 @@
+//! Calibration of detection scores to confidences in [0, 1].
+//!
+//! The score of a detection is the sum of scores of the sliding windows merged into it,
+//! so its range depends on the model and on the detector configuration. A [`Calibration`]
+//! maps scores to confidences, which can be interpreted as the probability of the detection
+//! being a face. It is fitted offline from detections labeled as true or false positives,
+//! using the same model and configuration as in production, stored in a small text file next
+//! to the model, and attached to the model with [`Model::with_calibration`](crate::Model::with_calibration).
+
+use std::cmp::Ordering;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+
+/// Mapping of detection scores to confidences in [0, 1].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Calibration {
+    kind: CalibrationKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum CalibrationKind {
+    /// `1 / (1 + exp(a * score + b))`
+    Platt { a: f64, b: f64 },
+    /// Piecewise linear interpolation between points with increasing scores
+    /// and non-decreasing confidences.
+    Isotonic { points: Vec<(f64, f64)> },
+}
+
+impl Calibration {
+    /// Create a Platt (logistic) calibration, which maps a score `s` to
+    /// `1 / (1 + exp(a * s + b))`.
+    ///
+    /// Returns an error, if `a` or `b` is not finite.
+    pub fn platt(a: f64, b: f64) -> Result<Self, CalibrationError> {
+        if !a.is_finite() || !b.is_finite() {
+            return Err(CalibrationError::InvalidData(format!(
+                "Platt parameters must be finite: {}, {}",
+                a, b
+            )));
+        }
+        Ok(Calibration {
+            kind: CalibrationKind::Platt { a, b },
+        })
+    }
+
+    /// Create an isotonic calibration from `(score, confidence)` points.
+    ///
+    /// Scores between the points are mapped by linear interpolation, scores outside of
+    /// the points to the confidence of the nearest point. Returns an error, if there are
+    /// no points, the scores are not strictly increasing, or the confidences are not
+    /// non-decreasing within [0, 1].
+    pub fn isotonic(points: Vec<(f64, f64)>) -> Result<Self, CalibrationError> {
+        if points.is_empty() {
+            return Err(CalibrationError::InvalidData(
+                "Isotonic calibration needs at least one point".to_string(),
+            ));
+        }
+        if points
+            .iter()
+            .any(|&(s, c)| !s.is_finite() || !(0.0..=1.0).contains(&c))
+        {
+            return Err(CalibrationError::InvalidData(
+                "Isotonic calibration points must have finite scores and confidences in [0, 1]"
+                    .to_string(),
+            ));
+        }
+        if points
+            .windows(2)
+            .any(|w| w[0].0 >= w[1].0 || w[0].1 > w[1].1)
+        {
+            return Err(CalibrationError::InvalidData(
+                "Isotonic calibration points must be increasing".to_string(),
+            ));
+        }
+        Ok(Calibration {
+            kind: CalibrationKind::Isotonic { points },
+        })
+    }
+
+    /// Fit a Platt calibration to `(score, is_face)` samples by maximum likelihood.
+    ///
+    /// Uses the algorithm of Lin, Lin and Weng, "A note on Platt's probabilistic outputs
+    /// for support vector machines", with regularized targets. Returns an error, if there
+    /// are no positive or no negative samples, or a score is not finite.
+    pub fn fit_platt(samples: &[(f64, bool)]) -> Result<Self, CalibrationError> {
+        check_samples(samples)?;
+        let num_pos = samples.iter().filter(|&&(_, label)| label).count() as f64;
+        let num_neg = samples.len() as f64 - num_pos;
+        if num_pos == 0.0 || num_neg == 0.0 {
+            return Err(CalibrationError::InvalidData(
+                "Platt calibration needs positive and negative samples".to_string(),
+            ));
+        }
+
+        let hi_target = (num_pos + 1.0) / (num_pos + 2.0);
+        let lo_target = 1.0 / (num_neg + 2.0);
+        let targets: Vec<f64> = samples
+            .iter()
+            .map(|&(_, label)| if label { hi_target } else { lo_target })
+            .collect();
+
+        let objective = |a: f64, b: f64| -> f64 {
+            samples
+                .iter()
+                .zip(&targets)
+                .map(|(&(s, _), &t)| {
+                    let f = a * s + b;
+                    if f >= 0.0 {
+                        t * f + (-f).exp().ln_1p()
+                    } else {
+                        (t - 1.0) * f + f.exp().ln_1p()
+                    }
+                })
+                .sum()
+        };
+
+        const MAX_ITER: usize = 100;
+        const MIN_STEP: f64 = 1e-10;
+        const SIGMA: f64 = 1e-12;
+        const EPS: f64 = 1e-5;
+
+        let mut a = 0.0;
+        let mut b = ((num_neg + 1.0) / (num_pos + 1.0)).ln();
+        let mut fval = objective(a, b);
+
+        for _ in 0..MAX_ITER {
+            let (mut h11, mut h22, mut h21) = (SIGMA, SIGMA, 0.0);
+            let (mut g1, mut g2) = (0.0, 0.0);
+            for (&(s, _), &t) in samples.iter().zip(&targets) {
+                let p = logistic(-(a * s + b));
+                let d2 = p * (1.0 - p);
+                h11 += s * s * d2;
+                h22 += d2;
+                h21 += s * d2;
+                let d1 = t - p;
+                g1 += s * d1;
+                g2 += d1;
+            }
+            if g1.abs() < EPS && g2.abs() < EPS {
+                break;
+            }
+
+            let det = h11 * h22 - h21 * h21;
+            let da = -(h22 * g1 - h21 * g2) / det;
+            let db = -(-h21 * g1 + h11 * g2) / det;
+            let gd = g1 * da + g2 * db;
+
+            let mut step = 1.0;
+            while step >= MIN_STEP {
+                let new_a = a + step * da;
+                let new_b = b + step * db;
+                let new_f = objective(new_a, new_b);
+                if new_f < fval + 0.0001 * step * gd {
+                    a = new_a;
+                    b = new_b;
+                    fval = new_f;
+                    break;
+                }
+                step /= 2.0;
+            }
+            if step < MIN_STEP {
+                break;
+            }
+        }
+
+        Calibration::platt(a, b)
+    }
+
+    /// Fit an isotonic calibration to `(score, is_face)` samples, using the pool adjacent
+    /// violators algorithm.
+    ///
+    /// Returns an error, if there are no samples or a score is not finite.
+    pub fn fit_isotonic(samples: &[(f64, bool)]) -> Result<Self, CalibrationError> {
+        check_samples(samples)?;
+        if samples.is_empty() {
+            return Err(CalibrationError::InvalidData(
+                "Isotonic calibration needs at least one sample".to_string(),
+            ));
+        }
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(Ordering::Equal));
+
+        // blocks of (sum of scores, number of faces, number of samples),
+        // samples with equal scores always end up in the same block
+        let mut blocks: Vec<(f64, f64, f64)> = Vec::new();
+        let mut last_score = f64::NAN;
+        for &(score, label) in &sorted {
+            let label = if label { 1.0 } else { 0.0 };
+            match blocks.last_mut() {
+                Some(block) if score == last_score => {
+                    block.0 += score;
+                    block.1 += label;
+                    block.2 += 1.0;
+                }
+                _ => blocks.push((score, label, 1.0)),
+            }
+            last_score = score;
+
+            while blocks.len() > 1 {
+                let last = blocks[blocks.len() - 1];
+                let prev = blocks[blocks.len() - 2];
+                if prev.1 / prev.2 < last.1 / last.2 {
+                    break;
+                }
+                blocks.pop();
+                let prev = blocks.last_mut().unwrap();
+                prev.0 += last.0;
+                prev.1 += last.1;
+                prev.2 += last.2;
+            }
+        }
+
+        let points = blocks
+            .iter()
+            .map(|&(scores, faces, count)| (scores / count, faces / count))
+            .collect();
+        Calibration::isotonic(points)
+    }
+
+    /// Map a detection score to a confidence in [0, 1].
+    pub fn apply(&self, score: f64) -> f64 {
+        match self.kind {
+            CalibrationKind::Platt { a, b } => logistic(-(a * score + b)),
+            CalibrationKind::Isotonic { ref points } => {
+                let i = points.partition_point(|&(s, _)| s <= score);
+                if i == 0 {
+                    points[0].1
+                } else if i == points.len() {
+                    points[i - 1].1
+                } else {
+                    let (s0, c0) = points[i - 1];
+                    let (s1, c1) = points[i];
+                    c0 + (c1 - c0) * (score - s0) / (s1 - s0)
+                }
+            }
+        }
+    }
+
+    /// Write the calibration in the text format read by [`read_calibration`].
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        match self.kind {
+            CalibrationKind::Platt { a, b } => writeln!(writer, "platt {} {}", a, b),
+            CalibrationKind::Isotonic { ref points } => {
+                writeln!(writer, "isotonic {}", points.len())?;
+                for (score, confidence) in points {
+                    writeln!(writer, "{} {}", score, confidence)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// `1 / (1 + exp(-x))`, avoiding overflow for large `|x|`.
+fn logistic(x: f64) -> f64 {
+    if x >= 0.0 {
+        1.0 / (1.0 + (-x).exp())
+    } else {
+        let e = x.exp();
+        e / (1.0 + e)
+    }
+}
+
+fn check_samples(samples: &[(f64, bool)]) -> Result<(), CalibrationError> {
+    if samples.iter().any(|&(score, _)| !score.is_finite()) {
+        return Err(CalibrationError::InvalidData(
+            "Sample scores must be finite".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Errors of fitting, reading and creating a [`Calibration`].
+#[derive(Debug)]
+pub enum CalibrationError {
+    /// The underlying stream or file could not be read.
+    Io(io::Error),
+    /// The calibration file is malformed.
+    Parse { line: usize, message: String },
+    /// The samples or parameters do not describe a valid calibration.
+    InvalidData(String),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CalibrationError::Io(ref e) => write!(f, "I/O error: {}", e),
+            CalibrationError::Parse { line, ref message } => {
+                write!(f, "Malformed calibration at line {}: {}", line, message)
+            }
+            CalibrationError::InvalidData(ref message) => {
+                write!(f, "Invalid calibration: {}", message)
+            }
+        }
+    }
+}
+
+impl error::Error for CalibrationError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            CalibrationError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Load calibration from a file.
+pub fn load_calibration(path: &str) -> Result<Calibration, CalibrationError> {
+    let file = File::open(path).map_err(CalibrationError::Io)?;
+    read_calibration(file)
+}
+
+/// Read calibration from any stream or buffer.
+///
+/// The format is line-based: either `platt <a> <b>`, or `isotonic <n>` followed by
+/// `n` lines of `<score> <confidence>`. Only blank lines may follow.
+pub fn read_calibration<R: io::Read>(reader: R) -> Result<Calibration, CalibrationError> {
+    let mut lines = BufReader::new(reader).lines();
+    let mut line_number = 0;
+    let mut next_line = || -> Result<(usize, Vec<String>), CalibrationError> {
+        // the number of the line, which is read now or expected at the end of data
+        line_number += 1;
+        match lines.next() {
+            Some(line) => {
+                let line = line.map_err(CalibrationError::Io)?;
+                Ok((
+                    line_number,
+                    line.split_whitespace().map(str::to_string).collect(),
+                ))
+            }
+            None => Err(CalibrationError::Parse {
+                line: line_number,
+                message: "Unexpected end of calibration data".to_string(),
+            }),
+        }
+    };
+
+    let (line, header) = next_line()?;
+    let parse_error = |line: usize, message: &str| CalibrationError::Parse {
+        line,
+        message: message.to_string(),
+    };
+    let calibration = match header.first().map(String::as_str) {
+        Some("platt") if header.len() == 3 => {
+            let a = parse_f64(&header[1], line)?;
+            let b = parse_f64(&header[2], line)?;
+            Calibration::platt(a, b)
+        }
+        Some("isotonic") if header.len() == 2 => {
+            let n: usize = header[1]
+                .parse()
+                .map_err(|_| parse_error(line, "Illegal number of points"))?;
+            let mut points = Vec::with_capacity(n.min(1 << 12));
+            for _ in 0..n {
+                let (line, fields) = next_line()?;
+                if fields.len() != 2 {
+                    return Err(parse_error(line, "Expected score and confidence"));
+                }
+                points.push((parse_f64(&fields[0], line)?, parse_f64(&fields[1], line)?));
+            }
+            Calibration::isotonic(points)
+        }
+        _ => Err(parse_error(line, "Unknown calibration kind")),
+    }?;
+
+    for line in lines {
+        line_number += 1;
+        if !line.map_err(CalibrationError::Io)?.trim().is_empty() {
+            return Err(parse_error(
+                line_number,
+                "Unexpected data after the calibration",
+            ));
+        }
+    }
+    Ok(calibration)
+}
+
+fn parse_f64(s: &str, line: usize) -> Result<f64, CalibrationError> {
+    s.parse().map_err(|_| CalibrationError::Parse {
+        line,
+        message: format!("Illegal number: {}", s),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_calibration, Calibration, CalibrationError};
+
+    fn samples() -> Vec<(f64, bool)> {
+        vec![
+            (1.0, false),
+            (2.0, false),
+            (3.0, true),
+            (4.0, false),
+            (5.0, true),
+            (6.0, true),
+            (7.0, false),
+            (8.0, true),
+            (9.0, true),
+            (10.0, true),
+        ]
+    }
+
+    #[test]
+    fn test_fit_platt() {
+        let calibration = Calibration::fit_platt(&samples()).unwrap();
+        let confidences: Vec<f64> = (0..=12).map(|s| calibration.apply(f64::from(s))).collect();
+        assert!(confidences.windows(2).all(|w| w[0] < w[1]));
+        assert!(confidences[0] > 0.0 && confidences[0] < 0.3);
+        assert!(confidences[12] < 1.0 && confidences[12] > 0.9);
+
+        assert!(matches!(
+            Calibration::fit_platt(&[(1.0, true), (2.0, true)]),
+            Err(CalibrationError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn test_fit_isotonic() {
+        let calibration = Calibration::fit_isotonic(&samples()).unwrap();
+        let expected =
+            Calibration::isotonic(vec![(1.5, 0.0), (3.5, 0.5), (6.0, 2.0 / 3.0), (9.0, 1.0)])
+                .unwrap();
+        assert_eq!(expected, calibration);
+
+        assert_eq!(0.0, calibration.apply(-5.0));
+        assert_eq!(0.0, calibration.apply(1.5));
+        assert_eq!(0.3125, calibration.apply(2.75));
+        assert_eq!(1.0, calibration.apply(20.0));
+    }
+
+    #[test]
+    fn test_isotonic_rejects_decreasing_points() {
+        assert!(Calibration::isotonic(vec![(1.0, 0.5), (2.0, 0.4)]).is_err());
+        assert!(Calibration::isotonic(vec![(1.0, 0.5), (1.0, 0.6)]).is_err());
+        assert!(Calibration::isotonic(vec![(1.0, 1.5)]).is_err());
+        assert!(Calibration::isotonic(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        for calibration in &[
+            Calibration::fit_platt(&samples()).unwrap(),
+            Calibration::fit_isotonic(&samples()).unwrap(),
+        ] {
+            let mut buf = Vec::new();
+            calibration.write(&mut buf).unwrap();
+            assert_eq!(calibration, &read_calibration(&buf[..]).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_read_malformed() {
+        let error = read_calibration(&b"isotonic 2\n1.0 0.5\n"[..]).unwrap_err();
+        assert!(matches!(error, CalibrationError::Parse { line: 3, .. }));
+        let error = read_calibration(&b""[..]).unwrap_err();
+        assert!(matches!(error, CalibrationError::Parse { line: 1, .. }));
+        let error = read_calibration(&b"platt 1.0 x\n"[..]).unwrap_err();
+        assert!(matches!(error, CalibrationError::Parse { line: 1, .. }));
+        let error = read_calibration(&b"sigmoid 1.0\n"[..]).unwrap_err();
+        assert!(matches!(error, CalibrationError::Parse { line: 1, .. }));
+        let error = read_calibration(&b"isotonic 1\n1.0 0.5\n\n2.0 0.6\n"[..]).unwrap_err();
+        assert!(matches!(error, CalibrationError::Parse { line: 4, .. }));
+        assert!(read_calibration(&b"platt 1.0 2.0\n\n"[..]).is_ok());
+    }
+}
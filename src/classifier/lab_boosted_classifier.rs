@@ -55,8 +55,50 @@ impl LabBoostedClassifier {
 
 const K_FEAT_GROUP_SIZE: usize = 10;
 const K_STDDEV_THRESH: f64 = 10.0;
+/// Number of distinct LAB feature values.
+const K_NUM_FEAT_VAL: usize = 256;
+/// Maximum feature offset inside of a 40x40 window;
+/// each LAB feature spans 3x3 rectangles of 3x3 pixels.
+const K_MAX_FEAT_OFFSET: i32 = 40 - 9;
 
 impl LabBoostedClassifier {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.features.len() != self.base_classifiers.len() {
+            return Err(format!(
+                "number of LAB features ({}) does not match the number of base classifiers ({})",
+                self.features.len(),
+                self.base_classifiers.len()
+            ));
+        }
+        if self.base_classifiers.len() % K_FEAT_GROUP_SIZE != 0 {
+            return Err(format!(
+                "number of base classifiers ({}) is not a multiple of {}",
+                self.base_classifiers.len(),
+                K_FEAT_GROUP_SIZE
+            ));
+        }
+        if let Some(&(x, y)) = self.features.iter().find(|(x, y)| {
+            !(0..=K_MAX_FEAT_OFFSET).contains(x) || !(0..=K_MAX_FEAT_OFFSET).contains(y)
+        }) {
+            return Err(format!(
+                "LAB feature offset ({}, {}) is out of window",
+                x, y
+            ));
+        }
+        if let Some(base_classifier) = self
+            .base_classifiers
+            .iter()
+            .find(|c| c.weights.len() != K_NUM_FEAT_VAL)
+        {
+            return Err(format!(
+                "number of base classifier weights ({}) does not match the number of LAB feature values ({})",
+                base_classifier.weights.len(),
+                K_NUM_FEAT_VAL
+            ));
+        }
+        Ok(())
+    }
+
     pub fn classify(&self, feature_map: &LabBoostedFeatureMap, roi: Rectangle) -> Score {
         let mut positive = true;
         let mut score = 0.0;
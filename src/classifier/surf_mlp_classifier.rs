@@ -202,7 +202,60 @@ impl Layer {
     }
 }
 
+/// Number of outputs of the final layer: the score and the bounding box regression.
+const K_NUM_OUTPUTS: usize = 4;
+
 impl SurfMlpClassifier {
+    pub fn validate(&self, feature_map: &SurfMlpFeatureMap) -> Result<(), String> {
+        if self.layers.len() < 2 {
+            return Err(format!(
+                "MLP has {} layer(s), at least 2 are required",
+                self.layers.len()
+            ));
+        }
+
+        let num_features = feature_map.get_feature_count();
+        let mut input_dim = 0;
+        for &feature_id in &self.feature_ids {
+            if feature_id < 1 || feature_id as usize > num_features {
+                return Err(format!(
+                    "SURF feature id {} is out of range [1, {}]",
+                    feature_id, num_features
+                ));
+            }
+            input_dim += feature_map.get_feature_vector_dim((feature_id - 1) as usize);
+        }
+
+        for layer in &self.layers {
+            if layer.input_dim != input_dim {
+                return Err(format!(
+                    "MLP layer input dimension ({}) does not match the preceding output dimension ({})",
+                    layer.input_dim, input_dim
+                ));
+            }
+            if layer.weights.len() != layer.input_dim * layer.output_dim
+                || layer.biases.len() != layer.output_dim
+            {
+                return Err(format!(
+                    "MLP layer of size {}x{} has {} weights and {} biases",
+                    layer.input_dim,
+                    layer.output_dim,
+                    layer.weights.len(),
+                    layer.biases.len()
+                ));
+            }
+            input_dim = layer.output_dim;
+        }
+
+        if input_dim != K_NUM_OUTPUTS {
+            return Err(format!(
+                "MLP produces {} output(s), {} are required",
+                input_dim, K_NUM_OUTPUTS
+            ));
+        }
+        Ok(())
+    }
+
     pub fn classify(
         &self,
         output: Option<&mut Vec<f32>>,
@@ -222,7 +275,7 @@ impl SurfMlpClassifier {
             unsafe {
                 for &feature_id in &self.feature_ids[..] {
                     feature_map.get_feature_vector((feature_id - 1) as usize, dest, roi);
-                    let offset = feature_map.get_feature_vector_dim(feature_id as usize);
+                    let offset = feature_map.get_feature_vector_dim((feature_id - 1) as usize);
                     dest = dest.add(offset);
                 }
             }
@@ -244,3 +297,37 @@ impl SurfMlpClassifier {
         score
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{SurfMlpBuffers, SurfMlpClassifier};
+    use crate::feat::{FeatureMap, SurfMlpFeatureMap};
+    use crate::{ImageData, Rectangle};
+
+    #[test]
+    pub fn test_classify_with_last_feature() {
+        let pixels: Vec<u8> = (0..40 * 40).map(|i| (i * 7 % 251) as u8).collect();
+        let image = ImageData::new(&pixels, 40, 40);
+        let mut feature_map = SurfMlpFeatureMap::new();
+        feature_map.compute(&image);
+
+        // Feature ids are 1-based, so the last feature of the pool
+        // must be addressed with its own (0-based) dimension.
+        let num_features = feature_map.get_feature_count();
+        let dim = feature_map.get_feature_vector_dim(num_features - 1);
+        let mut classifier = SurfMlpClassifier::new();
+        classifier.add_feature_id(num_features as i32);
+        classifier.add_layer(dim, 2, vec![0.0; dim * 2], vec![0.0; 2]);
+        classifier.add_output_layer(2, 4, vec![0.0; 8], vec![0.0; 4]);
+        assert!(classifier.validate(&feature_map).is_ok());
+
+        let mut bufs = SurfMlpBuffers::new();
+        let score = classifier.classify(
+            None,
+            &mut bufs,
+            &mut feature_map,
+            Rectangle::new(0, 0, 40, 40),
+        );
+        assert_eq!(0.5, score.score);
+    }
+}
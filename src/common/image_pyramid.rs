@@ -16,25 +16,303 @@
 // You should have received a copy of the BSD 2-Clause License along with the software.
 // If not, see < https://opensource.org/licenses/BSD-2-Clause>.
 
-use std::cmp;
+use std::{cmp, mem};
+
+/// Layout of pixels in [`ImageData`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    /// 8-bit gray-scale, one byte per pixel.
+    Gray,
+    /// 8-bit red, green and blue channels, three bytes per pixel.
+    Rgb,
+    /// 8-bit red, green, blue and alpha channels, four bytes per pixel.
+    Rgba,
+    /// 8-bit blue, green and red channels, three bytes per pixel.
+    Bgr,
+    /// 8-bit blue, green, red and alpha channels, four bytes per pixel.
+    Bgra,
+}
+
+impl PixelFormat {
+    /// Number of bytes per pixel.
+    #[inline]
+    pub fn num_channels(self) -> u32 {
+        match self {
+            PixelFormat::Gray => 1,
+            PixelFormat::Rgb | PixelFormat::Bgr => 3,
+            PixelFormat::Rgba | PixelFormat::Bgra => 4,
+        }
+    }
+
+    /// Byte offsets of the red, green and blue channels inside of a pixel.
+    #[inline]
+    fn rgb_offsets(self) -> (usize, usize, usize) {
+        match self {
+            PixelFormat::Gray => (0, 0, 0),
+            PixelFormat::Rgb | PixelFormat::Rgba => (0, 1, 2),
+            PixelFormat::Bgr | PixelFormat::Bgra => (2, 1, 0),
+        }
+    }
+}
+
+/// Layout of a planar YUV 4:2:0 frame.
+///
+/// In all of the supported layouts the frame starts with a full resolution
+/// luma (Y) plane, followed by the chroma planes, subsampled by 2 in both directions.
+///
+/// If luma rows are `stride` bytes apart, chroma rows are expected to be `stride / 2`
+/// (rounded up) bytes apart for I420, and `stride` (rounded up to an even number)
+/// bytes apart for NV12 and NV21.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum YuvFormat {
+    /// Y plane, followed by U and V planes. Also known as YUV420p.
+    I420,
+    /// Y plane, followed by a plane of interleaved U and V samples.
+    Nv12,
+    /// Y plane, followed by a plane of interleaved V and U samples.
+    Nv21,
+}
 
-#[derive(Debug)]
+impl YuvFormat {
+    /// Minimum length of a frame, which luma rows are `stride` bytes apart.
+    fn frame_len(self, width: u32, height: u32, stride: u32) -> usize {
+        let luma_len = stride as usize * height as usize;
+        let chroma_width = (width as usize).div_ceil(2);
+        let chroma_height = height.div_ceil(2);
+        let chroma_stride = stride.div_ceil(2);
+        let chroma_len = match self {
+            YuvFormat::I420 => {
+                2 * ImageData::min_data_len(chroma_width, chroma_height, chroma_stride)
+            }
+            YuvFormat::Nv12 | YuvFormat::Nv21 => {
+                ImageData::min_data_len(2 * chroma_width, chroma_height, 2 * chroma_stride)
+            }
+        };
+        luma_len + chroma_len
+    }
+}
+
+/// Image, that borrows its pixels from a caller-provided buffer.
+///
+/// Rows of pixels may be separated by padding: `stride` is the distance in bytes
+/// between the starts of two consecutive rows, and is equal to `width * num_channels`
+/// for images without padding.
+#[derive(Copy, Clone, Debug)]
 pub struct ImageData<'a> {
     data: &'a [u8],
     width: u32,
     height: u32,
-    num_channels: u32,
+    stride: u32,
+    pixel_format: PixelFormat,
 }
 
 impl<'a> ImageData<'a> {
+    /// Create a gray-scale image.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `data` is not equal to `width * height`.
     #[inline]
     pub fn new(data: &'a [u8], width: u32, height: u32) -> Self {
-        assert_eq!(data.len(), width as usize * height as usize);
+        Self::with_pixel_format(data, width, height, PixelFormat::Gray)
+    }
+
+    /// Create a gray-scale image, which rows are `stride` bytes apart.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stride` is less than `width`, or if `data` is too short
+    /// to hold `height` rows.
+    #[inline]
+    pub fn with_stride(data: &'a [u8], width: u32, height: u32, stride: u32) -> Self {
+        Self::with_pixel_format_and_stride(data, width, height, stride, PixelFormat::Gray)
+    }
+
+    /// Create a gray-scale image from the luma (Y) plane of a planar YUV 4:2:0 frame,
+    /// without copying the pixels.
+    ///
+    /// The chroma planes are not used for detection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is too short to hold the whole frame.
+    #[inline]
+    pub fn from_yuv(data: &'a [u8], width: u32, height: u32, format: YuvFormat) -> Self {
+        Self::from_yuv_with_stride(data, width, height, width, format)
+    }
+
+    /// Create a gray-scale image from the luma (Y) plane of a planar YUV 4:2:0 frame,
+    /// which luma rows are `stride` bytes apart, without copying the pixels.
+    ///
+    /// See [`YuvFormat`] for the expected stride of the chroma planes.
+    /// If the chroma planes are stored elsewhere or with a different stride,
+    /// use [`ImageData::with_stride`] with the luma plane instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stride` is less than `width`, or if `data` is too short
+    /// to hold the whole frame.
+    pub fn from_yuv_with_stride(
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: YuvFormat,
+    ) -> Self {
+        let frame_len = format.frame_len(width, height, stride);
+        assert!(
+            data.len() >= frame_len,
+            "Data length ({}) is too short for a {:?} frame of size {}x{} with stride {} ({})",
+            data.len(),
+            format,
+            width,
+            height,
+            stride,
+            frame_len
+        );
+        let luma_len = stride as usize * height as usize;
+        Self::with_stride(&data[..luma_len], width, height, stride)
+    }
+
+    /// Create an image with packed 8-bit RGB pixels.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `data` is not equal to `width * height * 3`.
+    #[inline]
+    pub fn from_rgb(data: &'a [u8], width: u32, height: u32) -> Self {
+        Self::with_pixel_format(data, width, height, PixelFormat::Rgb)
+    }
+
+    /// Create an image with packed 8-bit RGBA pixels.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `data` is not equal to `width * height * 4`.
+    #[inline]
+    pub fn from_rgba(data: &'a [u8], width: u32, height: u32) -> Self {
+        Self::with_pixel_format(data, width, height, PixelFormat::Rgba)
+    }
+
+    /// Create an image with packed 8-bit BGR pixels.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `data` is not equal to `width * height * 3`.
+    #[inline]
+    pub fn from_bgr(data: &'a [u8], width: u32, height: u32) -> Self {
+        Self::with_pixel_format(data, width, height, PixelFormat::Bgr)
+    }
+
+    /// Create an image with packed 8-bit BGRA pixels.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `data` is not equal to `width * height * 4`.
+    #[inline]
+    pub fn from_bgra(data: &'a [u8], width: u32, height: u32) -> Self {
+        Self::with_pixel_format(data, width, height, PixelFormat::Bgra)
+    }
+
+    /// Create an image with the given pixel format.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of `data` does not match the dimensions and the pixel format.
+    pub fn with_pixel_format(
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+        pixel_format: PixelFormat,
+    ) -> Self {
+        let stride = width * pixel_format.num_channels();
+        assert_eq!(data.len(), stride as usize * height as usize);
+        Self::with_pixel_format_and_stride(data, width, height, stride, pixel_format)
+    }
+
+    /// Create an image with the given pixel format, which rows are `stride` bytes apart.
+    ///
+    /// The padding after the last row may be omitted from `data`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `stride` is less than `width * num_channels`, or if `data` is too short
+    /// to hold `height` rows.
+    pub fn with_pixel_format_and_stride(
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+        stride: u32,
+        pixel_format: PixelFormat,
+    ) -> Self {
+        let row_len = width as usize * pixel_format.num_channels() as usize;
+        assert!(
+            stride as usize >= row_len,
+            "Stride ({}) is less than the row length ({})",
+            stride,
+            row_len
+        );
+        assert!(
+            data.len() >= Self::min_data_len(row_len, height, stride),
+            "Data length ({}) is too short for {} rows with stride {}",
+            data.len(),
+            height,
+            stride
+        );
         ImageData {
             data,
             width,
             height,
-            num_channels: 1,
+            stride,
+            pixel_format,
+        }
+    }
+
+    #[inline]
+    fn min_data_len(row_len: usize, height: u32, stride: u32) -> usize {
+        if row_len == 0 || height == 0 {
+            0
+        } else {
+            (height as usize - 1) * stride as usize + row_len
+        }
+    }
+
+    /// Get a rectangular region of this image, without copying the pixels.
+    ///
+    /// Coordinates of faces, detected on the returned image,
+    /// are relative to the top left corner of the region.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region does not lie within the image.
+    pub fn sub_image(&self, x: u32, y: u32, width: u32, height: u32) -> ImageData<'a> {
+        assert!(
+            x as u64 + width as u64 <= self.width as u64
+                && y as u64 + height as u64 <= self.height as u64,
+            "Region {}x{} at ({}, {}) is out of image bounds {}x{}",
+            width,
+            height,
+            x,
+            y,
+            self.width,
+            self.height
+        );
+
+        let num_channels = self.num_channels() as usize;
+        let row_len = width as usize * num_channels;
+        let start = y as usize * self.stride as usize + x as usize * num_channels;
+        let end = start + Self::min_data_len(row_len, height, self.stride);
+
+        ImageData {
+            data: if start < end {
+                &self.data[start..end]
+            } else {
+                &[]
+            },
+            width,
+            height,
+            stride: self.stride,
+            pixel_format: self.pixel_format,
         }
     }
 
@@ -48,60 +326,164 @@ impl<'a> ImageData<'a> {
         self.height
     }
 
+    /// Distance in bytes between the starts of two consecutive rows.
+    #[inline]
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+
     #[inline]
     pub fn num_channels(&self) -> u32 {
-        self.num_channels
+        self.pixel_format.num_channels()
     }
 
+    #[inline]
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// Underlying buffer, including the padding between rows, if any.
     #[inline]
     pub fn data(&self) -> &'a [u8] {
         self.data
     }
+
+    /// Whether the rows follow each other without padding.
+    #[inline]
+    pub fn is_contiguous(&self) -> bool {
+        self.stride == self.width * self.num_channels()
+    }
+
+    /// Pixels of the row `y`, without padding.
+    #[inline]
+    pub fn row(&self, y: u32) -> &'a [u8] {
+        let start = y as usize * self.stride as usize;
+        &self.data[start..start + self.width as usize * self.num_channels() as usize]
+    }
+
+    /// Iterator over the rows of the image, without padding.
+    #[inline]
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u8]> {
+        let image = *self;
+        (0..self.height).map(move |y| image.row(y))
+    }
+
+    /// Get a gray-scale version of this image.
+    ///
+    /// Gray-scale images are returned as is, color images are converted into `buf`,
+    /// which is only re-allocated if it is too small to hold the converted image.
+    ///
+    /// Color is converted to luma using the ITU-R BT.601 weights:
+    /// `Y = 0.299 R + 0.587 G + 0.114 B`, computed in 8-bit fixed point.
+    pub fn to_gray<'b>(&self, buf: &'b mut Vec<u8>) -> ImageData<'b>
+    where
+        'a: 'b,
+    {
+        if self.pixel_format == PixelFormat::Gray {
+            return *self;
+        }
+
+        let num_channels = self.num_channels() as usize;
+        let (r, g, b) = self.pixel_format.rgb_offsets();
+
+        buf.clear();
+        for row in self.rows() {
+            buf.extend(row.chunks_exact(num_channels).map(|pixel| {
+                let luma =
+                    77 * u32::from(pixel[r]) + 150 * u32::from(pixel[g]) + 29 * u32::from(pixel[b]);
+                ((luma + 128) >> 8) as u8
+            }));
+        }
+
+        ImageData::new(buf, self.width, self.height)
+    }
+
+    /// Get a single color channel (0 for red, 1 for green, 2 for blue) of this image
+    /// as a gray-scale image, extracted into `buf`. Gray-scale images are returned as is.
+    pub(crate) fn channel<'b>(&self, channel: usize, buf: &'b mut Vec<u8>) -> ImageData<'b>
+    where
+        'a: 'b,
+    {
+        if self.pixel_format == PixelFormat::Gray {
+            return *self;
+        }
+
+        let num_channels = self.num_channels() as usize;
+        let (r, g, b) = self.pixel_format.rgb_offsets();
+        let offset = [r, g, b][channel];
+
+        buf.clear();
+        for row in self.rows() {
+            buf.extend(row.chunks_exact(num_channels).map(|pixel| pixel[offset]));
+        }
+
+        ImageData::new(buf, self.width, self.height)
+    }
+
+    /// Copy the pixels into `dest` without padding between rows.
+    pub fn copy_to(&self, dest: &mut Vec<u8>) {
+        dest.clear();
+        if self.is_contiguous() {
+            dest.extend_from_slice(&self.data[..self.height as usize * self.stride as usize]);
+        } else {
+            for row in self.rows() {
+                dest.extend_from_slice(row);
+            }
+        }
+    }
+}
+
+/// How the levels of an image pyramid are computed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PyramidMode {
+    /// Every level is interpolated bilinearly from the full-resolution image. This is the
+    /// behavior of SeetaFace and the default mode. It is fast, but aliases at small scales,
+    /// where a level skips most of the pixels of the image.
+    #[default]
+    Bilinear,
+    /// Every level, which shrinks the image, is computed from the previous level by averaging
+    /// the pixels each of its pixels covers. This filters out the details, which are too fine
+    /// for the level, so large faces are found more reliably, at the cost of about twice
+    /// the time of building the pyramid. Levels, which enlarge the image, are interpolated
+    /// bilinearly.
+    AreaAveraging,
 }
 
+/// Pyramid of down- or upsampled versions of an image, which the sliding window is run on.
+///
+/// The pyramid only owns the buffers of the scaled images. They are allocated for the size of
+/// the largest level and kept between images, so that a pyramid, which is re-used for images
+/// of the same size, does not allocate. A level of scale 1 borrows the image itself.
 pub struct ImagePyramid {
     max_scale: f32,
     min_scale: f32,
-    scale_factor: f32,
     scale_step: f32,
-    width1x: u32,
-    height1x: u32,
-    width_scaled: u32,
-    height_scaled: u32,
-    img_buf: Vec<u8>,
+    mode: PyramidMode,
     img_buf_scaled: Vec<u8>,
-    img_buf_scaled_width: u32,
-    img_buf_scaled_height: u32,
+    img_buf_previous: Vec<u8>,
+    row_buf: Vec<f32>,
 }
 
 impl ImagePyramid {
     pub fn new() -> Self {
-        let img_buf_scaled_width: u32 = 2;
-        let img_buf_scaled_height: u32 = 2;
-
         ImagePyramid {
             max_scale: 1.0,
             min_scale: 1.0,
-            scale_factor: 1.0,
             scale_step: 0.8,
-            width1x: 0,
-            height1x: 0,
-            width_scaled: 0,
-            height_scaled: 0,
-            img_buf: Vec::new(),
-            img_buf_scaled: Vec::with_capacity(
-                (img_buf_scaled_width * img_buf_scaled_height) as usize,
-            ),
-            img_buf_scaled_width,
-            img_buf_scaled_height,
+            mode: PyramidMode::Bilinear,
+            img_buf_scaled: Vec::new(),
+            img_buf_previous: Vec::new(),
+            row_buf: Vec::new(),
         }
     }
 
-    #[allow(dead_code)]
+    /// Set the scale of the first (largest) image of the pyramid.
+    ///
+    /// Scales greater than 1 upsample the image with bilinear interpolation.
+    #[inline]
     pub fn set_max_scale(&mut self, max_scale: f32) {
         self.max_scale = max_scale;
-        self.scale_factor = max_scale;
-        self.update_buf_scaled();
     }
 
     #[inline]
@@ -116,76 +498,139 @@ impl ImagePyramid {
     }
 
     #[inline]
-    pub fn get_image_1x(&self) -> ImageData {
-        ImageData::new(&self.img_buf, self.width1x, self.height1x)
+    pub fn set_mode(&mut self, mode: PyramidMode) {
+        self.mode = mode;
     }
 
-    pub fn set_image_1x(&mut self, img_data: &[u8], width: u32, height: u32) {
-        self.width1x = width;
-        self.height1x = height;
-
-        self.img_buf.clear();
-        self.img_buf.extend_from_slice(img_data);
-
-        self.scale_factor = self.max_scale;
-        self.update_buf_scaled();
-    }
+    /// Start to iterate over the levels of the pyramid of an image, from the largest one.
+    pub fn levels<'p, 'a>(&'p mut self, image_1x: &ImageData<'a>) -> PyramidLevels<'p, 'a> {
+        let max_width = (image_1x.width() as f32 * self.max_scale) as usize;
+        let max_height = (image_1x.height() as f32 * self.max_scale) as usize;
+        let max_len = max_width * max_height;
+        reserve(&mut self.img_buf_scaled, max_len);
+        if self.mode == PyramidMode::AreaAveraging {
+            // the buffers are swapped, when a level is computed from the previous one
+            reserve(&mut self.img_buf_previous, max_len);
+            reserve(&mut self.row_buf, image_1x.width() as usize);
+        }
 
-    fn update_buf_scaled(&mut self) {
-        if self.width1x == 0 || self.height1x == 0 {
-            return;
+        PyramidLevels {
+            scale_factor: self.max_scale,
+            pyramid: self,
+            image_1x: *image_1x,
+            previous: None,
         }
+    }
+}
 
-        let max_width = (self.width1x as f32 * self.max_scale + 0.5) as u32;
-        let max_height = (self.height1x as f32 * self.max_scale + 0.5) as u32;
+impl Default for ImagePyramid {
+    fn default() -> Self {
+        ImagePyramid::new()
+    }
+}
 
-        if max_width > self.img_buf_scaled_width || max_height > self.img_buf_scaled_height {
-            self.img_buf_scaled_width = max_width;
-            self.img_buf_scaled_height = max_height;
-            self.img_buf_scaled = Vec::with_capacity((max_width * max_height) as usize);
-        }
+/// Replaces the buffer by a larger one, if it can not hold `len` elements, so that it
+/// does not grow step by step.
+fn reserve<T>(buf: &mut Vec<T>, len: usize) {
+    if buf.capacity() < len {
+        *buf = Vec::with_capacity(len);
     }
+}
 
-    pub fn get_next_scale_image(&mut self) -> Option<(ImageData, f32)> {
-        if self.scale_factor < self.min_scale {
+/// Cursor over the levels of an [`ImagePyramid`] of an image.
+pub struct PyramidLevels<'p, 'a> {
+    pyramid: &'p mut ImagePyramid,
+    image_1x: ImageData<'a>,
+    scale_factor: f32,
+    /// Size of the previous level, if it was downsampled and is kept in the scaled buffer.
+    previous: Option<(u32, u32)>,
+}
+
+impl<'p, 'a> PyramidLevels<'p, 'a> {
+    /// Compute the next level and return it with its scale, or `None` after the smallest one.
+    ///
+    /// The level is only valid until the next call, which overwrites its buffer.
+    pub fn next_level(&mut self) -> Option<(ImageData<'_>, f32)> {
+        if self.scale_factor < self.pyramid.min_scale {
             return None;
         }
 
         let scale_factor = self.scale_factor;
-        self.width_scaled = (self.width1x as f32 * scale_factor) as u32;
-        self.height_scaled = (self.height1x as f32 * scale_factor) as u32;
-
-        let src = ImageData::new(&self.img_buf, self.width1x, self.height1x);
-        resize_image(
-            &src,
-            &mut self.img_buf_scaled,
-            self.width_scaled,
-            self.height_scaled,
-        );
-        let img_scaled =
-            ImageData::new(&self.img_buf_scaled, self.width_scaled, self.height_scaled);
-        self.scale_factor *= self.scale_step;
+        self.scale_factor *= self.pyramid.scale_step;
+        let previous = self.previous.take();
+
+        if scale_factor == 1.0 {
+            return Some((self.image_1x, scale_factor));
+        }
+
+        let width_scaled = (self.image_1x.width() as f32 * scale_factor) as u32;
+        let height_scaled = (self.image_1x.height() as f32 * scale_factor) as u32;
+        let pyramid = &mut *self.pyramid;
+        if pyramid.mode == PyramidMode::AreaAveraging && scale_factor < 1.0 {
+            let src = match previous {
+                Some((width, height)) => {
+                    mem::swap(&mut pyramid.img_buf_scaled, &mut pyramid.img_buf_previous);
+                    ImageData::new(&pyramid.img_buf_previous, width, height)
+                }
+                None => self.image_1x,
+            };
+            resize_image_area(
+                &src,
+                &mut pyramid.img_buf_scaled,
+                &mut pyramid.row_buf,
+                width_scaled,
+                height_scaled,
+            );
+            self.previous = Some((width_scaled, height_scaled));
+        } else {
+            resize_image(
+                &self.image_1x,
+                &mut pyramid.img_buf_scaled,
+                width_scaled,
+                height_scaled,
+            );
+        }
+        let img_scaled = ImageData::new(&pyramid.img_buf_scaled, width_scaled, height_scaled);
 
         Some((img_scaled, scale_factor))
     }
 }
 
-pub fn resize_image(src: &ImageData, dest: &mut Vec<u8>, width: u32, height: u32) {
-    dest.clear();
+/// Sample a gray-scale image at a point by bilinear interpolation, where pixel `(i, j)` has
+/// its center at `(i + 0.5, j + 0.5)`. Points outside of the image repeat its edges.
+///
+/// Points at pixel centers yield the exact pixel values.
+pub(crate) fn sample_bilinear(image: &ImageData, x: f32, y: f32) -> f32 {
+    let max_x = image.width() as i32 - 1;
+    let max_y = image.height() as i32 - 1;
+    let pixel =
+        |x: i32, y: i32| f32::from(image.row(y.clamp(0, max_y) as u32)[x.clamp(0, max_x) as usize]);
+
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i32, y0 as i32);
+
+    if fx == 0.0 && fy == 0.0 {
+        return pixel(x0, y0);
+    }
+    let top = pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1, y0) * fx;
+    let bottom = pixel(x0, y0 + 1) * (1.0 - fx) + pixel(x0 + 1, y0 + 1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
 
+pub fn resize_image(src: &ImageData, dest: &mut Vec<u8>, width: u32, height: u32) {
     if src.width() == width && src.height() == height {
-        dest.extend_from_slice(src.data());
+        src.copy_to(dest);
         return;
     }
 
-    unsafe {
-        let area = width as usize * height as usize;
-        dest.reserve(area);
-        dest.set_len(area);
-    }
+    dest.clear();
+    dest.resize(width as usize * height as usize, 0);
 
     let dest = dest.as_mut_ptr();
     let src_data = src.data().as_ptr();
+    let src_stride = src.stride();
 
     let lf_x_scl = f64::from(src.width()) / f64::from(width);
     let lf_y_scl = f64::from(src.height()) / f64::from(height);
@@ -202,11 +647,11 @@ pub fn resize_image(src: &ImageData, dest: &mut Vec<u8>, width: u32, height: u32
                 let lf_weight_x = lf_x_s - f64::from(n_x_s);
                 let lf_weight_y = lf_y_s - f64::from(n_y_s);
 
-                let d1 = f64::from(*src_data.offset((n_y_s * src.width() + n_x_s) as isize));
-                let d2 = f64::from(*src_data.offset((n_y_s * src.width() + n_x_s + 1) as isize));
-                let d3 = f64::from(*src_data.offset(((n_y_s + 1) * src.width() + n_x_s) as isize));
+                let d1 = f64::from(*src_data.offset((n_y_s * src_stride + n_x_s) as isize));
+                let d2 = f64::from(*src_data.offset((n_y_s * src_stride + n_x_s + 1) as isize));
+                let d3 = f64::from(*src_data.offset(((n_y_s + 1) * src_stride + n_x_s) as isize));
                 let d4 =
-                    f64::from(*src_data.offset(((n_y_s + 1) * src.width() + n_x_s + 1) as isize));
+                    f64::from(*src_data.offset(((n_y_s + 1) * src_stride + n_x_s + 1) as isize));
 
                 let dest_val = (1.0 - lf_weight_y) * ((1.0 - lf_weight_x) * d1 + lf_weight_x * d2)
                     + lf_weight_y * ((1.0 - lf_weight_x) * d3 + lf_weight_x * d4);
@@ -216,3 +661,266 @@ pub fn resize_image(src: &ImageData, dest: &mut Vec<u8>, width: u32, height: u32
         }
     }
 }
+
+/// Shrink an image by averaging the pixels, which each pixel of the destination covers,
+/// weighted by the covered area. Unlike bilinear interpolation, every pixel of the source
+/// contributes, so fine details do not alias when shrinking by large factors.
+///
+/// `row_buf` holds a row of the source, which is averaged vertically.
+///
+/// # Panics
+///
+/// Panics if the destination is larger than the source in either direction.
+fn resize_image_area(
+    src: &ImageData,
+    dest: &mut Vec<u8>,
+    row_buf: &mut Vec<f32>,
+    width: u32,
+    height: u32,
+) {
+    assert!(width <= src.width() && height <= src.height());
+    if src.width() == width && src.height() == height {
+        src.copy_to(dest);
+        return;
+    }
+
+    dest.clear();
+    dest.resize(width as usize * height as usize, 0);
+    row_buf.clear();
+    row_buf.resize(src.width() as usize, 0.0);
+
+    let x_ratio = f64::from(src.width()) / f64::from(width);
+    let y_ratio = f64::from(src.height()) / f64::from(height);
+    let area = (x_ratio * y_ratio) as f32;
+
+    for (y, dest_row) in dest.chunks_exact_mut(width as usize).enumerate() {
+        row_buf.iter_mut().for_each(|v| *v = 0.0);
+        for_each_covered(y, y_ratio, src.height(), |src_y, weight| {
+            for (sum, &value) in row_buf.iter_mut().zip(src.row(src_y as u32)) {
+                *sum += weight * f32::from(value);
+            }
+        });
+
+        for (x, value) in dest_row.iter_mut().enumerate() {
+            let mut sum = 0.0;
+            for_each_covered(x, x_ratio, src.width(), |src_x, weight| {
+                sum += weight * row_buf[src_x];
+            });
+            *value = (sum / area + 0.5) as u8;
+        }
+    }
+}
+
+/// Calls `f` with the index and the covered length of each source pixel, which is covered
+/// by the destination pixel `i` of a row or column, shrunk by `ratio`.
+#[inline]
+fn for_each_covered<F: FnMut(usize, f32)>(i: usize, ratio: f64, src_len: u32, mut f: F) {
+    let start = i as f64 * ratio;
+    let end = ((i + 1) as f64 * ratio).min(f64::from(src_len));
+    for src_i in start.floor() as usize..end.ceil() as usize {
+        let covered = end.min((src_i + 1) as f64) - start.max(src_i as f64);
+        if covered > 0.0 {
+            f(src_i, covered as f32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        resize_image, resize_image_area, ImageData, ImagePyramid, PixelFormat, PyramidMode,
+        YuvFormat,
+    };
+
+    fn load_test_image() -> image::GrayImage {
+        image::open("assets/test/scientists.jpg")
+            .unwrap()
+            .to_luma8()
+    }
+
+    #[test]
+    fn test_pyramid_borrows_level_of_scale_1() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height).sub_image(10, 10, 700, 360);
+
+        let mut pyramid = ImagePyramid::new();
+        pyramid.set_min_scale(0.5);
+        let mut levels = pyramid.levels(&image);
+        let (level, scale) = levels.next_level().unwrap();
+        assert_eq!(1.0, scale);
+        assert_eq!(image.data().as_ptr(), level.data().as_ptr());
+        assert_eq!(image.stride(), level.stride());
+
+        let (level, scale) = levels.next_level().unwrap();
+        assert_eq!(0.8, scale);
+        assert_eq!((560, 288), (level.width(), level.height()));
+        // 0.64 and 0.512
+        assert!(levels.next_level().is_some());
+        assert!(levels.next_level().is_some());
+        assert!(levels.next_level().is_none());
+    }
+
+    #[test]
+    fn test_resize_image_area() {
+        let mut dest = Vec::new();
+        let mut row_buf = Vec::new();
+        let src = ImageData::new(&[0, 90, 180, 10, 20, 30], 3, 2);
+        resize_image_area(&src, &mut dest, &mut row_buf, 2, 1);
+        // rows (0 + 90 / 2) / 1.5 = 30 and (10 + 20 / 2) / 1.5 = 13.3 averaged, and so on
+        assert_eq!(vec![22, 88], dest);
+
+        // a fine checkerboard shrinks to gray, where bilinear interpolation aliases
+        let checkerboard: Vec<u8> = (0..100 * 100)
+            .map(|i| if (i % 100 + i / 100) % 2 == 0 { 0 } else { 255 })
+            .collect();
+        let src = ImageData::new(&checkerboard, 100, 100);
+        resize_image_area(&src, &mut dest, &mut row_buf, 30, 30);
+        assert!(dest.iter().all(|&v| (100..=155).contains(&v)));
+        resize_image(&src, &mut dest, 30, 30);
+        assert!(dest.iter().any(|&v| !(100..=155).contains(&v)));
+    }
+
+    #[test]
+    fn test_area_averaging_pyramid() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height).sub_image(0, 0, 700, 360);
+
+        let mut pyramid = ImagePyramid::new();
+        pyramid.set_mode(PyramidMode::AreaAveraging);
+        pyramid.set_max_scale(1.25);
+        pyramid.set_min_scale(0.3);
+        let mut levels = pyramid.levels(&image);
+
+        // enlarged bilinearly, then shrunk from the image and from the previous levels
+        let mut sizes = Vec::new();
+        while let Some((level, scale)) = levels.next_level() {
+            let mean = level.rows().flatten().map(|&v| f64::from(v)).sum::<f64>()
+                / f64::from(level.width() * level.height());
+            sizes.push((level.width(), level.height(), scale, mean.round()));
+        }
+        let image_mean = image.rows().flatten().map(|&v| f64::from(v)).sum::<f64>()
+            / f64::from(image.width() * image.height());
+        assert_eq!(7, sizes.len());
+        assert_eq!((875, 450), (sizes[0].0, sizes[0].1));
+        assert_eq!((700, 360), (sizes[1].0, sizes[1].1));
+        for &(width, height, scale, mean) in &sizes {
+            assert_eq!((700.0 * scale) as u32, width);
+            assert_eq!((360.0 * scale) as u32, height);
+            // averaging preserves the brightness
+            assert!((mean - image_mean).abs() < 2.0);
+        }
+    }
+
+    #[test]
+    fn test_to_gray_keeps_gray_image() {
+        let data = [1, 2, 3, 4];
+        let mut buf = Vec::new();
+        let gray = ImageData::new(&data, 2, 2).to_gray(&mut buf);
+        assert_eq!(&data, gray.data());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_to_gray_bt601() {
+        let data = [255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let mut buf = Vec::new();
+        let gray = ImageData::from_rgb(&data, 5, 1).to_gray(&mut buf);
+        assert_eq!(PixelFormat::Gray, gray.pixel_format());
+        assert_eq!(&[255, 0, 77, 149, 29], gray.data());
+    }
+
+    #[test]
+    fn test_to_gray_channel_order() {
+        let rgba = [10, 20, 30, 255, 200, 100, 50, 0];
+        let bgra = [30, 20, 10, 255, 50, 100, 200, 0];
+        let bgr = [30, 20, 10, 50, 100, 200];
+        let (mut buf1, mut buf2, mut buf3) = (Vec::new(), Vec::new(), Vec::new());
+        let from_rgba = ImageData::from_rgba(&rgba, 1, 2).to_gray(&mut buf1);
+        let from_bgra = ImageData::from_bgra(&bgra, 1, 2).to_gray(&mut buf2);
+        let from_bgr = ImageData::from_bgr(&bgr, 1, 2).to_gray(&mut buf3);
+        assert_eq!(from_rgba.data(), from_bgra.data());
+        assert_eq!(from_rgba.data(), from_bgr.data());
+    }
+
+    #[test]
+    fn test_stride_rows() {
+        // 2x2 image with one byte of padding per row, padding omitted after the last row
+        let data = [1, 2, 0, 3, 4];
+        let image = ImageData::with_stride(&data, 2, 2, 3);
+        assert!(!image.is_contiguous());
+        assert_eq!(
+            vec![&[1, 2][..], &[3, 4][..]],
+            image.rows().collect::<Vec<_>>()
+        );
+
+        let mut buf = Vec::new();
+        image.copy_to(&mut buf);
+        assert_eq!(vec![1, 2, 3, 4], buf);
+    }
+
+    #[test]
+    fn test_sub_image() {
+        let data: Vec<u8> = (0..20).collect();
+        let image = ImageData::new(&data, 5, 4);
+        let sub_image = image.sub_image(1, 2, 3, 2);
+        assert_eq!(5, sub_image.stride());
+        assert_eq!(
+            vec![&[11, 12, 13][..], &[16, 17, 18][..]],
+            sub_image.rows().collect::<Vec<_>>()
+        );
+
+        let mut resized = Vec::new();
+        resize_image(&sub_image, &mut resized, 3, 2);
+        assert_eq!(vec![11, 12, 13, 16, 17, 18], resized);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_sub_image_out_of_bounds() {
+        let data = [0; 4];
+        ImageData::new(&data, 2, 2).sub_image(1, 1, 2, 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_stride_less_than_width() {
+        ImageData::with_stride(&[0; 4], 2, 2, 1);
+    }
+
+    #[test]
+    fn test_from_yuv() {
+        // 3x3 frame: 9 luma samples, followed by 2x2 samples for each of U and V
+        let data: Vec<u8> = (0..17).collect();
+        let image = ImageData::from_yuv(&data, 3, 3, YuvFormat::I420);
+        assert_eq!(PixelFormat::Gray, image.pixel_format());
+        assert_eq!(&data[..9], image.data());
+
+        let image = ImageData::from_yuv(&data, 3, 3, YuvFormat::Nv12);
+        assert_eq!(&data[..9], image.data());
+    }
+
+    #[test]
+    fn test_from_yuv_with_stride() {
+        // 2x2 frame with 4 bytes per luma row and 2 bytes per chroma row
+        let data = [1, 2, 0, 0, 3, 4, 0, 0, 5, 0, 6, 0];
+        let image = ImageData::from_yuv_with_stride(&data, 2, 2, 4, YuvFormat::I420);
+        assert_eq!(
+            vec![&[1, 2][..], &[3, 4][..]],
+            image.rows().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_from_yuv_without_chroma() {
+        ImageData::from_yuv(&[0; 16], 4, 4, YuvFormat::Nv21);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_rgb_data_length_mismatch() {
+        ImageData::from_rgb(&[0; 4], 2, 2);
+    }
+}
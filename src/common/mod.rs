@@ -20,9 +20,20 @@ mod image_pyramid;
 
 use std::mem;
 
-pub use self::image_pyramid::{resize_image, ImageData, ImagePyramid};
+pub(crate) use self::image_pyramid::sample_bilinear;
+pub use self::image_pyramid::{
+    resize_image, ImageData, ImagePyramid, PixelFormat, PyramidMode, YuvFormat,
+};
 
-#[derive(Copy, Clone, Debug)]
+/// Axis-aligned rectangle in pixel coordinates.
+///
+/// A rectangle covers the pixels with `x <= px < x + width` and `y <= py < y + height`,
+/// i.e. the left and top edges are inclusive and the right and bottom edges are exclusive.
+/// So a rectangle of width 0 or height 0 is empty, and two rectangles which only touch
+/// each other do not intersect.
+///
+/// Edges, which lie beyond the range of `i32`, saturate at `i32::MIN` or `i32::MAX`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Rectangle {
     x: i32,
     y: i32,
@@ -80,12 +91,224 @@ impl Rectangle {
     pub fn set_height(&mut self, height: u32) {
         self.height = height;
     }
+
+    /// The exclusive right edge, i.e. `x + width`.
+    #[inline]
+    pub fn right(&self) -> i32 {
+        self.x.saturating_add_unsigned(self.width)
+    }
+
+    /// The exclusive bottom edge, i.e. `y + height`.
+    #[inline]
+    pub fn bottom(&self) -> i32 {
+        self.y.saturating_add_unsigned(self.height)
+    }
+
+    /// The rectangle between the given edges, which are clamped to the range of `i32`.
+    fn from_edges(x: i64, y: i64, right: i64, bottom: i64) -> Rectangle {
+        let clamp = |v: i64| v.clamp(i64::from(i32::MIN), i64::from(i32::MAX)) as i32;
+        let (x, y) = (clamp(x), clamp(y));
+        let (right, bottom) = (clamp(right).max(x), clamp(bottom).max(y));
+        Rectangle::new(x, y, right.abs_diff(x), bottom.abs_diff(y))
+    }
+
+    /// Number of pixels covered by the rectangle.
+    #[inline]
+    pub fn area(&self) -> u64 {
+        u64::from(self.width) * u64::from(self.height)
+    }
+
+    /// Whether the rectangle covers no pixels.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The center point, which lies between pixels for rectangles of even size.
+    #[inline]
+    pub fn center(&self) -> (f32, f32) {
+        (
+            self.x as f32 + self.width as f32 / 2.0,
+            self.y as f32 + self.height as f32 / 2.0,
+        )
+    }
+
+    /// Whether the pixel at (`x`, `y`) lies within the rectangle.
+    #[inline]
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        self.x <= x && x < self.right() && self.y <= y && y < self.bottom()
+    }
+
+    /// The pixels covered by both rectangles, or `None` if they do not intersect.
+    pub fn intersection(&self, other: &Rectangle) -> Option<Rectangle> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(Rectangle::new(x, y, right.abs_diff(x), bottom.abs_diff(y)))
+    }
+
+    /// The smallest rectangle containing both rectangles.
+    pub fn union(&self, other: &Rectangle) -> Rectangle {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Rectangle::new(x, y, right.abs_diff(x), bottom.abs_diff(y))
+    }
+
+    /// Intersection over union of the areas of both rectangles, in [0, 1].
+    ///
+    /// Returns 0 if the rectangles do not intersect.
+    pub fn iou(&self, other: &Rectangle) -> f32 {
+        let area_intersect = match self.intersection(other) {
+            Some(intersection) => intersection.area() as f32,
+            None => return 0.0,
+        };
+        let area_union = self.area() as f32 + other.area() as f32 - area_intersect;
+        area_intersect / area_union
+    }
+
+    /// The rectangle scaled by `factor` about its center, with the size rounded to whole pixels.
+    pub fn scale(&self, factor: f32) -> Rectangle {
+        let (center_x, center_y) = self.center();
+        let width = (self.width as f32 * factor).round().max(0.0);
+        let height = (self.height as f32 * factor).round().max(0.0);
+        Rectangle::new(
+            (center_x - width / 2.0).round() as i32,
+            (center_y - height / 2.0).round() as i32,
+            width as u32,
+            height as u32,
+        )
+    }
+
+    /// The rectangle grown by `margin` pixels on each side.
+    pub fn expand(&self, margin: u32) -> Rectangle {
+        let margin = i64::from(margin);
+        Rectangle::from_edges(
+            i64::from(self.x) - margin,
+            i64::from(self.y) - margin,
+            i64::from(self.right()) + margin,
+            i64::from(self.bottom()) + margin,
+        )
+    }
+
+    /// The part of the rectangle within an image of the given size.
+    ///
+    /// The result is empty, if the rectangle lies outside of the image.
+    pub fn clamp(&self, width: u32, height: u32) -> Rectangle {
+        self.intersection(&Rectangle::new(0, 0, width, height))
+            .unwrap_or_else(|| {
+                Rectangle::new(
+                    self.x.clamp(0, i32::try_from(width).unwrap_or(i32::MAX)),
+                    self.y.clamp(0, i32::try_from(height).unwrap_or(i32::MAX)),
+                    0,
+                    0,
+                )
+            })
+    }
+}
+
+/// Rectangle rotated about its center, in pixel coordinates.
+///
+/// The angle is measured in degrees, clockwise as displayed, i.e. with the y axis pointing down.
+/// A rectangle with angle 0 covers the same area as the axis-aligned [`Rectangle`] with the same
+/// center and size.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RotatedRectangle {
+    center_x: f32,
+    center_y: f32,
+    width: u32,
+    height: u32,
+    angle: f32,
+}
+
+impl RotatedRectangle {
+    #[inline]
+    pub fn new(center: (f32, f32), width: u32, height: u32, angle: f32) -> Self {
+        RotatedRectangle {
+            center_x: center.0,
+            center_y: center.1,
+            width,
+            height,
+            angle,
+        }
+    }
+
+    #[inline]
+    pub fn center(&self) -> (f32, f32) {
+        (self.center_x, self.center_y)
+    }
+
+    #[inline]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[inline]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[inline]
+    pub fn angle(&self) -> f32 {
+        self.angle
+    }
+
+    /// The corners, starting with the top left corner of the unrotated rectangle
+    /// and going clockwise.
+    pub fn corners(&self) -> [(f32, f32); 4] {
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let half_width = self.width as f32 / 2.0;
+        let half_height = self.height as f32 / 2.0;
+        [
+            (-half_width, -half_height),
+            (half_width, -half_height),
+            (half_width, half_height),
+            (-half_width, half_height),
+        ]
+        .map(|(dx, dy)| {
+            (
+                self.center_x + dx * cos - dy * sin,
+                self.center_y + dx * sin + dy * cos,
+            )
+        })
+    }
+
+    /// The smallest axis-aligned rectangle containing this one, rounded to whole pixels.
+    pub fn bounding_rect(&self) -> Rectangle {
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        let (sin, cos) = (sin.abs(), cos.abs());
+        let width = (self.width as f32 * cos + self.height as f32 * sin).round();
+        let height = (self.width as f32 * sin + self.height as f32 * cos).round();
+        Rectangle::new(
+            (self.center_x - width / 2.0).round() as i32,
+            (self.center_y - height / 2.0).round() as i32,
+            width as u32,
+            height as u32,
+        )
+    }
+}
+
+impl From<Rectangle> for RotatedRectangle {
+    fn from(rect: Rectangle) -> Self {
+        RotatedRectangle::new(rect.center(), rect.width, rect.height, 0.0)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct FaceInfo {
     bbox: Rectangle,
     score: f64,
+    confidence: Option<f64>,
+    roll: Option<f32>,
+    yaw: Option<f32>,
+    yaw_bin: Option<usize>,
+    rotated_bbox: Option<RotatedRectangle>,
+    provenance: Option<Box<Provenance>>,
 }
 
 impl Default for FaceInfo {
@@ -93,6 +316,12 @@ impl Default for FaceInfo {
         FaceInfo {
             bbox: Rectangle::new(0, 0, 0, 0),
             score: 0.0,
+            confidence: None,
+            roll: None,
+            yaw: None,
+            yaw_bin: None,
+            rotated_bbox: None,
+            provenance: None,
         }
     }
 }
@@ -113,6 +342,24 @@ impl FaceInfo {
         &mut self.bbox
     }
 
+    /// The bounding box of the face, rotated by the [`roll`](FaceInfo::roll) of the face.
+    ///
+    /// Faces found by the upright detector have a rotation angle of 0. The axis-aligned
+    /// [`bbox`](FaceInfo::bbox) is the bounding rectangle of the rotated one.
+    #[inline]
+    pub fn rotated_bbox(&self) -> RotatedRectangle {
+        self.rotated_bbox
+            .unwrap_or_else(|| RotatedRectangle::from(self.bbox))
+    }
+
+    /// Set the rotated bounding box and the roll of the face, and replace the bounding box
+    /// by its bounding rectangle.
+    pub(crate) fn set_rotated_bbox(&mut self, rotated_bbox: RotatedRectangle) {
+        self.bbox = rotated_bbox.bounding_rect();
+        self.roll = Some(rotated_bbox.angle());
+        self.rotated_bbox = Some(rotated_bbox);
+    }
+
     #[inline]
     pub fn set_score(&mut self, score: f64) {
         self.score = score;
@@ -122,6 +369,127 @@ impl FaceInfo {
     pub fn score(&self) -> f64 {
         self.score
     }
+
+    /// Calibrated confidence of the detection in [0, 1].
+    ///
+    /// Only available if the model has a calibration,
+    /// see [`Model::with_calibration`](crate::Model::with_calibration).
+    #[inline]
+    pub fn confidence(&self) -> Option<f64> {
+        self.confidence
+    }
+
+    #[inline]
+    pub(crate) fn set_confidence(&mut self, confidence: f64) {
+        self.confidence = Some(confidence);
+    }
+
+    /// In-plane rotation of the face in degrees, clockwise as displayed, if known.
+    ///
+    /// Only estimated when searching for rotated faces,
+    /// see [`DetectorConfig::roll_angles`](crate::DetectorConfig::roll_angles).
+    #[inline]
+    pub fn roll(&self) -> Option<f32> {
+        self.roll
+    }
+
+    /// Coarse out-of-plane rotation of the face around the vertical axis in degrees, if known.
+    ///
+    /// This is the angle of the view of the first-hierarchy branch which found the face,
+    /// so it is only available if the model has them, see
+    /// [`Model::with_branch_yaws`](crate::Model::with_branch_yaws).
+    #[inline]
+    pub fn yaw(&self) -> Option<f32> {
+        self.yaw
+    }
+
+    /// Coarse yaw bin of the face, i.e. the index of the view-specific first-hierarchy branch
+    /// which found the face. Available for all detected faces.
+    ///
+    /// The branches of a model are trained on different views, so faces with the same bin
+    /// have a similar yaw. The angle of the bin is available as [`yaw`](FaceInfo::yaw),
+    /// if the model has the views of its branches.
+    #[inline]
+    pub fn yaw_bin(&self) -> Option<usize> {
+        self.yaw_bin
+    }
+
+    #[inline]
+    pub(crate) fn set_yaw(&mut self, yaw: f32) {
+        self.yaw = Some(yaw);
+    }
+
+    #[inline]
+    pub(crate) fn set_yaw_bin(&mut self, yaw_bin: usize) {
+        self.yaw_bin = Some(yaw_bin);
+    }
+
+    /// How the detection was obtained by the detector.
+    ///
+    /// Only available if the detector collects provenance,
+    /// see [`DetectorConfig::collect_provenance`](crate::DetectorConfig::collect_provenance).
+    #[inline]
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.provenance.as_deref()
+    }
+
+    #[inline]
+    pub(crate) fn provenance_mut(&mut self) -> Option<&mut Provenance> {
+        self.provenance.as_deref_mut()
+    }
+
+    #[inline]
+    pub(crate) fn set_provenance(&mut self, provenance: Provenance) {
+        self.provenance = Some(Box::new(provenance));
+    }
+}
+
+/// Details on how a detection was obtained, for calibration and debugging.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provenance {
+    pub(crate) pyramid_scale: f32,
+    pub(crate) stage_scores: Vec<f32>,
+    pub(crate) regression_outputs: Vec<[f32; 3]>,
+    pub(crate) num_merged_windows: usize,
+}
+
+impl Provenance {
+    pub(crate) fn new(pyramid_scale: f32, score: f32) -> Self {
+        Provenance {
+            pyramid_scale,
+            stage_scores: vec![score],
+            regression_outputs: Vec::new(),
+            num_merged_windows: 1,
+        }
+    }
+
+    /// Scale factor of the image pyramid level, at which the sliding window was found.
+    #[inline]
+    pub fn pyramid_scale(&self) -> f32 {
+        self.pyramid_scale
+    }
+
+    /// Score of the detection at each stage of the cascade, starting with the first hierarchy.
+    ///
+    /// The scores are those of a single window, before merging by non-maximum suppression.
+    #[inline]
+    pub fn stage_scores(&self) -> &[f32] {
+        &self.stage_scores
+    }
+
+    /// Raw bounding box regression outputs of each MLP stage: the horizontal and vertical
+    /// offset and the size, each in (0, 1), with 0.5 meaning no change.
+    #[inline]
+    pub fn regression_outputs(&self) -> &[[f32; 3]] {
+        &self.regression_outputs
+    }
+
+    /// Number of sliding windows, which were merged into the detection
+    /// by non-maximum suppression.
+    #[inline]
+    pub fn num_merged_windows(&self) -> usize {
+        self.num_merged_windows
+    }
 }
 
 pub struct Seq<T, G>
@@ -160,7 +528,100 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::Seq;
+    use super::{Rectangle, RotatedRectangle, Seq};
+
+    #[test]
+    fn test_rectangle_edges_are_half_open() {
+        let rect = Rectangle::new(2, 3, 4, 5);
+        assert_eq!((6, 8), (rect.right(), rect.bottom()));
+        assert_eq!(20, rect.area());
+        assert_eq!((4.0, 5.5), rect.center());
+        assert!(rect.contains(2, 3));
+        assert!(rect.contains(5, 7));
+        assert!(!rect.contains(6, 7));
+        assert!(!rect.contains(5, 8));
+        assert!(!rect.contains(1, 3));
+        assert!(Rectangle::new(2, 3, 0, 5).is_empty());
+        assert!(!Rectangle::new(2, 3, 0, 5).contains(2, 3));
+    }
+
+    #[test]
+    fn test_rectangle_intersection_and_union() {
+        let a = Rectangle::new(0, 0, 10, 10);
+        let b = Rectangle::new(5, -5, 10, 10);
+        assert_eq!(Some(Rectangle::new(5, 0, 5, 5)), a.intersection(&b));
+        assert_eq!(Rectangle::new(0, -5, 15, 15), a.union(&b));
+
+        // touching rectangles do not intersect
+        let c = Rectangle::new(10, 0, 10, 10);
+        assert_eq!(None, a.intersection(&c));
+        assert_eq!(Rectangle::new(0, 0, 20, 10), a.union(&c));
+    }
+
+    #[test]
+    fn test_rectangle_iou() {
+        let a = Rectangle::new(0, 0, 10, 10);
+        assert_eq!(1.0, a.iou(&a));
+        assert_eq!(0.0, a.iou(&Rectangle::new(10, 0, 10, 10)));
+        // intersection 50, union 150
+        assert_eq!(1.0 / 3.0, a.iou(&Rectangle::new(5, 0, 10, 10)));
+    }
+
+    #[test]
+    fn test_rectangle_scale_expand_and_clamp() {
+        let rect = Rectangle::new(10, 10, 20, 20);
+        assert_eq!(Rectangle::new(5, 5, 30, 30), rect.scale(1.5));
+        assert_eq!(Rectangle::new(15, 15, 10, 10), rect.scale(0.5));
+        assert_eq!(Rectangle::new(5, 5, 30, 30), rect.expand(5));
+
+        assert_eq!(Rectangle::new(0, 0, 45, 25), rect.expand(15).clamp(100, 25));
+        assert_eq!(rect, rect.clamp(100, 100));
+        assert!(rect.clamp(5, 100).is_empty());
+    }
+
+    #[test]
+    fn test_rectangle_saturates_at_i32_range() {
+        let rect = Rectangle::new(i32::MAX - 10, i32::MIN, u32::MAX, u32::MAX);
+        assert_eq!((i32::MAX, i32::MAX), (rect.right(), rect.bottom()));
+
+        let expanded = Rectangle::new(10, 10, 20, 20).expand(u32::MAX);
+        assert_eq!(
+            Rectangle::new(i32::MIN, i32::MIN, u32::MAX, u32::MAX),
+            expanded
+        );
+        assert_eq!(
+            Rectangle::new(0, 0, 100, 50),
+            Rectangle::new(i32::MAX, i32::MAX, u32::MAX, u32::MAX)
+                .expand(u32::MAX)
+                .clamp(100, 50)
+        );
+        assert_eq!(
+            Rectangle::new(i32::MAX - 10, 0, 10, 10),
+            rect.expand(0).clamp(u32::MAX, 10)
+        );
+
+        let huge = Rectangle::new(i32::MIN, i32::MIN, u32::MAX, u32::MAX);
+        assert_eq!(huge, huge.union(&Rectangle::new(i32::MAX - 1, 0, 1, 1)));
+        assert_eq!(Some(huge), huge.intersection(&huge));
+        assert!(Rectangle::new(0, 0, 10, 10).clamp(u32::MAX, 0).is_empty());
+    }
+
+    #[test]
+    fn test_rotated_rectangle() {
+        let rect = Rectangle::new(10, 20, 40, 20);
+        let rotated = RotatedRectangle::from(rect);
+        assert_eq!((30.0, 30.0), rotated.center());
+        assert_eq!(rect, rotated.bounding_rect());
+
+        let rotated = RotatedRectangle::new((30.0, 30.0), 40, 20, 90.0);
+        assert_eq!(Rectangle::new(20, 10, 20, 40), rotated.bounding_rect());
+        let corners = rotated.corners();
+        assert!((corners[0].0 - 40.0).abs() < 1e-4 && (corners[0].1 - 10.0).abs() < 1e-4);
+        assert!((corners[2].0 - 20.0).abs() < 1e-4 && (corners[2].1 - 50.0).abs() < 1e-4);
+
+        let rotated = RotatedRectangle::new((0.0, 0.0), 10, 10, 45.0);
+        assert_eq!(Rectangle::new(-7, -7, 14, 14), rotated.bounding_rect());
+    }
 
     #[test]
     pub fn test_seq_take() {
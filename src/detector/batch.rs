@@ -0,0 +1,52 @@
+//! Detection on batches of images with a workspace per thread.
+
+use super::DetectorWorkspace;
+use crate::{Detector, Error, FaceInfo, ImageData};
+use std::cell::RefCell;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+thread_local! {
+    /// Workspace of the current thread for batch detection. It lives as long as the thread,
+    /// so the worker threads of a pool keep their buffers between batches, at the size
+    /// of the largest image they processed. The buffers are freed when the thread exits.
+    static WORKSPACE: RefCell<DetectorWorkspace> = RefCell::new(DetectorWorkspace::new());
+}
+
+/// Runs `f` with the workspace of the current thread.
+///
+/// A worker thread, which waits for the parallel parts of a detection, may pick up another
+/// image of the batch, while the workspace is still borrowed. Such a nested detection gets
+/// a temporary workspace.
+fn with_thread_workspace<R>(f: impl FnOnce(&mut DetectorWorkspace) -> R) -> R {
+    WORKSPACE.with(|workspace| match workspace.try_borrow_mut() {
+        Ok(mut workspace) => f(&mut workspace),
+        Err(_) => f(&mut DetectorWorkspace::new()),
+    })
+}
+
+pub fn detect_batch<D: Detector + ?Sized>(
+    detector: &D,
+    images: &[ImageData],
+) -> Result<Vec<Vec<FaceInfo>>, Error> {
+    #[cfg(feature = "rayon")]
+    let it = images.par_iter();
+
+    #[cfg(not(feature = "rayon"))]
+    let it = images.iter();
+
+    it.map(|image| {
+        with_thread_workspace(|workspace| detector.try_detect_with_workspace(workspace, image))
+    })
+    .collect()
+}
+
+#[cfg(feature = "rayon")]
+pub fn detect_batch_in_pool<D: Detector + ?Sized>(
+    detector: &D,
+    images: &[ImageData],
+    pool: &rayon::ThreadPool,
+) -> Result<Vec<Vec<FaceInfo>>, Error> {
+    pool.install(|| detect_batch(detector, images))
+}
@@ -0,0 +1,359 @@
+//! Validated settings of the detector.
+
+use crate::common::PyramidMode;
+use crate::Error;
+
+use super::{NmsStrategy, FUST_MIN_WINDOW_SIZE};
+
+/// Detection settings of a [`Detector`](crate::Detector).
+///
+/// Use [`DetectorConfig::builder`] to create a validated configuration and
+/// [`create_detector_with_config`](crate::create_detector_with_config) to apply it.
+/// The default configuration matches the defaults of a newly created detector.
+///
+/// With the `serde` feature, the configuration can be serialized and deserialized.
+/// Missing fields take their default values.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct DetectorConfig {
+    pub(super) min_face_size: u32,
+    pub(super) max_face_size: Option<u32>,
+    pub(super) slide_window_step_x: u32,
+    pub(super) slide_window_step_y: u32,
+    pub(super) pyramid_scale_factor: f32,
+    pub(super) pyramid_mode: PyramidMode,
+    pub(super) score_thresh: f64,
+    pub(super) region_margin: u32,
+    pub(super) nms_strategy: NmsStrategy,
+    pub(super) nms_iou_thresh: f32,
+    pub(super) collect_provenance: bool,
+    pub(super) roll_angles: Vec<f32>,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        DetectorConfig {
+            min_face_size: 40,
+            max_face_size: None,
+            slide_window_step_x: 4,
+            slide_window_step_y: 4,
+            pyramid_scale_factor: 0.8,
+            pyramid_mode: PyramidMode::Bilinear,
+            score_thresh: 3.85,
+            region_margin: 0,
+            nms_strategy: NmsStrategy::ScoreSum,
+            nms_iou_thresh: 0.3,
+            collect_provenance: false,
+            roll_angles: vec![0.0],
+        }
+    }
+}
+
+impl DetectorConfig {
+    /// Create a builder, starting from the default configuration.
+    pub fn builder() -> DetectorConfigBuilder {
+        DetectorConfigBuilder {
+            config: DetectorConfig::default(),
+        }
+    }
+
+    /// Minimum size of faces to detect.
+    pub fn min_face_size(&self) -> u32 {
+        self.min_face_size
+    }
+
+    /// Maximum size of faces to detect, `None` if it is only limited by the image size.
+    pub fn max_face_size(&self) -> Option<u32> {
+        self.max_face_size
+    }
+
+    /// Sliding window step in horizontal and vertical directions.
+    pub fn slide_window_step(&self) -> (u32, u32) {
+        (self.slide_window_step_x, self.slide_window_step_y)
+    }
+
+    /// Factor between adjacent scales of image pyramid.
+    pub fn pyramid_scale_factor(&self) -> f32 {
+        self.pyramid_scale_factor
+    }
+
+    /// How the levels of image pyramid are computed.
+    pub fn pyramid_mode(&self) -> PyramidMode {
+        self.pyramid_mode
+    }
+
+    /// Score threshold of detected faces.
+    pub fn score_thresh(&self) -> f64 {
+        self.score_thresh
+    }
+
+    /// Margin in pixels, by which regions are expanded when detecting faces in regions.
+    pub fn region_margin(&self) -> u32 {
+        self.region_margin
+    }
+
+    /// Strategy of non-maximum suppression after the last stage of the detector.
+    pub fn nms_strategy(&self) -> NmsStrategy {
+        self.nms_strategy
+    }
+
+    /// IoU threshold of non-maximum suppression after the last stage of the detector.
+    pub fn nms_iou_thresh(&self) -> f32 {
+        self.nms_iou_thresh
+    }
+
+    /// Whether detected faces carry their [`Provenance`](crate::Provenance).
+    pub fn collect_provenance(&self) -> bool {
+        self.collect_provenance
+    }
+
+    /// In-plane rotations of faces to search for, in degrees clockwise as displayed.
+    pub fn roll_angles(&self) -> &[f32] {
+        &self.roll_angles
+    }
+
+    /// Whether the detector searches for rotated faces rather than only upright ones.
+    pub(super) fn searches_rotations(&self) -> bool {
+        self.roll_angles != [0.0]
+    }
+
+    /// Check that all settings lie in their legal ranges.
+    ///
+    /// Returns the error for the first illegal setting, see the `try_set_*` methods
+    /// of [`Detector`](crate::Detector) for the ranges.
+    pub fn validate(&self) -> Result<(), Error> {
+        check_min_face_size(self.min_face_size)?;
+        check_slide_window_step(self.slide_window_step_x, self.slide_window_step_y)?;
+        check_pyramid_scale_factor(self.pyramid_scale_factor)?;
+        check_score_thresh(self.score_thresh)?;
+        self.nms_strategy.validate()?;
+        check_iou_thresh(self.nms_iou_thresh)?;
+        check_roll_angles(&self.roll_angles)
+    }
+}
+
+/// Builder of a [`DetectorConfig`].
+///
+/// Settings, which are not set explicitly, keep their default values.
+#[derive(Clone, Debug)]
+pub struct DetectorConfigBuilder {
+    config: DetectorConfig,
+}
+
+impl DetectorConfigBuilder {
+    /// Set the minimum size of faces to detect, no smaller than 20.
+    ///
+    /// Sizes below 40, the size of the sliding window, make the detector
+    /// upsample the image, which is considerably slower.
+    pub fn min_face_size(mut self, min_face_size: u32) -> Self {
+        self.config.min_face_size = min_face_size;
+        self
+    }
+
+    /// Set the maximum size of faces to detect.
+    ///
+    /// `None` (the default) means that the size of faces is only limited by the image size.
+    pub fn max_face_size(mut self, max_face_size: Option<u32>) -> Self {
+        self.config.max_face_size = max_face_size;
+        self
+    }
+
+    /// Set the sliding window step in horizontal and vertical directions.
+    pub fn slide_window_step(mut self, step_x: u32, step_y: u32) -> Self {
+        self.config.slide_window_step_x = step_x;
+        self.config.slide_window_step_y = step_y;
+        self
+    }
+
+    /// Set the factor between adjacent scales of image pyramid, in [0.01, 0.99].
+    pub fn pyramid_scale_factor(mut self, scale_factor: f32) -> Self {
+        self.config.pyramid_scale_factor = scale_factor;
+        self
+    }
+
+    /// Set how the levels of image pyramid are computed, trading speed for accuracy,
+    /// see [`PyramidMode`].
+    pub fn pyramid_mode(mut self, mode: PyramidMode) -> Self {
+        self.config.pyramid_mode = mode;
+        self
+    }
+
+    /// Set the score threshold of detected faces, greater than 0.
+    pub fn score_thresh(mut self, thresh: f64) -> Self {
+        self.config.score_thresh = thresh;
+        self
+    }
+
+    /// Set the margin in pixels, by which regions are expanded on each side when detecting
+    /// faces in regions of an image, see [`Detector::detect_in_regions`](crate::Detector::detect_in_regions).
+    pub fn region_margin(mut self, margin: u32) -> Self {
+        self.config.region_margin = margin;
+        self
+    }
+
+    /// Set the strategy of non-maximum suppression, which merges overlapping detections
+    /// after the last stage of the detector.
+    pub fn nms_strategy(mut self, strategy: NmsStrategy) -> Self {
+        self.config.nms_strategy = strategy;
+        self
+    }
+
+    /// Set the IoU threshold of non-maximum suppression after the last stage of the detector,
+    /// in [0, 1]. Detections overlapping more than that are merged.
+    pub fn nms_iou_thresh(mut self, thresh: f32) -> Self {
+        self.config.nms_iou_thresh = thresh;
+        self
+    }
+
+    /// Set whether detected faces carry their [`Provenance`](crate::Provenance), i.e. the
+    /// pyramid scale and per-stage scores. Disabled by default, as it costs
+    /// an allocation per sliding window accepted by the first hierarchy.
+    pub fn collect_provenance(mut self, collect: bool) -> Self {
+        self.config.collect_provenance = collect;
+        self
+    }
+
+    /// Set the in-plane rotations of faces to search for, in degrees clockwise as displayed.
+    ///
+    /// The default `[0.0]` only finds upright faces, which tolerates a roll of about ±15 degrees.
+    /// With other angles, the detector additionally runs on the image rotated by each of them,
+    /// e.g. `[0.0, 90.0, 180.0, 270.0]` for sideways photos, and fuses the detections; the faces
+    /// then carry their [`roll`](crate::FaceInfo::roll) and a
+    /// [`rotated_bbox`](crate::FaceInfo::rotated_bbox). Every angle costs about as much as
+    /// an upright detection, and angles other than multiples of 90 degrees additionally
+    /// interpolate the rotated image.
+    pub fn roll_angles(mut self, angles: Vec<f32>) -> Self {
+        self.config.roll_angles = angles;
+        self
+    }
+
+    /// Validate the settings and create the configuration.
+    pub fn build(self) -> Result<DetectorConfig, Error> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+pub(super) fn check_min_face_size(min_face_size: u32) -> Result<(), Error> {
+    if min_face_size < FUST_MIN_WINDOW_SIZE {
+        return Err(Error::IllegalMinFaceSize(min_face_size));
+    }
+    Ok(())
+}
+
+pub(super) fn check_slide_window_step(step_x: u32, step_y: u32) -> Result<(), Error> {
+    if step_x == 0 || step_y == 0 {
+        return Err(Error::IllegalSlideWindowStep { step_x, step_y });
+    }
+    Ok(())
+}
+
+pub(super) fn check_pyramid_scale_factor(scale_factor: f32) -> Result<(), Error> {
+    if !(0.01..=0.99).contains(&scale_factor) {
+        return Err(Error::IllegalScaleFactor(scale_factor));
+    }
+    Ok(())
+}
+
+pub(super) fn check_score_thresh(thresh: f64) -> Result<(), Error> {
+    if thresh.is_nan() || thresh <= 0.0 {
+        return Err(Error::IllegalScoreThreshold(thresh));
+    }
+    Ok(())
+}
+
+pub(super) fn check_iou_thresh(thresh: f32) -> Result<(), Error> {
+    if !(0.0..=1.0).contains(&thresh) {
+        return Err(Error::IllegalIouThreshold(thresh));
+    }
+    Ok(())
+}
+
+fn check_roll_angles(angles: &[f32]) -> Result<(), Error> {
+    if angles.is_empty() || angles.iter().any(|angle| !angle.is_finite()) {
+        return Err(Error::IllegalRollAngles(angles.to_vec()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DetectorConfig;
+    use crate::{Error, NmsStrategy, PyramidMode};
+
+    #[test]
+    fn test_builder_keeps_defaults() {
+        let config = DetectorConfig::builder().build().unwrap();
+        assert_eq!(DetectorConfig::default(), config);
+        assert_eq!(40, config.min_face_size());
+        assert_eq!(None, config.max_face_size());
+        assert_eq!((4, 4), config.slide_window_step());
+        assert_eq!(0.8, config.pyramid_scale_factor());
+        assert_eq!(PyramidMode::Bilinear, config.pyramid_mode());
+        assert_eq!(3.85, config.score_thresh());
+        assert_eq!(0, config.region_margin());
+        assert_eq!(NmsStrategy::ScoreSum, config.nms_strategy());
+        assert_eq!(0.3, config.nms_iou_thresh());
+        assert!(!config.collect_provenance());
+        assert_eq!(&[0.0], config.roll_angles());
+    }
+
+    #[test]
+    fn test_builder_rejects_illegal_values() {
+        assert_eq!(
+            Err(Error::IllegalMinFaceSize(19)),
+            DetectorConfig::builder().min_face_size(19).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalSlideWindowStep {
+                step_x: 4,
+                step_y: 0
+            }),
+            DetectorConfig::builder().slide_window_step(4, 0).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalScaleFactor(1.0)),
+            DetectorConfig::builder().pyramid_scale_factor(1.0).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalScoreThreshold(0.0)),
+            DetectorConfig::builder().score_thresh(0.0).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalIouThreshold(1.5)),
+            DetectorConfig::builder().nms_iou_thresh(1.5).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalSoftNmsSigma(-1.0)),
+            DetectorConfig::builder()
+                .nms_strategy(NmsStrategy::SoftGaussian { sigma: -1.0 })
+                .build()
+        );
+        assert_eq!(
+            Err(Error::IllegalRollAngles(Vec::new())),
+            DetectorConfig::builder().roll_angles(Vec::new()).build()
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let config = DetectorConfig::builder()
+            .min_face_size(40)
+            .max_face_size(Some(200))
+            .score_thresh(2.0)
+            .nms_strategy(NmsStrategy::SoftGaussian { sigma: 0.5 })
+            .pyramid_mode(PyramidMode::AreaAveraging)
+            .build()
+            .unwrap();
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(config, serde_json::from_str(&json).unwrap());
+
+        let partial: DetectorConfig = serde_json::from_str(r#"{"score_thresh": 2.0}"#).unwrap();
+        assert_eq!(
+            DetectorConfig::builder().score_thresh(2.0).build().unwrap(),
+            partial
+        );
+    }
+}
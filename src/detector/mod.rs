@@ -16,162 +16,254 @@
 // You should have received a copy of the BSD 2-Clause License along with the software.
 // If not, see < https://opensource.org/licenses/BSD-2-Clause>.
 
-use std::cmp::Ordering::*;
-use std::{cmp, ptr};
-
-use crate::classifier::{Classifier, Score, SurfMlpBuffers};
-use crate::common::{resize_image, FaceInfo, ImageData, ImagePyramid, Rectangle, Seq};
+mod batch;
+mod config;
+mod nms;
+mod rotation;
+mod streaming;
+
+pub use self::batch::detect_batch;
+#[cfg(feature = "rayon")]
+pub use self::batch::detect_batch_in_pool;
+pub use self::config::{DetectorConfig, DetectorConfigBuilder};
+pub use self::nms::NmsStrategy;
+pub use self::streaming::{StreamingConfig, StreamingConfigBuilder, StreamingDetector};
+
+use self::nms::{non_maximum_suppression, NmsBuffers};
+use self::rotation::{normalize_angle, ImageRotation};
+
+#[cfg(feature = "rayon")]
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+#[cfg(feature = "rayon")]
+use std::sync::{Mutex, PoisonError};
+use std::{cmp, mem, ptr};
+
+use crate::classifier::{Classifier, SurfMlpBuffers, SurfMlpClassifier};
+use crate::common::{resize_image, FaceInfo, ImageData, ImagePyramid, Provenance, Rectangle, Seq};
 use crate::feat::FeatureMap;
 use crate::feat::LabBoostedFeatureMap;
 use crate::feat::SurfMlpFeatureMap;
 use crate::model::Model;
-use crate::Detector;
+use crate::{Detector, Error};
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
 const FUST_MIN_WINDOW_SIZE: u32 = 20;
+/// Size of the sliding window, which the cascade classifiers are trained for.
+const K_WND_SIZE: u32 = 40;
+/// Maximum ratio of the area of the bounding rectangle of two overlapping regions
+/// to the area covered by them, up to which they are scanned as a single region.
+const K_MAX_REGION_MERGE_RATIO: f64 = 1.25;
 
 impl Detector for FuStDetector {
-    fn detect(&mut self, image: &ImageData) -> Vec<FaceInfo> {
+    fn try_detect(&mut self, image: &ImageData) -> Result<Vec<FaceInfo>, Error> {
+        let mut workspace = self.workspace.take().unwrap_or_default();
+        let faces = self.try_detect_with_workspace(&mut workspace, image);
+        self.workspace = Some(workspace);
+        faces
+    }
+
+    fn try_detect_in_regions(
+        &mut self,
+        image: &ImageData,
+        regions: &[Rectangle],
+    ) -> Result<Vec<FaceInfo>, Error> {
+        let mut workspace = self.workspace.take().unwrap_or_default();
+        let faces = self.try_detect_in_regions_with_workspace(&mut workspace, image, regions);
+        self.workspace = Some(workspace);
+        faces
+    }
+
+    fn try_detect_with_workspace(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+    ) -> Result<Vec<FaceInfo>, Error> {
         if !is_legal_image(image) {
-            panic!("Illegal image: {:?}", image);
+            return Err(Error::IllegalImage {
+                width: image.width(),
+                height: image.height(),
+                num_channels: image.num_channels(),
+            });
         }
 
-        let mut min_img_size = cmp::min(image.height(), image.width());
-        if self.max_face_size > 0 {
-            min_img_size = cmp::min(self.max_face_size as u32, min_img_size);
+        let region = Rectangle::new(0, 0, image.width(), image.height());
+        Ok(self.detect_in_regions_impl(workspace, image, &[region]))
+    }
+
+    fn try_detect_in_regions_with_workspace(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+        regions: &[Rectangle],
+    ) -> Result<Vec<FaceInfo>, Error> {
+        if !is_legal_image(image) {
+            return Err(Error::IllegalImage {
+                width: image.width(),
+                height: image.height(),
+                num_channels: image.num_channels(),
+            });
         }
 
-        const K_WND_SIZE: f32 = 40.0;
+        let regions = self.prepare_regions(image, regions);
+        Ok(self.detect_in_regions_impl(workspace, image, &regions))
+    }
 
-        let mut image_pyramid = ImagePyramid::new();
-        image_pyramid.set_image_1x(image.data(), image.width(), image.height());
-        // TODO: uncomment (expect perf hit)
-        //        image_pyramid.set_max_scale(K_WND_SIZE / self.min_face_size as f32);
-        image_pyramid.set_min_scale(K_WND_SIZE / min_img_size as f32);
-        image_pyramid.set_scale_step(self.image_pyramid_scale_factor);
-        self.set_window_size(K_WND_SIZE as u32);
+    fn config(&self) -> &DetectorConfig {
+        &self.config
+    }
 
-        self.detect_impl(&mut image_pyramid)
-            .into_iter()
-            .filter(|x| x.score() >= self.cls_thresh)
-            .collect()
+    fn try_set_config(&mut self, config: DetectorConfig) -> Result<(), Error> {
+        config.validate()?;
+        self.config = config;
+        Ok(())
     }
 
-    fn set_window_size(&mut self, wnd_size: u32) {
+    fn try_set_window_size(&mut self, wnd_size: u32) -> Result<(), Error> {
         if wnd_size < FUST_MIN_WINDOW_SIZE {
-            panic!("Illegal window size: {}", wnd_size);
+            return Err(Error::IllegalWindowSize(wnd_size));
         }
-        self.wnd_size = wnd_size;
+        Ok(())
     }
 
-    fn set_slide_window_step(&mut self, step_x: u32, step_y: u32) {
-        if step_x <= 0 {
-            panic!("Illegal horizontal step: {}", step_x);
-        }
-        if step_y <= 0 {
-            panic!("Illegal vertical step: {}", step_y);
-        }
-        self.slide_wnd_step_x = step_x;
-        self.slide_wnd_step_y = step_y;
+    fn try_set_slide_window_step(&mut self, step_x: u32, step_y: u32) -> Result<(), Error> {
+        config::check_slide_window_step(step_x, step_y)?;
+        self.config.slide_window_step_x = step_x;
+        self.config.slide_window_step_y = step_y;
+        Ok(())
     }
 
-    fn set_min_face_size(&mut self, min_face_size: u32) {
-        if min_face_size < FUST_MIN_WINDOW_SIZE {
-            panic!("Illegal min face size: {}", min_face_size);
-        }
-        self.min_face_size = min_face_size as i32;
+    fn try_set_min_face_size(&mut self, min_face_size: u32) -> Result<(), Error> {
+        config::check_min_face_size(min_face_size)?;
+        self.config.min_face_size = min_face_size;
+        Ok(())
     }
 
     #[inline]
     fn set_max_face_size(&mut self, max_face_size: u32) {
-        self.max_face_size = max_face_size as i32;
+        self.config.max_face_size = if max_face_size > 0 {
+            Some(max_face_size)
+        } else {
+            None
+        };
     }
 
-    fn set_pyramid_scale_factor(&mut self, scale_factor: f32) {
-        if !(0.01..=0.99).contains(&scale_factor) {
-            panic!("Illegal scale factor: {}", scale_factor);
-        }
-        self.image_pyramid_scale_factor = scale_factor;
+    #[inline]
+    fn set_region_margin(&mut self, margin: u32) {
+        self.config.region_margin = margin;
     }
 
-    fn set_score_thresh(&mut self, thresh: f64) {
-        if thresh <= 0.0 {
-            panic!("Illegal threshold: {}", thresh);
-        }
-        self.cls_thresh = thresh;
+    fn try_set_pyramid_scale_factor(&mut self, scale_factor: f32) -> Result<(), Error> {
+        config::check_pyramid_scale_factor(scale_factor)?;
+        self.config.pyramid_scale_factor = scale_factor;
+        Ok(())
+    }
+
+    fn try_set_score_thresh(&mut self, thresh: f64) -> Result<(), Error> {
+        config::check_score_thresh(thresh)?;
+        self.config.score_thresh = thresh;
+        Ok(())
     }
 }
 
 #[inline]
 fn is_legal_image(image: &ImageData) -> bool {
-    image.num_channels() == 1 && image.width() > 0 && image.height() > 0
+    image.width() > 0 && image.height() > 0
 }
 
 pub struct FuStDetector {
-    feature_maps: FeatureMaps,
-    model: Model,
-    wnd_data_buf: Vec<u8>,
-    wnd_data: Vec<u8>,
-    wnd_size: u32,
-    slide_wnd_step_x: u32,
-    slide_wnd_step_y: u32,
-    min_face_size: i32,
-    max_face_size: i32,
-    cls_thresh: f64,
-    image_pyramid_scale_factor: f32,
+    model: Arc<Model>,
+    workspace: Option<DetectorWorkspace>,
+    config: DetectorConfig,
 }
 
-struct FeatureMaps {
+/// Intermediate buffers, used by a detector while processing an image.
+///
+/// A workspace is not tied to a particular detector or image size and can be re-used
+/// between detection calls to avoid re-allocating the buffers. Each thread, that runs
+/// detection concurrently, needs a workspace of its own.
+pub struct DetectorWorkspace {
+    pyramid: ImagePyramid,
     lab_boosted: LabBoostedFeatureMap,
+    #[cfg(feature = "rayon")]
+    windows: Vec<WindowWorkspace>,
+    #[cfg(not(feature = "rayon"))]
+    window: WindowWorkspace,
+    gray_buf: Vec<u8>,
+    rotated_buf: Vec<u8>,
+    /// Positive windows of the first hierarchy, per row of a pyramid level.
+    scan_rows: Vec<Vec<ScannedWindow>>,
+    /// Proposals of the later hierarchies, per classifier of a hierarchy.
+    proposals: Vec<Vec<FaceInfo>>,
+    proposals_nms: Vec<Vec<FaceInfo>>,
+    /// Whether each proposal passes the current stage.
+    #[cfg(feature = "rayon")]
+    accepted: Vec<bool>,
+    buf_idx: Vec<i32>,
+    nms: NmsBuffers,
+}
+
+/// Buffers for classifying a single window with the later hierarchies.
+///
+/// With the `rayon` feature, windows are classified in parallel, each worker thread
+/// using a window workspace of its own. The detector workspace keeps them in a pool,
+/// which grows to the number of threads classifying windows at the same time.
+struct WindowWorkspace {
     surf_mlp: SurfMlpFeatureMap,
     surf_buf: SurfMlpBuffers,
+    wnd_data_buf: Vec<u8>,
+    wnd_data: Vec<u8>,
+    mlp_predicts: Vec<f32>,
 }
 
-impl FuStDetector {
-    pub fn new(model: Model) -> Self {
-        let wnd_size = 40;
-        let slide_wnd_step_x = 4;
-        let slide_wnd_step_y = 4;
+impl DetectorWorkspace {
+    pub fn new() -> Self {
+        DetectorWorkspace {
+            pyramid: ImagePyramid::new(),
+            lab_boosted: LabBoostedFeatureMap::new(),
+            #[cfg(feature = "rayon")]
+            windows: Vec::new(),
+            #[cfg(not(feature = "rayon"))]
+            window: WindowWorkspace::new(),
+            gray_buf: Vec::new(),
+            rotated_buf: Vec::new(),
+            scan_rows: Vec::new(),
+            proposals: Vec::new(),
+            proposals_nms: Vec::new(),
+            #[cfg(feature = "rayon")]
+            accepted: Vec::new(),
+            buf_idx: Vec::new(),
+            nms: NmsBuffers::default(),
+        }
+    }
 
-        FuStDetector {
-            feature_maps: FeatureMaps {
-                lab_boosted: LabBoostedFeatureMap::new(),
-                surf_mlp: SurfMlpFeatureMap::new(),
-                surf_buf: SurfMlpBuffers::new(),
-            },
-            model,
-            wnd_data_buf: vec![0; (wnd_size * wnd_size) as usize],
-            wnd_data: vec![0; (wnd_size * wnd_size) as usize],
-            wnd_size,
-            slide_wnd_step_x,
-            slide_wnd_step_y,
-            min_face_size: 20,
-            max_face_size: -1,
-            cls_thresh: 3.85,
-            image_pyramid_scale_factor: 0.8,
-        }
-    }
-
-    fn feature_map_for_classifier<'a>(
-        classifier: &Classifier,
-        maps: &'a mut FeatureMaps,
-    ) -> &'a mut dyn FeatureMap {
-        match classifier {
-            Classifier::LabBoosted(_) => &mut maps.lab_boosted,
-            Classifier::SurfMlp(_) => &mut maps.surf_mlp,
-        }
-    }
-
-    fn classify_with_classifier(
-        classifier: &Classifier,
-        output: Option<&mut Vec<f32>>,
-        maps: &mut FeatureMaps,
-        roi: Rectangle,
-    ) -> Score {
-        match classifier {
-            Classifier::SurfMlp(c) => {
-                c.classify(output, &mut maps.surf_buf, &mut maps.surf_mlp, roi)
-            }
-            Classifier::LabBoosted(c) => c.classify(&maps.lab_boosted, roi),
+    /// Clears the proposals of the first hierarchy, keeping the capacity of their buffers.
+    fn reset_proposals(&mut self, first_hierarchy_size: usize) {
+        if self.proposals.len() < first_hierarchy_size {
+            self.proposals.resize_with(first_hierarchy_size, Vec::new);
+        }
+        for proposals in &mut self.proposals {
+            proposals.clear();
+        }
+    }
+}
+
+impl Default for DetectorWorkspace {
+    fn default() -> Self {
+        DetectorWorkspace::new()
+    }
+}
+
+impl WindowWorkspace {
+    fn new() -> Self {
+        WindowWorkspace {
+            surf_mlp: SurfMlpFeatureMap::new(),
+            surf_buf: SurfMlpBuffers::new(),
+            wnd_data_buf: vec![0; (K_WND_SIZE * K_WND_SIZE) as usize],
+            wnd_data: vec![0; (K_WND_SIZE * K_WND_SIZE) as usize],
+            mlp_predicts: vec![0.0; 4],
         }
     }
 
@@ -182,6 +274,7 @@ impl FuStDetector {
         let roi_height = roi.height() as i32;
         let img_width = img.width() as i32;
         let img_height = img.height() as i32;
+        let img_stride = img.stride() as i32;
 
         let pad_right = cmp::max(roi.x() + roi_width - img_width, 0);
         let pad_left = if roi.x() >= 0 {
@@ -207,7 +300,7 @@ impl FuStDetector {
             src = img
                 .data()
                 .as_ptr()
-                .offset((roi.y() * img_width + roi.x()) as isize);
+                .offset((roi.y() * img_stride + roi.x()) as isize);
         }
         let mut dest = self.wnd_data_buf.as_mut_ptr();
         let len = roi_width as usize;
@@ -225,7 +318,7 @@ impl FuStDetector {
                 for _y in pad_top..(roi_height - pad_bottom) {
                     unsafe {
                         ptr::copy_nonoverlapping(src, dest, len);
-                        src = src.offset(img_width as isize);
+                        src = src.offset(img_stride as isize);
                         dest = dest.offset(roi_width as isize);
                     }
                 }
@@ -234,7 +327,7 @@ impl FuStDetector {
                 for _y in pad_top..(roi_height - pad_bottom) {
                     unsafe {
                         ptr::copy_nonoverlapping(src, dest, len2);
-                        src = src.offset(img_width as isize);
+                        src = src.offset(img_stride as isize);
                         dest = dest.offset(roi_width as isize);
                         ptr::write_bytes(dest.offset(-pad_right as isize), 0, pad_right as usize);
                     }
@@ -245,7 +338,7 @@ impl FuStDetector {
                     unsafe {
                         ptr::write_bytes(dest, 0, pad_left as usize);
                         ptr::copy_nonoverlapping(src, dest.offset(pad_left as isize), len2);
-                        src = src.offset(img_width as isize);
+                        src = src.offset(img_stride as isize);
                         dest = dest.offset(roi_width as isize);
                     }
                 }
@@ -255,7 +348,7 @@ impl FuStDetector {
                     unsafe {
                         ptr::write_bytes(dest, 0, pad_left as usize);
                         ptr::copy_nonoverlapping(src, dest.offset(pad_left as isize), len2);
-                        src = src.offset(img_width as isize);
+                        src = src.offset(img_stride as isize);
                         dest = dest.offset(roi_width as isize);
                         ptr::write_bytes(dest.offset(-pad_right as isize), 0, pad_right as usize);
                     }
@@ -270,167 +363,403 @@ impl FuStDetector {
         }
 
         let src_img = ImageData::new(&self.wnd_data_buf, roi.width(), roi.height());
-        resize_image(&src_img, &mut self.wnd_data, self.wnd_size, self.wnd_size);
+        resize_image(&src_img, &mut self.wnd_data, K_WND_SIZE, K_WND_SIZE);
     }
+}
 
-    fn detect_impl(&mut self, image: &mut ImagePyramid) -> Vec<FaceInfo> {
-        let first_hierarchy_size = self.model.get_hierarchy_size(0) as usize;
-        let mut proposals = vec![Vec::new(); first_hierarchy_size];
-        let mut proposals_nms = vec![Vec::new(); first_hierarchy_size];
-
-        while let Some((image_scaled, scale_factor)) = image.get_next_scale_image() {
-            Self::feature_map_for_classifier(
-                &self.model.get_classifiers()[0],
-                &mut self.feature_maps,
-            )
-            .compute(&image_scaled);
-
-            let step_x = self.slide_wnd_step_x;
-            let step_y = self.slide_wnd_step_y;
-            let max_x = image_scaled.width() - self.wnd_size;
-            let max_y = image_scaled.height() - self.wnd_size;
-
-            for y in Seq::new(0, move |n| n + step_y).take_while(move |n| *n <= max_y) {
-                for x in Seq::new(0, move |n| n + step_x).take_while(move |n| *n <= max_x) {
-                    let rect = Rectangle::new(x as i32, y as i32, self.wnd_size, self.wnd_size);
-                    Self::feature_map_for_classifier(
-                        &self.model.get_classifiers()[0],
-                        &mut self.feature_maps,
-                    );
-
-                    for (classifier, proposal) in self
-                        .model
-                        .get_classifiers()
-                        .iter()
-                        .zip(proposals.iter_mut())
-                        .take(first_hierarchy_size)
-                    {
-                        let score = Self::classify_with_classifier(
-                            classifier,
-                            None,
-                            &mut self.feature_maps,
-                            rect,
-                        );
-                        if score.is_positive() {
-                            let mut wnd_info = FaceInfo::new();
-                            let bbox = wnd_info.bbox_mut();
-                            bbox.set_x((x as f32 / scale_factor + 0.5) as i32);
-                            bbox.set_y((y as f32 / scale_factor + 0.5) as i32);
-                            let width = (self.wnd_size as f32 / scale_factor + 0.5) as u32;
-                            bbox.set_width(width);
-                            bbox.set_height(width);
-                            wnd_info.set_score(f64::from(score.score()));
-                            proposal.push(wnd_info);
-                        }
-                    }
+/// A window workspace, taken from a pool for the windows of a worker thread
+/// and returned to the pool on drop.
+#[cfg(feature = "rayon")]
+struct PooledWindow<'a> {
+    pool: &'a Mutex<Vec<WindowWorkspace>>,
+    window: Option<WindowWorkspace>,
+}
+
+#[cfg(feature = "rayon")]
+impl<'a> PooledWindow<'a> {
+    fn take(pool: &'a Mutex<Vec<WindowWorkspace>>) -> Self {
+        let window = pool
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop()
+            .unwrap_or_else(WindowWorkspace::new);
+        PooledWindow {
+            pool,
+            window: Some(window),
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl Deref for PooledWindow<'_> {
+    type Target = WindowWorkspace;
+
+    fn deref(&self) -> &WindowWorkspace {
+        self.window
+            .as_ref()
+            .expect("Window workspace is returned on drop")
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl DerefMut for PooledWindow<'_> {
+    fn deref_mut(&mut self) -> &mut WindowWorkspace {
+        self.window
+            .as_mut()
+            .expect("Window workspace is returned on drop")
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl Drop for PooledWindow<'_> {
+    fn drop(&mut self) {
+        if let Some(window) = self.window.take() {
+            self.pool
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(window);
+        }
+    }
+}
+
+impl FuStDetector {
+    pub fn new(model: Arc<Model>) -> Self {
+        FuStDetector::with_config(model, DetectorConfig::default())
+    }
+
+    pub fn with_config(model: Arc<Model>, config: DetectorConfig) -> Self {
+        FuStDetector {
+            model,
+            workspace: None,
+            config,
+        }
+    }
+
+    /// Range of image pyramid scales, at which the sliding window covers faces
+    /// of the configured sizes.
+    ///
+    /// Scales greater than 1 find faces smaller than the window by upsampling the image.
+    /// Scales outside of the range can not yield faces of the requested sizes and are skipped.
+    fn pyramid_scale_range(&self, width: u32, height: u32) -> (f32, f32) {
+        let mut max_face_size = cmp::min(width, height);
+        if let Some(size) = self.config.max_face_size {
+            max_face_size = cmp::min(size, max_face_size);
+        }
+
+        let min_scale = K_WND_SIZE as f32 / max_face_size as f32;
+        let max_scale = K_WND_SIZE as f32 / self.config.min_face_size as f32;
+        (min_scale, max_scale)
+    }
+
+    /// Whether a face of the given size lies within the configured bounds.
+    ///
+    /// The bounding box regression of the later hierarchies may shift the size of a face
+    /// slightly outside of the sizes covered by the image pyramid.
+    fn is_allowed_face_size(&self, size: u32) -> bool {
+        size >= self.config.min_face_size && self.config.max_face_size.is_none_or(|max| size <= max)
+    }
+
+    /// Expands the regions by the configured margin, clips them to the image and merges
+    /// overlapping regions, unless their bounding rectangle is much larger than the regions.
+    /// Regions, which do not intersect the image, are dropped.
+    fn prepare_regions(&self, image: &ImageData, regions: &[Rectangle]) -> Vec<Rectangle> {
+        merge_regions(
+            regions.iter().map(|region| {
+                region
+                    .expand(self.config.region_margin)
+                    .clamp(image.width(), image.height())
+            }),
+            K_MAX_REGION_MERGE_RATIO,
+        )
+    }
+
+    /// Detects faces in the given regions, which must lie within the image.
+    fn detect_in_regions_impl(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+        regions: &[Rectangle],
+    ) -> Vec<FaceInfo> {
+        let mut gray_buf = mem::take(&mut workspace.gray_buf);
+        let image = image.to_gray(&mut gray_buf);
+
+        let mut faces = if self.config.searches_rotations() {
+            self.detect_rotated(workspace, &image, regions)
+        } else {
+            self.detect_upright(workspace, &image, regions)
+        };
+
+        // the regions are scanned separately, so a face in the overlap of regions,
+        // which are not merged, may be found in each of them
+        if regions.len() > 1 {
+            let mut faces_nms = Vec::new();
+            non_maximum_suppression(
+                NmsStrategy::Greedy,
+                &mut faces,
+                &mut faces_nms,
+                self.config.nms_iou_thresh,
+                &mut workspace.nms,
+            );
+            faces = faces_nms;
+        }
+
+        self.annotate(&mut faces);
+
+        workspace.gray_buf = gray_buf;
+        faces
+    }
+
+    /// Sets the calibrated confidence of the detected faces and the yaw of their bin,
+    /// if the model provides them.
+    fn annotate(&self, faces: &mut [FaceInfo]) {
+        if let Some(calibration) = self.model.calibration() {
+            for face in faces.iter_mut() {
+                face.set_confidence(calibration.apply(face.score()));
+            }
+        }
+        for face in faces.iter_mut() {
+            if let Some(yaw) = face.yaw_bin().and_then(|bin| self.model.branch_yaw(bin)) {
+                face.set_yaw(yaw);
+            }
+        }
+    }
+
+    /// Drops the faces, which do not pass the score threshold or the face size bounds.
+    fn filter_faces(&self, faces: &mut Vec<FaceInfo>) {
+        faces.retain(|x| {
+            x.score() >= self.config.score_thresh && self.is_allowed_face_size(x.bbox().width())
+        });
+    }
+
+    /// Detects upright faces in the given regions of a gray-scale image, which pass
+    /// the score threshold and the face size bounds.
+    fn detect_upright(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+        regions: &[Rectangle],
+    ) -> Vec<FaceInfo> {
+        let mut faces = self.detect_impl(workspace, image, regions);
+        self.filter_faces(&mut faces);
+        faces
+    }
+
+    /// Detects faces with each of the configured roll angles, by detecting upright faces
+    /// on the image rotated by the opposite angle, and fuses the detections of all angles.
+    fn detect_rotated(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+        regions: &[Rectangle],
+    ) -> Vec<FaceInfo> {
+        let mut rotated_buf = mem::take(&mut workspace.rotated_buf);
+        let mut faces = Vec::new();
+
+        for &angle in &self.config.roll_angles {
+            let roll = normalize_angle(angle);
+            let rotation = ImageRotation::new(-roll, image.width(), image.height());
+            let rotated = rotation.rotate(image, &mut rotated_buf);
+            let rotated_regions = merge_regions(
+                regions.iter().map(|r| rotation.map_region(r)),
+                K_MAX_REGION_MERGE_RATIO,
+            );
+
+            for mut face in self.detect_upright(workspace, &rotated, &rotated_regions) {
+                face.set_rotated_bbox(rotation.map_back(face.bbox(), roll));
+                faces.push(face);
+            }
+        }
+        workspace.rotated_buf = rotated_buf;
+
+        // the same face is often found with adjacent roll angles
+        let mut faces_nms = Vec::new();
+        non_maximum_suppression(
+            NmsStrategy::Greedy,
+            &mut faces,
+            &mut faces_nms,
+            self.config.nms_iou_thresh,
+            &mut workspace.nms,
+        );
+        faces_nms
+    }
+
+    /// Runs the cascade on each region separately, so that the scores of windows in the overlap
+    /// of regions are not summed up twice, and returns the detections of all regions.
+    fn detect_impl(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+        regions: &[Rectangle],
+    ) -> Vec<FaceInfo> {
+        let mut faces = Vec::new();
+
+        for region in regions {
+            let (min_scale, max_scale) = self.pyramid_scale_range(region.width(), region.height());
+            if max_scale < min_scale {
+                continue;
+            }
+            workspace.reset_proposals(self.model.get_hierarchy_size(0) as usize);
+
+            let image_pyramid = &mut workspace.pyramid;
+            image_pyramid.set_max_scale(max_scale);
+            image_pyramid.set_min_scale(min_scale);
+            image_pyramid.set_scale_step(self.config.pyramid_scale_factor);
+            image_pyramid.set_mode(self.config.pyramid_mode);
+
+            let region_image = image.sub_image(
+                region.x() as u32,
+                region.y() as u32,
+                region.width(),
+                region.height(),
+            );
+            let mut levels = image_pyramid.levels(&region_image);
+            while let Some((image_scaled, scale_factor)) = levels.next_level() {
+                // rounding of the scaled size may leave no room for a single window
+                if image_scaled.width() < K_WND_SIZE || image_scaled.height() < K_WND_SIZE {
+                    continue;
+                }
+                workspace.lab_boosted.compute(&image_scaled);
+                let rows = self.scan_pyramid_level(
+                    &workspace.lab_boosted,
+                    &mut workspace.scan_rows,
+                    &image_scaled,
+                    (0, 0),
+                    scale_factor,
+                    region,
+                );
+                for window in rows.iter_mut().flat_map(|row| row.drain(..)) {
+                    workspace.proposals[window.branch].push(window.face);
                 }
             }
+
+            let region_faces = self.refine_proposals(workspace, image);
+            if faces.is_empty() {
+                faces = region_faces;
+            } else {
+                faces.extend(region_faces);
+            }
+        }
+
+        faces
+    }
+
+    /// Runs the later hierarchies on the proposals of the first hierarchy, given per branch
+    /// in the order of their windows in the workspace, and returns the merged detections
+    /// of the last one.
+    ///
+    /// Apart from the returned detections, the buffers of the workspace are re-used,
+    /// so that repeated detection on a single thread does not allocate once they have grown.
+    /// With more threads, the pool of window workspaces grows whenever more windows than
+    /// before are classified at the same time.
+    fn refine_proposals(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+    ) -> Vec<FaceInfo> {
+        let first_hierarchy_size = self.model.get_hierarchy_size(0) as usize;
+        let DetectorWorkspace {
+            proposals,
+            proposals_nms,
+            buf_idx,
+            nms,
+            ..
+        } = workspace;
+        if proposals_nms.len() < proposals.len() {
+            proposals_nms.resize_with(proposals.len(), Vec::new);
         }
 
         for i in 0..first_hierarchy_size {
-            non_maximum_suppression(&mut proposals[i], &mut proposals_nms[i], 0.8);
+            non_maximum_suppression(
+                NmsStrategy::ScoreSum,
+                &mut proposals[i],
+                &mut proposals_nms[i],
+                0.8,
+                nms,
+            );
             proposals[i].clear();
         }
 
-        let image1x = image.get_image_1x();
-        let mut mlp_predicts: Vec<f32> = vec![0.0; 4];
+        let image1x = *image;
+        #[cfg(feature = "rayon")]
+        let windows = Mutex::new(mem::take(&mut workspace.windows));
 
         let mut cls_idx = first_hierarchy_size;
         let mut model_idx = first_hierarchy_size;
-        let mut buf_idx: Vec<i32> = Vec::new();
 
         for i in 1..self.model.get_hierarchy_count() {
             let hierarchy_size_i = self.model.get_hierarchy_size(i) as usize;
             if buf_idx.len() < hierarchy_size_i {
                 buf_idx.resize(hierarchy_size_i, 0);
             }
+            if proposals.len() < hierarchy_size_i {
+                proposals.resize_with(hierarchy_size_i, Vec::new);
+                proposals_nms.resize_with(hierarchy_size_i, Vec::new);
+            }
 
             for r in buf_idx.iter_mut().take(hierarchy_size_i) {
                 {
                     let wnd_src = self.model.get_wnd_src(cls_idx);
                     *r = wnd_src[0];
-                    let num_wnd_src = wnd_src.len();
                     let r = *r as usize;
                     proposals[r].clear();
 
-                    for k in wnd_src.iter().take(num_wnd_src) {
-                        for item in &proposals_nms[*k as usize] {
-                            let last_index = proposals[r].len();
-                            proposals[r].insert(last_index, item.clone());
-                        }
+                    for k in wnd_src {
+                        proposals[r].extend_from_slice(&proposals_nms[*k as usize]);
                     }
                 }
                 let r = *r as usize;
 
                 let k_max = self.model.get_num_stage(cls_idx);
                 for k in 0..k_max {
-                    let mut bbox_id = 0;
+                    let classifier = match &self.model.get_classifiers()[model_idx] {
+                        Classifier::SurfMlp(c) => c,
+                        Classifier::LabBoosted(_) => {
+                            unreachable!("Later hierarchies consist of SURF-MLP classifiers")
+                        }
+                    };
+
+                    #[cfg(feature = "rayon")]
                     {
-                        let num_wnd = proposals[r].len();
-                        let bboxes = &mut proposals[r];
-
-                        for m in 0..num_wnd {
-                            if bboxes[m].bbox().x() + bboxes[m].bbox().width() as i32 <= 0
-                                || bboxes[m].bbox().y() + bboxes[m].bbox().height() as i32 <= 0
-                            {
-                                continue;
-                            }
-
-                            self.get_window_data(&image1x, bboxes[m].bbox_mut());
-                            let img_temp =
-                                ImageData::new(&self.wnd_data, self.wnd_size, self.wnd_size);
-                            let classifier = &self.model.get_classifiers()[model_idx];
-                            Self::feature_map_for_classifier(classifier, &mut self.feature_maps)
-                                .compute(&img_temp);
-                            let rect = Rectangle::new(0, 0, self.wnd_size, self.wnd_size);
-
-                            let new_score = Self::classify_with_classifier(
-                                classifier,
-                                Some(&mut mlp_predicts),
-                                &mut self.feature_maps,
-                                rect,
+                        let accepted = &mut workspace.accepted;
+                        accepted.clear();
+                        accepted.resize(proposals[r].len(), false);
+                        proposals[r]
+                            .par_iter_mut()
+                            .zip(accepted.par_iter_mut())
+                            .for_each_init(
+                                || PooledWindow::take(&windows),
+                                |window, (face, accepted)| {
+                                    *accepted =
+                                        Self::refine_proposal(classifier, window, &image1x, face);
+                                },
                             );
-                            if new_score.is_positive() {
-                                let x = bboxes[m].bbox().x() as f32;
-                                let y = bboxes[m].bbox().y() as f32;
-                                let w = bboxes[m].bbox().width() as f32;
-                                let h = bboxes[m].bbox().height() as f32;
-
-                                let bbox_w = ((mlp_predicts[3] * 2.0 - 1.0) * w + w + 0.5).floor();
-                                bboxes[bbox_id].bbox_mut().set_width(bbox_w as u32);
-                                bboxes[bbox_id].bbox_mut().set_height(bbox_w as u32);
-
-                                bboxes[bbox_id].bbox_mut().set_x(
-                                    ((mlp_predicts[1] * 2.0 - 1.0) * w
-                                        + x
-                                        + (w - bbox_w) * 0.5
-                                        + 0.5)
-                                        .floor() as i32,
-                                );
-
-                                bboxes[bbox_id].bbox_mut().set_y(
-                                    ((mlp_predicts[2] * 2.0 - 1.0) * h
-                                        + y
-                                        + (h - bbox_w) * 0.5
-                                        + 0.5)
-                                        .floor() as i32,
-                                );
-
-                                bboxes[bbox_id].set_score(f64::from(new_score.score()));
-
-                                bbox_id += 1;
-                            }
-                        }
+                        let mut accepted = accepted.iter();
+                        proposals[r].retain(|_| *accepted.next().unwrap());
                     }
 
-                    proposals[r].truncate(bbox_id);
+                    #[cfg(not(feature = "rayon"))]
+                    proposals[r].retain_mut(|face| {
+                        Self::refine_proposal(classifier, &mut workspace.window, &image1x, face)
+                    });
 
                     if k < (k_max - 1) {
-                        non_maximum_suppression(&mut proposals[r], &mut proposals_nms[r], 0.8);
-                        proposals[r] = proposals_nms[r].clone();
+                        non_maximum_suppression(
+                            NmsStrategy::ScoreSum,
+                            &mut proposals[r],
+                            &mut proposals_nms[r],
+                            0.8,
+                            nms,
+                        );
+                        proposals[r].clone_from(&proposals_nms[r]);
                     } else if i == (self.model.get_hierarchy_count() - 1) {
-                        non_maximum_suppression(&mut proposals[r], &mut proposals_nms[r], 0.3);
-                        proposals[r] = proposals_nms[r].clone();
+                        non_maximum_suppression(
+                            self.config.nms_strategy,
+                            &mut proposals[r],
+                            &mut proposals_nms[r],
+                            self.config.nms_iou_thresh,
+                            nms,
+                        );
+                        proposals[r].clone_from(&proposals_nms[r]);
                     }
 
                     model_idx += 1;
@@ -440,100 +769,815 @@ impl FuStDetector {
             }
 
             for j in 0..hierarchy_size_i {
-                proposals_nms[j] = proposals[buf_idx[j] as usize].clone();
+                proposals_nms[j].clone_from(&proposals[buf_idx[j] as usize]);
             }
         }
 
+        #[cfg(feature = "rayon")]
+        {
+            workspace.windows = windows.into_inner().unwrap_or_else(PoisonError::into_inner);
+        }
+
         proposals_nms[0].clone()
     }
+
+    /// Runs the first hierarchy on all windows of a pyramid level of a region and returns
+    /// the positive windows, mapped back to the 1x image, per row of windows.
+    /// The rows are stored in `rows`, which only ever grows to keep the buffers of the rows.
+    ///
+    /// The scaled image may be a part of the pyramid level, which starts at `origin`
+    /// (in level coordinates) and is aligned to the sliding window step.
+    ///
+    /// With the `rayon` feature, rows of windows are scanned in parallel. The windows are
+    /// returned in the same order either way, so the result does not depend on the scheduling.
+    fn scan_pyramid_level<'a>(
+        &self,
+        feature_map: &LabBoostedFeatureMap,
+        rows: &'a mut Vec<Vec<ScannedWindow>>,
+        image_scaled: &ImageData,
+        origin: (u32, u32),
+        scale_factor: f32,
+        region: &Rectangle,
+    ) -> &'a mut [Vec<ScannedWindow>] {
+        let first_hierarchy_size = self.model.get_hierarchy_size(0) as usize;
+        let classifiers = &self.model.get_classifiers()[..first_hierarchy_size];
+        let step_x = self.config.slide_window_step_x;
+        let step_y = self.config.slide_window_step_y;
+        let max_x = image_scaled.width() - K_WND_SIZE;
+        let max_y = image_scaled.height() - K_WND_SIZE;
+        let width = (K_WND_SIZE as f32 / scale_factor + 0.5) as u32;
+        let collect_provenance = self.config.collect_provenance;
+
+        let scan_row = |row: usize, faces: &mut Vec<ScannedWindow>| {
+            let y = row as u32 * step_y;
+            faces.clear();
+            for x in Seq::new(0, move |n| n + step_x).take_while(move |n| *n <= max_x) {
+                let rect = Rectangle::new(x as i32, y as i32, K_WND_SIZE, K_WND_SIZE);
+                for (i, classifier) in classifiers.iter().enumerate() {
+                    let score = match classifier {
+                        Classifier::LabBoosted(c) => c.classify(feature_map, rect),
+                        Classifier::SurfMlp(_) => {
+                            unreachable!("The first hierarchy consists of LAB classifiers")
+                        }
+                    };
+                    if score.is_positive() {
+                        let (level_x, level_y) = (origin.0 + x, origin.1 + y);
+                        let mut wnd_info = FaceInfo::new();
+                        let bbox = wnd_info.bbox_mut();
+                        bbox.set_x((level_x as f32 / scale_factor + 0.5) as i32 + region.x());
+                        bbox.set_y((level_y as f32 / scale_factor + 0.5) as i32 + region.y());
+                        bbox.set_width(width);
+                        bbox.set_height(width);
+                        wnd_info.set_score(f64::from(score.score()));
+                        wnd_info.set_yaw_bin(i);
+                        if collect_provenance {
+                            wnd_info.set_provenance(Provenance::new(scale_factor, score.score()));
+                        }
+                        faces.push(ScannedWindow {
+                            branch: i,
+                            x: level_x,
+                            y: level_y,
+                            face: wnd_info,
+                        });
+                    }
+                }
+            }
+        };
+
+        let num_rows = (max_y / step_y + 1) as usize;
+        if rows.len() < num_rows {
+            rows.resize_with(num_rows, Vec::new);
+        }
+        let rows = &mut rows[..num_rows];
+
+        #[cfg(feature = "rayon")]
+        rows.par_iter_mut()
+            .enumerate()
+            .for_each(|(row, faces)| scan_row(row, faces));
+
+        #[cfg(not(feature = "rayon"))]
+        rows.iter_mut()
+            .enumerate()
+            .for_each(|(row, faces)| scan_row(row, faces));
+
+        rows
+    }
+
+    /// Classifies the window of a proposal with a stage of the later hierarchies.
+    ///
+    /// Adjusts the score and the bounding box of the proposal by the regression output
+    /// and returns `true`, if the window is classified as positive.
+    fn refine_proposal(
+        classifier: &SurfMlpClassifier,
+        window: &mut WindowWorkspace,
+        image: &ImageData,
+        face: &mut FaceInfo,
+    ) -> bool {
+        let mut bbox = *face.bbox();
+        if bbox.x() + bbox.width() as i32 <= 0 || bbox.y() + bbox.height() as i32 <= 0 {
+            return false;
+        }
+
+        window.get_window_data(image, &mut bbox);
+        let img_temp = ImageData::new(&window.wnd_data, K_WND_SIZE, K_WND_SIZE);
+        window.surf_mlp.compute(&img_temp);
+        let rect = Rectangle::new(0, 0, K_WND_SIZE, K_WND_SIZE);
+
+        let new_score = classifier.classify(
+            Some(&mut window.mlp_predicts),
+            &mut window.surf_buf,
+            &mut window.surf_mlp,
+            rect,
+        );
+        if !new_score.is_positive() {
+            return false;
+        }
+
+        let mlp_predicts = &window.mlp_predicts;
+        let x = bbox.x() as f32;
+        let y = bbox.y() as f32;
+        let w = bbox.width() as f32;
+        let h = bbox.height() as f32;
+
+        let refined = face;
+        let bbox_w = ((mlp_predicts[3] * 2.0 - 1.0) * w + w + 0.5).floor();
+        refined.bbox_mut().set_width(bbox_w as u32);
+        refined.bbox_mut().set_height(bbox_w as u32);
+        refined.bbox_mut().set_x(
+            ((mlp_predicts[1] * 2.0 - 1.0) * w + x + (w - bbox_w) * 0.5 + 0.5).floor() as i32,
+        );
+        refined.bbox_mut().set_y(
+            ((mlp_predicts[2] * 2.0 - 1.0) * h + y + (h - bbox_w) * 0.5 + 0.5).floor() as i32,
+        );
+        refined.set_score(f64::from(new_score.score()));
+        if let Some(provenance) = refined.provenance_mut() {
+            provenance.stage_scores.push(new_score.score());
+            provenance
+                .regression_outputs
+                .push([mlp_predicts[1], mlp_predicts[2], mlp_predicts[3]]);
+        }
+
+        true
+    }
 }
 
-fn non_maximum_suppression(
-    bboxes: &mut Vec<FaceInfo>,
-    bboxes_nms: &mut Vec<FaceInfo>,
-    iou_thresh: f32,
-) {
-    bboxes_nms.clear();
-    bboxes.sort_by(|x, y| {
-        let x_score = x.score();
-        let y_score = y.score();
-        if x_score > y_score {
-            // x goes before y
-            Less
-        } else if x_score < y_score {
-            Greater
-        } else {
-            Equal
+/// A positive window of the first hierarchy.
+#[derive(Clone)]
+struct ScannedWindow {
+    /// Index of the classifier of the first hierarchy, which accepted the window.
+    branch: usize,
+    /// Position of the window in its pyramid level.
+    x: u32,
+    y: u32,
+    /// The window, mapped back to the 1x image.
+    face: FaceInfo,
+}
+
+/// Merges intersecting regions into their bounding rectangle, as long as it is at most
+/// `max_area_ratio` times as large as the area covered by the regions. Empty regions are dropped.
+///
+/// With an infinite ratio, the resulting regions never intersect, so no part of the image
+/// is scanned twice. Otherwise regions, which only overlap a little (e.g. at a corner),
+/// are kept apart, as their bounding rectangle would cover much more of the image.
+fn merge_regions<I: IntoIterator<Item = Rectangle>>(
+    regions: I,
+    max_area_ratio: f64,
+) -> Vec<Rectangle> {
+    let should_merge = |a: &Rectangle, b: &Rectangle| match a.intersection(b) {
+        Some(intersection) => {
+            let covered = a.area() + b.area() - intersection.area();
+            a.union(b).area() as f64 <= covered as f64 * max_area_ratio
+        }
+        None => false,
+    };
+
+    let mut merged: Vec<Rectangle> = Vec::new();
+    for mut rect in regions {
+        if rect.is_empty() {
+            continue;
+        }
+
+        while let Some(i) = merged.iter().position(|m| should_merge(m, &rect)) {
+            rect = rect.union(&merged.swap_remove(i));
+        }
+        merged.push(rect);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        merge_regions, DetectorConfig, DetectorWorkspace, FuStDetector, NmsStrategy,
+        K_MAX_REGION_MERGE_RATIO,
+    };
+    use crate::{
+        load_model, Calibration, Detector, Error, FaceInfo, ImageData, PyramidMode, Rectangle,
+    };
+    use std::thread;
+
+    fn create_detector() -> FuStDetector {
+        FuStDetector::new(
+            load_model("model/seeta_fd_frontal_v1.0.bin")
+                .unwrap()
+                .into(),
+        )
+    }
+
+    fn load_test_image() -> image::GrayImage {
+        image::open("assets/test/scientists.jpg")
+            .unwrap()
+            .to_luma8()
+    }
+
+    fn to_tuples(faces: &[FaceInfo]) -> Vec<(i32, i32, u32, u32, f64)> {
+        faces
+            .iter()
+            .map(|face| {
+                let bbox = face.bbox();
+                (
+                    bbox.x(),
+                    bbox.y(),
+                    bbox.width(),
+                    bbox.height(),
+                    face.score(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_try_detect_empty_image() {
+        let mut detector = create_detector();
+        let image = ImageData::new(&[], 0, 0);
+        assert_eq!(
+            Some(Error::IllegalImage {
+                width: 0,
+                height: 0,
+                num_channels: 1
+            }),
+            detector.try_detect(&image).err()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Illegal image")]
+    fn test_detect_empty_image_panics() {
+        let mut detector = create_detector();
+        detector.detect(&ImageData::new(&[], 0, 0));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_try_setters_reject_illegal_values() {
+        let mut detector = create_detector();
+        assert_eq!(
+            Err(Error::IllegalWindowSize(19)),
+            detector.try_set_window_size(19)
+        );
+        assert_eq!(
+            Err(Error::IllegalSlideWindowStep {
+                step_x: 0,
+                step_y: 4
+            }),
+            detector.try_set_slide_window_step(0, 4)
+        );
+        assert_eq!(
+            Err(Error::IllegalMinFaceSize(10)),
+            detector.try_set_min_face_size(10)
+        );
+        assert_eq!(
+            Err(Error::IllegalScaleFactor(1.5)),
+            detector.try_set_pyramid_scale_factor(1.5)
+        );
+        assert!(detector.try_set_pyramid_scale_factor(f32::NAN).is_err());
+        assert_eq!(
+            Err(Error::IllegalScoreThreshold(0.0)),
+            detector.try_set_score_thresh(0.0)
+        );
+        assert!(detector.try_set_score_thresh(f64::NAN).is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_try_setters_accept_legal_values() {
+        let mut detector = create_detector();
+        assert_eq!(Ok(()), detector.try_set_window_size(40));
+        assert_eq!(Ok(()), detector.try_set_slide_window_step(4, 4));
+        assert_eq!(Ok(()), detector.try_set_min_face_size(20));
+        assert_eq!(Ok(()), detector.try_set_pyramid_scale_factor(0.8));
+        assert_eq!(Ok(()), detector.try_set_score_thresh(2.0));
+    }
+
+    #[test]
+    fn test_config_reflects_setters() {
+        let mut detector = create_detector();
+        assert_eq!(&DetectorConfig::default(), detector.config());
+
+        detector.set_min_face_size(40);
+        detector.set_max_face_size(200);
+        detector.set_slide_window_step(2, 3);
+        detector.set_pyramid_scale_factor(0.5);
+        detector.set_score_thresh(2.0);
+        let expected = DetectorConfig::builder()
+            .min_face_size(40)
+            .max_face_size(Some(200))
+            .slide_window_step(2, 3)
+            .pyramid_scale_factor(0.5)
+            .score_thresh(2.0)
+            .build()
+            .unwrap();
+        assert_eq!(&expected, detector.config());
+
+        let detector = FuStDetector::with_config(detector.model.clone(), expected.clone());
+        assert_eq!(&expected, detector.config());
+    }
+
+    #[test]
+    fn test_min_face_size_below_window_size_finds_small_faces() {
+        use image::imageops::{self, FilterType};
+
+        let image = load_test_image();
+        let crop = imageops::crop_imm(&image, 0, 0, 700, 360).to_image();
+        let small = imageops::resize(&crop, 350, 180, FilterType::Triangle);
+        let small = ImageData::new(&small, 350, 180);
+
+        let mut detector = create_detector();
+        let default_faces = detector.detect(&small);
+
+        detector.set_min_face_size(20);
+        let faces = detector.detect(&small);
+        assert!(faces.len() >= 8, "found {} faces", faces.len());
+        assert!(faces.len() > default_faces.len());
+        assert!(faces.iter().all(|face| face.bbox().width() < 40));
+    }
+
+    #[test]
+    fn test_detect_honors_face_size_bounds() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let crop = ImageData::new(&image, width, height).sub_image(0, 0, 700, 360);
+
+        let mut detector = create_detector();
+        let all_faces = detector.detect(&crop);
+
+        detector.set_min_face_size(52);
+        detector.set_max_face_size(58);
+        let faces = detector.detect(&crop);
+        assert!(!faces.is_empty());
+        assert!(faces.len() < all_faces.len());
+        for face in &faces {
+            let size = face.bbox().width();
+            assert!((52..=58).contains(&size), "face of size {}", size);
         }
-    });
 
-    let mut select_idx = 0;
-    let mut mask_merged = vec![false; bboxes.len()];
+        detector.set_min_face_size(60);
+        detector.set_max_face_size(50);
+        assert!(detector.detect(&crop).is_empty());
+    }
+
+    #[test]
+    fn test_detect_in_regions() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height);
+        let crop = image.sub_image(0, 0, 700, 360);
+
+        let mut detector = create_detector();
+        let whole_image = [Rectangle::new(-10, -10, 800, 400)];
+        assert_eq!(
+            to_tuples(&detector.detect(&crop)),
+            to_tuples(&detector.detect_in_regions(&crop, &whole_image))
+        );
+
+        // two overlapping regions around the face at (1418, 210)
+        let regions = [
+            Rectangle::new(1400, 190, 70, 100),
+            Rectangle::new(1430, 190, 70, 100),
+        ];
+        let faces = detector.detect_in_regions(&image, &regions);
+        assert_eq!(1, faces.len());
+        let bbox = faces[0].bbox();
+        assert!(bbox.x() >= 1400 && bbox.x() + bbox.width() as i32 <= 1500);
+        assert!(bbox.y() >= 190 && bbox.y() + bbox.height() as i32 <= 290);
+    }
+
+    #[test]
+    fn test_detect_in_crossing_regions() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height);
+
+        // a tall and a wide region, which both contain the face at (1419, 210)
+        let regions = [
+            Rectangle::new(1410, 150, 80, 200),
+            Rectangle::new(1370, 200, 160, 80),
+        ];
+        let detector = create_detector();
+        assert_eq!(2, detector.prepare_regions(&image, &regions).len());
+
+        let mut workspace = DetectorWorkspace::new();
+        let faces = detector
+            .try_detect_in_regions_with_workspace(&mut workspace, &image, &regions)
+            .unwrap();
+        assert_eq!(1, faces.len());
+        assert!(faces[0].bbox().iou(&Rectangle::new(1419, 210, 62, 62)) > 0.8);
+
+        // the scores of windows in the overlap are not summed up across the regions,
+        // so the face has the score of a detection on the crop of either region alone
+        let best_score = regions
+            .iter()
+            .flat_map(|r| {
+                let crop = image.sub_image(r.x() as u32, r.y() as u32, r.width(), r.height());
+                detector.detect_with_workspace(&mut workspace, &crop)
+            })
+            .map(|face| face.score())
+            .fold(f64::NEG_INFINITY, f64::max);
+        assert_eq!(best_score, faces[0].score());
+    }
+
+    #[test]
+    fn test_prepare_regions_with_huge_margin() {
+        let pixels = [0u8; 64 * 48];
+        let image = ImageData::new(&pixels, 64, 48);
+        let mut detector = create_detector();
+        detector.set_region_margin(u32::MAX);
+        let regions = [
+            Rectangle::new(i32::MAX, i32::MAX, u32::MAX, u32::MAX),
+            Rectangle::new(i32::MIN, 0, 1, 1),
+        ];
+        assert_eq!(
+            vec![Rectangle::new(0, 0, 64, 48)],
+            detector.prepare_regions(&image, &regions)
+        );
+        assert!(detector.detect_in_regions(&image, &regions).is_empty());
+    }
+
+    #[test]
+    fn test_merge_regions() {
+        let a = Rectangle::new(0, 0, 100, 100);
+        // overlapping side by side, the bounding rectangle covers no extra area
+        let b = Rectangle::new(50, 0, 100, 100);
+        assert_eq!(
+            vec![Rectangle::new(0, 0, 150, 100)],
+            merge_regions(vec![a, b], K_MAX_REGION_MERGE_RATIO)
+        );
+        // overlapping at a corner, the bounding rectangle is 9/7 of the covered area
+        let c = Rectangle::new(50, 50, 100, 100);
+        assert_eq!(
+            vec![a, c],
+            merge_regions(vec![a, c], K_MAX_REGION_MERGE_RATIO)
+        );
+        assert_eq!(
+            vec![Rectangle::new(0, 0, 150, 150)],
+            merge_regions(vec![a, c], f64::INFINITY)
+        );
+        // contained and empty regions
+        let d = Rectangle::new(10, 10, 20, 20);
+        let empty = Rectangle::new(10, 10, 0, 20);
+        assert_eq!(
+            vec![a],
+            merge_regions(vec![d, empty, a], K_MAX_REGION_MERGE_RATIO)
+        );
+        // touching regions do not intersect
+        let e = Rectangle::new(100, 0, 100, 100);
+        assert_eq!(vec![a, e], merge_regions(vec![a, e], f64::INFINITY));
+    }
+
+    #[test]
+    fn test_detect_in_regions_with_margin() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height);
+        let region = [Rectangle::new(1430, 225, 30, 30)];
 
-    loop {
-        while select_idx < bboxes.len() && mask_merged[select_idx] {
-            select_idx += 1;
+        let mut detector = create_detector();
+        assert!(detector.detect_in_regions(&image, &region).is_empty());
+        assert!(detector.detect_in_regions(&image, &[]).is_empty());
+
+        detector.set_region_margin(40);
+        assert_eq!(1, detector.detect_in_regions(&image, &region).len());
+    }
+
+    #[test]
+    fn test_detect_with_nms_strategy() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let crop = ImageData::new(&image, width, height).sub_image(0, 0, 700, 360);
+
+        let mut detector = create_detector();
+        let num_faces = detector.detect(&crop).len();
+
+        let config = DetectorConfig::builder()
+            .nms_strategy(NmsStrategy::Greedy)
+            .nms_iou_thresh(0.2)
+            .score_thresh(0.9)
+            .build()
+            .unwrap();
+        detector.set_config(config.clone());
+        let faces = detector.detect(&crop);
+        assert!(faces.len() >= num_faces / 2);
+        assert!(faces.iter().all(|face| face.score() <= 1.0));
+        for (i, a) in faces.iter().enumerate() {
+            for b in &faces[i + 1..] {
+                assert!(a.bbox().iou(b.bbox()) <= 0.2);
+            }
         }
 
-        if select_idx == bboxes.len() {
-            break;
+        let illegal = DetectorConfig {
+            nms_iou_thresh: -0.1,
+            ..config.clone()
+        };
+        assert_eq!(
+            Err(Error::IllegalIouThreshold(-0.1)),
+            detector.try_set_config(illegal)
+        );
+        assert_eq!(&config, detector.config());
+    }
+
+    #[test]
+    fn test_detect_with_provenance() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let crop = ImageData::new(&image, width, height).sub_image(0, 0, 700, 360);
+
+        let mut detector = create_detector();
+        let expected = to_tuples(&detector.detect(&crop));
+        assert!(detector.detect(&crop)[0].provenance().is_none());
+
+        let config = DetectorConfig::builder()
+            .collect_provenance(true)
+            .build()
+            .unwrap();
+        detector.set_config(config);
+        let faces = detector.detect(&crop);
+        assert_eq!(expected, to_tuples(&faces));
+
+        // one LAB stage and three MLP stages in the bundled model
+        for face in &faces {
+            let provenance = face.provenance().unwrap();
+            assert!(provenance.pyramid_scale() > 0.0 && provenance.pyramid_scale() <= 1.0);
+            assert_eq!(4, provenance.stage_scores().len());
+            assert_eq!(3, provenance.regression_outputs().len());
+            assert!(provenance.num_merged_windows() > 1);
+            let last_score = *provenance.stage_scores().last().unwrap();
+            assert!(face.score() >= f64::from(last_score));
         }
+    }
 
-        bboxes_nms.push(bboxes[select_idx].clone());
-        mask_merged[select_idx] = true;
+    #[test]
+    fn test_detect_with_calibration() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let crop = ImageData::new(&image, width, height).sub_image(0, 0, 700, 360);
+
+        let mut detector = create_detector();
+        let faces = detector.detect(&crop);
+        assert!(faces.iter().all(|face| face.confidence().is_none()));
+
+        let calibration = Calibration::platt(-1.0, 10.0).unwrap();
+        let model = load_model("model/seeta_fd_frontal_v1.0.bin")
+            .unwrap()
+            .with_calibration(calibration.clone());
+        let mut detector = FuStDetector::new(model.into());
+        let calibrated = detector.detect(&crop);
+        assert_eq!(to_tuples(&faces), to_tuples(&calibrated));
+        for face in &calibrated {
+            let confidence = face.confidence().unwrap();
+            assert!((0.0..=1.0).contains(&confidence));
+            assert_eq!(calibration.apply(face.score()), confidence);
+        }
+    }
 
-        let mut score;
-        {
-            score = bboxes_nms.last().unwrap().score();
+    #[test]
+    fn test_detect_with_branch_yaws() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let crop = ImageData::new(&image, width, height).sub_image(0, 0, 700, 360);
+
+        let mut detector = create_detector();
+        let faces = detector.detect(&crop);
+        assert!(faces.iter().all(|face| face.yaw().is_none()));
+        assert!(faces.iter().all(|face| face.roll().is_none()));
+        // the bundled model has three view-specific branches
+        assert!(faces.iter().all(|face| face.yaw_bin().unwrap() < 3));
+
+        let yaws = [-30.0, 0.0, 30.0];
+        let model = load_model("model/seeta_fd_frontal_v1.0.bin")
+            .unwrap()
+            .with_branch_yaws(yaws.to_vec())
+            .unwrap();
+        let mut detector = FuStDetector::new(model.into());
+        let faces_with_yaw = detector.detect(&crop);
+        assert_eq!(to_tuples(&faces), to_tuples(&faces_with_yaw));
+        for face in &faces_with_yaw {
+            assert_eq!(Some(yaws[face.yaw_bin().unwrap()]), face.yaw());
+        }
+    }
+
+    #[test]
+    fn test_detect_rotated_faces() {
+        let image = load_test_image();
+        let crop = image::imageops::crop_imm(&image, 0, 0, 700, 360).to_image();
+        let mut detector = create_detector();
+        let upright = detector.detect(&ImageData::new(&crop, 700, 360));
+        assert!(upright.iter().all(|face| face.roll().is_none()));
+
+        // rotated clockwise, so that the faces have a roll of 90 degrees
+        let rotated = image::imageops::rotate90(&crop);
+        let config = DetectorConfig::builder()
+            .roll_angles(vec![0.0, 90.0, 180.0, 270.0])
+            .build()
+            .unwrap();
+        detector.set_config(config);
+        let faces = detector.detect(&ImageData::new(&rotated, 360, 700));
+        assert!(faces.iter().all(|face| face.roll().is_some()));
+
+        for face in &upright {
+            let bbox = face.bbox();
+            let expected =
+                Rectangle::new(360 - bbox.bottom(), bbox.x(), bbox.height(), bbox.width());
+            let found = faces
+                .iter()
+                .find(|f| *f.bbox() == expected)
+                .unwrap_or_else(|| panic!("Face {:?} not found", expected));
+            assert_eq!(face.score(), found.score());
+            assert_eq!(Some(90.0), found.roll());
+            assert_eq!(90.0, found.rotated_bbox().angle());
         }
+    }
 
-        let area1;
-        let x1;
-        let y1;
-        let x2;
-        let y2;
+    #[test]
+    fn test_detect_with_area_averaging_pyramid() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height);
+        let crop = image.sub_image(0, 0, 700, 360);
+
+        let mut detector = create_detector();
+        let bilinear = detector.detect(&crop);
+
+        let config = DetectorConfig::builder()
+            .pyramid_mode(PyramidMode::AreaAveraging)
+            .build()
+            .unwrap();
+        detector.set_config(config);
+        let area_averaging = detector.detect(&crop);
+
+        assert_eq!(bilinear.len(), area_averaging.len());
+        for face in &area_averaging {
+            assert!(bilinear
+                .iter()
+                .any(|other| other.bbox().iou(face.bbox()) > 0.5));
+        }
+    }
+
+    #[test]
+    fn test_detect_with_stride() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let mut detector = create_detector();
+        let expected = detector.detect(&ImageData::new(&image, width, height));
+        assert!(!expected.is_empty());
+
+        let stride = width + 13;
+        let mut padded = vec![0xff; (stride * height) as usize];
+        for (src, dest) in image
+            .chunks_exact(width as usize)
+            .zip(padded.chunks_exact_mut(stride as usize))
         {
-            area1 = (bboxes[select_idx].bbox().width() * bboxes[select_idx].bbox().height()) as f32;
-            x1 = bboxes[select_idx].bbox().x();
-            y1 = bboxes[select_idx].bbox().y();
-            x2 = bboxes[select_idx].bbox().x() + bboxes[select_idx].bbox().width() as i32 - 1;
-            y2 = bboxes[select_idx].bbox().y() + bboxes[select_idx].bbox().height() as i32 - 1;
+            dest[..width as usize].copy_from_slice(src);
         }
+        let actual = detector.detect(&ImageData::with_stride(&padded, width, height, stride));
 
-        select_idx += 1;
+        assert_eq!(to_tuples(&expected), to_tuples(&actual));
+    }
 
-        for i in select_idx..bboxes.len() {
-            if mask_merged[i] {
-                continue;
-            }
+    #[test]
+    fn test_detector_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<FuStDetector>();
+        assert_send_sync::<Box<dyn Detector>>();
+        assert_send_sync::<DetectorWorkspace>();
+    }
 
-            let x = cmp::max(x1, bboxes[i].bbox().x());
-            let y = cmp::max(y1, bboxes[i].bbox().y());
-            let w = cmp::min(
-                x2,
-                bboxes[i].bbox().x() + bboxes[i].bbox().width() as i32 - 1,
-            ) - x
-                + 1;
-            let h = cmp::min(
-                y2,
-                bboxes[i].bbox().y() + bboxes[i].bbox().height() as i32 - 1,
-            ) - y
-                + 1;
-
-            if w <= 0 || h <= 0 {
-                continue;
-            }
+    #[test]
+    fn test_detect_with_workspace_from_multiple_threads() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height);
+        let crops = [
+            image.sub_image(0, 0, 700, 360),
+            image.sub_image(966, 0, 700, 360),
+        ];
+
+        let mut detector = create_detector();
+        let expected: Vec<_> = crops
+            .iter()
+            .map(|crop| to_tuples(&detector.detect(crop)))
+            .collect();
+        assert!(expected.iter().all(|faces| !faces.is_empty()));
+
+        let detector = &detector;
+        let actual: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = crops
+                .iter()
+                .map(|crop| {
+                    scope.spawn(move || {
+                        let mut workspace = DetectorWorkspace::new();
+                        to_tuples(&detector.detect_with_workspace(&mut workspace, crop))
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        assert_eq!(expected, actual);
+    }
 
-            let area2 = (bboxes[i].bbox().width() * bboxes[i].bbox().height()) as f32;
-            let area_intersect = (w * h) as f32;
-            let area_union = area1 + area2 - area_intersect;
-            if area_intersect / area_union > iou_thresh {
-                mask_merged[i] = true;
-                let bbox_i_score = bboxes[i].score();
-                score += bbox_i_score;
-            }
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_workspace_keeps_window_workspaces() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let crop = ImageData::new(&image, width, height).sub_image(0, 0, 700, 360);
+
+        let detector = create_detector();
+        let mut workspace = DetectorWorkspace::new();
+        let expected = to_tuples(&detector.detect_with_workspace(&mut workspace, &crop));
+        assert!(!expected.is_empty());
+        assert!(!workspace.windows.is_empty());
+        assert_eq!(
+            expected,
+            to_tuples(&detector.detect_with_workspace(&mut workspace, &crop))
+        );
+    }
+
+    #[test]
+    fn test_detect_batch_preserves_order() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height);
+        let crops = [
+            image.sub_image(966, 0, 700, 360),
+            image.sub_image(0, 0, 700, 360),
+            image.sub_image(966, 0, 700, 360),
+        ];
+
+        let mut detector = create_detector();
+        let expected: Vec<_> = crops
+            .iter()
+            .map(|crop| to_tuples(&detector.detect(crop)))
+            .collect();
+
+        let to_batch_tuples =
+            |faces: Vec<Vec<FaceInfo>>| faces.iter().map(|f| to_tuples(f)).collect::<Vec<_>>();
+        assert_eq!(expected, to_batch_tuples(detector.detect_batch(&crops)));
+        // the second batch re-uses the workspaces of the threads
+        assert_eq!(expected, to_batch_tuples(detector.detect_batch(&crops)));
+        assert!(detector.detect_batch(&[]).is_empty());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_detect_batch_does_not_depend_on_thread_count() {
+        let image = load_test_image();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height);
+        let crops = [
+            image.sub_image(0, 0, 700, 360),
+            image.sub_image(966, 0, 700, 360),
+        ];
+
+        let detector = create_detector();
+        let pool = |num_threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap()
+        };
+        let single = detector.try_detect_batch_in_pool(&crops, &pool(1)).unwrap();
+        let multi = pool(4);
+        assert!(!single[0].is_empty());
+        for _ in 0..2 {
+            let faces = detector.try_detect_batch_in_pool(&crops, &multi).unwrap();
+            assert_eq!(to_tuples(&single[0]), to_tuples(&faces[0]));
+            assert_eq!(to_tuples(&single[1]), to_tuples(&faces[1]));
         }
+    }
 
-        bboxes_nms.last_mut().unwrap().set_score(score);
+    #[test]
+    fn test_try_detect_batch_rejects_empty_image() {
+        let detector = create_detector();
+        let image = [0u8; 40 * 40];
+        let images = [ImageData::new(&image, 40, 40), ImageData::new(&[], 0, 0)];
+        assert_eq!(
+            detector.try_detect_batch(&images).err(),
+            Some(Error::IllegalImage {
+                width: 0,
+                height: 0,
+                num_channels: 1
+            })
+        );
     }
 }
@@ -0,0 +1,344 @@
+// This file is part of the open-source port of SeetaFace engine, which originally includes three modules:
+//      SeetaFace Detection, SeetaFace Alignment, and SeetaFace Identification.
+//
+// This file is part of the SeetaFace Detection module, containing codes implementing the face detection method described in the following paper:
+//
+//      Funnel-structured cascade for multi-view face detection with alignment awareness,
+//      Shuzhe Wu, Meina Kan, Zhenliang He, Shiguang Shan, Xilin Chen.
+//      In Neurocomputing (under review)
+//
+// Copyright (C) 2016, Visual Information Processing and Learning (VIPL) group,
+// Institute of Computing Technology, Chinese Academy of Sciences, Beijing, China.
+//
+// As an open-source face recognition engine: you can redistribute SeetaFace source codes
+// and/or modify it under the terms of the BSD 2-Clause License.
+//
+// You should have received a copy of the BSD 2-Clause License along with the software.
+// If not, see < https://opensource.org/licenses/BSD-2-Clause>.
+
+use std::cmp::Ordering::*;
+use std::mem;
+
+use crate::common::{FaceInfo, Rectangle};
+use crate::Error;
+
+/// Strategy of non-maximum suppression, which merges overlapping detections of the same face
+/// after the last stage of the detector.
+///
+/// Detections are merged, if their intersection over union (IoU) is greater than the IoU
+/// threshold of the detector configuration. Note that the score of a single detection lies
+/// in (0, 1), so the strategies, which do not sum up scores, require a score threshold below 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NmsStrategy {
+    /// Greedy suppression, which keeps the detection with the highest score and assigns it
+    /// the sum of scores of the suppressed detections. This is the behavior of SeetaFace
+    /// and the default strategy.
+    #[default]
+    ScoreSum,
+    /// Greedy suppression, which keeps the detection with the highest score and its score.
+    Greedy,
+    /// Soft-NMS, which decays the score of an overlapping detection by `1 - IoU`
+    /// instead of suppressing it.
+    SoftLinear,
+    /// Soft-NMS, which decays the score of every detection by `exp(-IoU² / sigma)`.
+    /// The IoU threshold is not used.
+    SoftGaussian { sigma: f32 },
+    /// Weighted box fusion, which replaces overlapping detections by the average of their
+    /// bounding boxes, weighted by their scores, and assigns it the sum of their scores.
+    WeightedBoxFusion,
+}
+
+impl NmsStrategy {
+    pub(super) fn validate(&self) -> Result<(), Error> {
+        match *self {
+            NmsStrategy::SoftGaussian { sigma } if !(sigma > 0.0 && sigma.is_finite()) => {
+                Err(Error::IllegalSoftNmsSigma(sigma))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Merge {
+    SumScores,
+    KeepScore,
+    FuseBoxes,
+}
+
+/// Scratch buffers of the non-maximum suppression, kept in the detector workspace.
+#[derive(Default)]
+pub(super) struct NmsBuffers {
+    order: Vec<usize>,
+    mask_merged: Vec<bool>,
+}
+
+pub(super) fn non_maximum_suppression(
+    strategy: NmsStrategy,
+    bboxes: &mut [FaceInfo],
+    bboxes_nms: &mut Vec<FaceInfo>,
+    iou_thresh: f32,
+    buffers: &mut NmsBuffers,
+) {
+    match strategy {
+        NmsStrategy::ScoreSum => {
+            greedy_nms(bboxes, bboxes_nms, iou_thresh, Merge::SumScores, buffers)
+        }
+        NmsStrategy::Greedy => {
+            greedy_nms(bboxes, bboxes_nms, iou_thresh, Merge::KeepScore, buffers)
+        }
+        NmsStrategy::WeightedBoxFusion => {
+            greedy_nms(bboxes, bboxes_nms, iou_thresh, Merge::FuseBoxes, buffers)
+        }
+        NmsStrategy::SoftLinear => soft_nms(bboxes, bboxes_nms, |iou| {
+            if iou > iou_thresh {
+                1.0 - iou
+            } else {
+                1.0
+            }
+        }),
+        NmsStrategy::SoftGaussian { sigma } => {
+            soft_nms(bboxes, bboxes_nms, |iou| (-iou * iou / sigma).exp())
+        }
+    }
+}
+
+fn greedy_nms(
+    bboxes: &[FaceInfo],
+    bboxes_nms: &mut Vec<FaceInfo>,
+    iou_thresh: f32,
+    merge: Merge,
+    buffers: &mut NmsBuffers,
+) {
+    bboxes_nms.clear();
+
+    // indices by descending score, equal scores in their original order
+    let order = &mut buffers.order;
+    order.clear();
+    order.extend(0..bboxes.len());
+    order.sort_unstable_by(|&x, &y| {
+        let x_score = bboxes[x].score();
+        let y_score = bboxes[y].score();
+        if x_score > y_score {
+            // x goes before y
+            Less
+        } else if x_score < y_score {
+            Greater
+        } else {
+            x.cmp(&y)
+        }
+    });
+
+    let mask_merged = &mut buffers.mask_merged;
+    mask_merged.clear();
+    mask_merged.resize(bboxes.len(), false);
+
+    for (n, &select_idx) in order.iter().enumerate() {
+        if mask_merged[n] {
+            continue;
+        }
+        mask_merged[n] = true;
+
+        let selected = &bboxes[select_idx];
+        let mut score = selected.score();
+        let mut num_merged_windows = 0;
+        let mut fused = BoxSum::new();
+        fused.add(selected);
+
+        for (m, &i) in order.iter().enumerate().skip(n + 1) {
+            if mask_merged[m] {
+                continue;
+            }
+
+            if selected.bbox().iou(bboxes[i].bbox()) > iou_thresh {
+                mask_merged[m] = true;
+                score += bboxes[i].score();
+                num_merged_windows += bboxes[i].provenance().map_or(0, |p| p.num_merged_windows);
+                fused.add(&bboxes[i]);
+            }
+        }
+
+        bboxes_nms.push(selected.clone());
+        let face = bboxes_nms.last_mut().unwrap();
+        if let Some(provenance) = face.provenance_mut() {
+            provenance.num_merged_windows += num_merged_windows;
+        }
+        match merge {
+            Merge::KeepScore => {}
+            Merge::SumScores => face.set_score(score),
+            Merge::FuseBoxes => {
+                fused.store_average(face.bbox_mut());
+                face.set_score(score);
+            }
+        }
+    }
+}
+
+/// Runs soft-NMS in place: the selected detections are moved to the front of `bboxes`
+/// and the rest of it is left in an unspecified order.
+fn soft_nms<F>(bboxes: &mut [FaceInfo], bboxes_nms: &mut Vec<FaceInfo>, decay: F)
+where
+    F: Fn(f32) -> f32,
+{
+    bboxes_nms.clear();
+    let mut remaining = bboxes;
+
+    while !remaining.is_empty() {
+        let mut best = 0;
+        for i in 1..remaining.len() {
+            if remaining[i].score() > remaining[best].score() {
+                best = i;
+            }
+        }
+        // move the selected detection to the front, keeping the order of the others
+        remaining[..=best].rotate_right(1);
+        let (selected, rest) = mem::take(&mut remaining)
+            .split_first_mut()
+            .expect("Remaining detections are not empty");
+
+        let mut len = 0;
+        for i in 0..rest.len() {
+            let factor = decay(selected.bbox().iou(rest[i].bbox()));
+            let face = &mut rest[i];
+            face.set_score(face.score() * f64::from(factor));
+            if face.score() > 0.0 {
+                rest.swap(len, i);
+                len += 1;
+            }
+        }
+        remaining = &mut rest[..len];
+
+        bboxes_nms.push(selected.clone());
+    }
+}
+
+/// Sum of the bounding boxes of detections, weighted by their scores.
+struct BoxSum {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    weight: f64,
+}
+
+impl BoxSum {
+    fn new() -> Self {
+        BoxSum {
+            x: 0.0,
+            y: 0.0,
+            width: 0.0,
+            height: 0.0,
+            weight: 0.0,
+        }
+    }
+
+    fn add(&mut self, face: &FaceInfo) {
+        let bbox = face.bbox();
+        let weight = face.score();
+        self.x += f64::from(bbox.x()) * weight;
+        self.y += f64::from(bbox.y()) * weight;
+        self.width += f64::from(bbox.width()) * weight;
+        self.height += f64::from(bbox.height()) * weight;
+        self.weight += weight;
+    }
+
+    fn store_average(&self, bbox: &mut Rectangle) {
+        if self.weight <= 0.0 {
+            return;
+        }
+        bbox.set_x((self.x / self.weight).round() as i32);
+        bbox.set_y((self.y / self.weight).round() as i32);
+        bbox.set_width((self.width / self.weight).round() as u32);
+        bbox.set_height((self.height / self.weight).round() as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{non_maximum_suppression, NmsBuffers, NmsStrategy};
+    use crate::common::{FaceInfo, Rectangle};
+    use crate::Error;
+
+    fn face(x: i32, y: i32, size: u32, score: f64) -> FaceInfo {
+        let mut face = FaceInfo::new();
+        *face.bbox_mut() = Rectangle::new(x, y, size, size);
+        face.set_score(score);
+        face
+    }
+
+    fn run(strategy: NmsStrategy, iou_thresh: f32) -> Vec<(i32, i32, u32, f64)> {
+        // two overlapping boxes (IoU = 2/3) and a separate one
+        let mut faces = vec![
+            face(0, 0, 10, 0.5),
+            face(200, 0, 10, 0.25),
+            face(2, 0, 10, 1.0),
+        ];
+        let mut faces_nms = Vec::new();
+        non_maximum_suppression(
+            strategy,
+            &mut faces,
+            &mut faces_nms,
+            iou_thresh,
+            &mut NmsBuffers::default(),
+        );
+        faces_nms
+            .iter()
+            .map(|f| (f.bbox().x(), f.bbox().y(), f.bbox().width(), f.score()))
+            .collect()
+    }
+
+    #[test]
+    fn test_score_sum() {
+        assert_eq!(
+            vec![(2, 0, 10, 1.5), (200, 0, 10, 0.25)],
+            run(NmsStrategy::ScoreSum, 0.3)
+        );
+        assert_eq!(
+            vec![(2, 0, 10, 1.0), (0, 0, 10, 0.5), (200, 0, 10, 0.25)],
+            run(NmsStrategy::ScoreSum, 0.8)
+        );
+    }
+
+    #[test]
+    fn test_greedy() {
+        assert_eq!(
+            vec![(2, 0, 10, 1.0), (200, 0, 10, 0.25)],
+            run(NmsStrategy::Greedy, 0.3)
+        );
+    }
+
+    #[test]
+    fn test_weighted_box_fusion() {
+        // x = (2 * 1.0 + 0 * 0.5) / 1.5
+        assert_eq!(
+            vec![(1, 0, 10, 1.5), (200, 0, 10, 0.25)],
+            run(NmsStrategy::WeightedBoxFusion, 0.3)
+        );
+    }
+
+    #[test]
+    fn test_soft_nms() {
+        let linear = run(NmsStrategy::SoftLinear, 0.3);
+        assert_eq!(3, linear.len());
+        assert_eq!((2, 0, 10, 1.0), linear[0]);
+        assert_eq!((200, 0, 10, 0.25), linear[1]);
+        assert_eq!((0, 0, 10), (linear[2].0, linear[2].1, linear[2].2));
+        assert!((linear[2].3 - 0.5 / 3.0).abs() < 1e-6);
+
+        let gaussian = run(NmsStrategy::SoftGaussian { sigma: 0.5 }, 0.3);
+        assert_eq!(3, gaussian.len());
+        assert_eq!((0, 0, 10), (gaussian[2].0, gaussian[2].1, gaussian[2].2));
+        let iou = 2.0f64 / 3.0;
+        assert!((gaussian[2].3 - 0.5 * (-iou * iou / 0.5).exp()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(Ok(()), NmsStrategy::SoftGaussian { sigma: 0.5 }.validate());
+        assert_eq!(
+            Err(Error::IllegalSoftNmsSigma(0.0)),
+            NmsStrategy::SoftGaussian { sigma: 0.0 }.validate()
+        );
+    }
+}
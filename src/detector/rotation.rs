@@ -0,0 +1,229 @@
+//! Rotation of images for the search of faces with in-plane rotation.
+
+use crate::common::{sample_bilinear, ImageData, Rectangle, RotatedRectangle};
+
+/// Rotation of an image about its center, which is used to search for faces with
+/// a given roll by running the upright detector on the rotated image.
+///
+/// The rotated image is large enough to hold the whole source image. Rotations by multiples
+/// of 90 degrees map pixels exactly, other rotations sample the source bilinearly.
+pub(super) struct ImageRotation {
+    cos: f32,
+    sin: f32,
+    src_center: (f32, f32),
+    dst_center: (f32, f32),
+    width: u32,
+    height: u32,
+}
+
+impl ImageRotation {
+    /// Rotation of an image of the given size by `angle` degrees, clockwise as displayed.
+    pub fn new(angle: f32, src_width: u32, src_height: u32) -> Self {
+        let (sin, cos) = sin_cos_degrees(angle);
+        let (src_width, src_height) = (src_width as f32, src_height as f32);
+        let width = (src_width * cos.abs() + src_height * sin.abs()).round();
+        let height = (src_width * sin.abs() + src_height * cos.abs()).round();
+        ImageRotation {
+            cos,
+            sin,
+            src_center: (src_width / 2.0, src_height / 2.0),
+            dst_center: (width / 2.0, height / 2.0),
+            width: width as u32,
+            height: height as u32,
+        }
+    }
+
+    #[inline]
+    pub fn is_identity(&self) -> bool {
+        self.sin == 0.0 && self.cos == 1.0
+    }
+
+    #[inline]
+    fn to_rotated(&self, x: f32, y: f32) -> (f32, f32) {
+        let (dx, dy) = (x - self.src_center.0, y - self.src_center.1);
+        (
+            self.dst_center.0 + dx * self.cos - dy * self.sin,
+            self.dst_center.1 + dx * self.sin + dy * self.cos,
+        )
+    }
+
+    #[inline]
+    fn to_source(&self, x: f32, y: f32) -> (f32, f32) {
+        let (dx, dy) = (x - self.dst_center.0, y - self.dst_center.1);
+        (
+            self.src_center.0 + dx * self.cos + dy * self.sin,
+            self.src_center.1 - dx * self.sin + dy * self.cos,
+        )
+    }
+
+    /// Rotate a gray-scale image into `buf`.
+    ///
+    /// Pixels of the rotated image, which lie outside of the source image, are black,
+    /// like the parts of windows beyond the image, which the detector pads with zeros.
+    /// Repeating the edges instead would smear them into streaks, which look like content.
+    pub fn rotate<'b>(&self, image: &ImageData<'b>, buf: &'b mut Vec<u8>) -> ImageData<'b> {
+        if self.is_identity() {
+            return *image;
+        }
+
+        let (src_width, src_height) = (image.width() as f32, image.height() as f32);
+        buf.clear();
+        for v in 0..self.height {
+            for u in 0..self.width {
+                let (x, y) = self.to_source(u as f32 + 0.5, v as f32 + 0.5);
+                let inside = (0.0..=src_width).contains(&x) && (0.0..=src_height).contains(&y);
+                buf.push(if inside {
+                    (sample_bilinear(image, x, y) + 0.5) as u8
+                } else {
+                    0
+                });
+            }
+        }
+
+        ImageData::new(buf, self.width, self.height)
+    }
+
+    /// The part of the rotated image, which covers a region of the source image.
+    pub fn map_region(&self, region: &Rectangle) -> Rectangle {
+        let corners = [
+            (region.x(), region.y()),
+            (region.right(), region.y()),
+            (region.right(), region.bottom()),
+            (region.x(), region.bottom()),
+        ]
+        .map(|(x, y)| self.to_rotated(x as f32, y as f32));
+
+        let min_x = corners.iter().map(|c| c.0).fold(f32::INFINITY, f32::min);
+        let min_y = corners.iter().map(|c| c.1).fold(f32::INFINITY, f32::min);
+        let max_x = corners
+            .iter()
+            .map(|c| c.0)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let max_y = corners
+            .iter()
+            .map(|c| c.1)
+            .fold(f32::NEG_INFINITY, f32::max);
+
+        let x = min_x.floor() as i32;
+        let y = min_y.floor() as i32;
+        Rectangle::new(
+            x,
+            y,
+            (max_x.ceil() as i32 - x).max(0) as u32,
+            (max_y.ceil() as i32 - y).max(0) as u32,
+        )
+        .clamp(self.width, self.height)
+    }
+
+    /// Map an upright rectangle of the rotated image back to the source image,
+    /// where it is rotated by `roll` degrees, the inverse of this rotation.
+    pub fn map_back(&self, rect: &Rectangle, roll: f32) -> RotatedRectangle {
+        let (x, y) = rect.center();
+        RotatedRectangle::new(self.to_source(x, y), rect.width(), rect.height(), roll)
+    }
+}
+
+/// Sine and cosine of an angle in degrees, which are exact for multiples of 90 degrees.
+fn sin_cos_degrees(angle: f32) -> (f32, f32) {
+    let angle = angle.rem_euclid(360.0);
+    if angle == 0.0 {
+        (0.0, 1.0)
+    } else if angle == 90.0 {
+        (1.0, 0.0)
+    } else if angle == 180.0 {
+        (0.0, -1.0)
+    } else if angle == 270.0 {
+        (-1.0, 0.0)
+    } else {
+        angle.to_radians().sin_cos()
+    }
+}
+
+/// Normalize an angle in degrees to (-180, 180].
+pub(super) fn normalize_angle(angle: f32) -> f32 {
+    let angle = angle.rem_euclid(360.0);
+    if angle > 180.0 {
+        angle - 360.0
+    } else {
+        angle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_angle, ImageRotation};
+    use crate::common::{ImageData, Rectangle};
+
+    #[test]
+    fn test_rotate_by_right_angles() {
+        // 3x2 image
+        let data = [1, 2, 3, 4, 5, 6];
+        let image = ImageData::new(&data, 3, 2);
+        let mut buf = Vec::new();
+
+        let rotation = ImageRotation::new(90.0, 3, 2);
+        let rotated = rotation.rotate(&image, &mut buf);
+        assert_eq!((2, 3), (rotated.width(), rotated.height()));
+        assert_eq!(&[4, 1, 5, 2, 6, 3], rotated.data());
+
+        let rotation = ImageRotation::new(180.0, 3, 2);
+        assert_eq!(
+            &[6, 5, 4, 3, 2, 1],
+            rotation.rotate(&image, &mut buf).data()
+        );
+
+        let rotation = ImageRotation::new(-90.0, 3, 2);
+        assert_eq!(
+            &[3, 6, 2, 5, 1, 4],
+            rotation.rotate(&image, &mut buf).data()
+        );
+
+        let rotation = ImageRotation::new(360.0, 3, 2);
+        assert!(rotation.is_identity());
+        assert_eq!(&data, rotation.rotate(&image, &mut buf).data());
+    }
+
+    #[test]
+    fn test_map_region_and_back() {
+        let rotation = ImageRotation::new(90.0, 300, 200);
+        assert_eq!(
+            Rectangle::new(0, 0, 200, 300),
+            rotation.map_region(&Rectangle::new(0, 0, 300, 200))
+        );
+        // (x, y) is mapped to (200 - y, x)
+        let region = Rectangle::new(10, 20, 40, 30);
+        let rotated = rotation.map_region(&region);
+        assert_eq!(Rectangle::new(150, 10, 30, 40), rotated);
+
+        let back = rotation.map_back(&rotated, -90.0);
+        assert_eq!((30.0, 35.0), back.center());
+        assert_eq!(region, back.bounding_rect());
+    }
+
+    #[test]
+    fn test_rotate_by_arbitrary_angle() {
+        let data = [100; 20 * 10];
+        let image = ImageData::new(&data, 20, 10);
+        let mut buf = Vec::new();
+        let rotation = ImageRotation::new(30.0, 20, 10);
+        let rotated = rotation.rotate(&image, &mut buf);
+        // 20 * cos(30) + 10 * sin(30), 20 * sin(30) + 10 * cos(30)
+        assert_eq!((22, 19), (rotated.width(), rotated.height()));
+        // the corners lie outside of the source image
+        assert!(rotated
+            .data()
+            .iter()
+            .all(|&value| value == 100 || value == 0));
+        assert_eq!(0, rotated.row(0)[0]);
+        assert_eq!(0, rotated.row(18)[21]);
+        assert_eq!(100, rotated.row(9)[11]);
+    }
+
+    #[test]
+    fn test_normalize_angle() {
+        assert_eq!(90.0, normalize_angle(-270.0));
+        assert_eq!(180.0, normalize_angle(-180.0));
+        assert_eq!(-90.0, normalize_angle(270.0));
+        assert_eq!(-15.0, normalize_angle(-15.0));
+    }
+}
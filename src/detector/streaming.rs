@@ -0,0 +1,594 @@
+//! Detection on video frames, which rescans only the parts of a frame that changed.
+
+use std::mem;
+use std::sync::Arc;
+
+use super::{
+    is_legal_image, merge_regions, DetectorConfig, DetectorWorkspace, FuStDetector, ScannedWindow,
+    K_WND_SIZE,
+};
+use crate::common::{FaceInfo, ImageData, PyramidMode, Rectangle};
+use crate::feat::FeatureMap;
+use crate::model::Model;
+use crate::Error;
+
+/// Settings of a [`StreamingDetector`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamingConfig {
+    refresh_interval: u32,
+    block_size: u32,
+    diff_thresh: f32,
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            refresh_interval: 30,
+            block_size: 16,
+            diff_thresh: 4.0,
+        }
+    }
+}
+
+impl StreamingConfig {
+    /// Create a builder, starting from the default configuration.
+    pub fn builder() -> StreamingConfigBuilder {
+        StreamingConfigBuilder {
+            config: StreamingConfig::default(),
+        }
+    }
+
+    /// Number of frames, after which a frame is scanned as a whole.
+    pub fn refresh_interval(&self) -> u32 {
+        self.refresh_interval
+    }
+
+    /// Width and height of the blocks, which are compared between frames.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Mean absolute difference of the pixels of a block, above which it counts as changed.
+    pub fn diff_thresh(&self) -> f32 {
+        self.diff_thresh
+    }
+
+    /// Check that all settings lie in their legal ranges.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.refresh_interval == 0 {
+            return Err(Error::IllegalRefreshInterval(self.refresh_interval));
+        }
+        if self.block_size == 0 {
+            return Err(Error::IllegalBlockSize(self.block_size));
+        }
+        if !(self.diff_thresh >= 0.0 && self.diff_thresh.is_finite()) {
+            return Err(Error::IllegalDiffThreshold(self.diff_thresh));
+        }
+        Ok(())
+    }
+}
+
+/// Builder of a [`StreamingConfig`].
+///
+/// Settings, which are not set explicitly, keep their default values.
+#[derive(Clone, Debug)]
+pub struct StreamingConfigBuilder {
+    config: StreamingConfig,
+}
+
+impl StreamingConfigBuilder {
+    /// Set the number of frames, after which a frame is scanned as a whole, at least 1
+    /// (the default is 30). This bounds the time, for which the detections can lag behind
+    /// slow changes, e.g. of the lighting, which stay below the difference threshold.
+    /// 1 scans every frame as a whole.
+    pub fn refresh_interval(mut self, interval: u32) -> Self {
+        self.config.refresh_interval = interval;
+        self
+    }
+
+    /// Set the width and height of the blocks, which are compared between frames,
+    /// at least 1 (the default is 16).
+    pub fn block_size(mut self, size: u32) -> Self {
+        self.config.block_size = size;
+        self
+    }
+
+    /// Set the mean absolute difference of the pixels of a block, above which it counts as
+    /// changed, non-negative (the default is 4). Higher thresholds ignore more of the sensor
+    /// noise, but also miss subtle changes.
+    pub fn diff_thresh(mut self, thresh: f32) -> Self {
+        self.config.diff_thresh = thresh;
+        self
+    }
+
+    /// Validate the settings and create the configuration.
+    pub fn build(self) -> Result<StreamingConfig, Error> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Face detector for consecutive frames of a video from a static camera.
+///
+/// The detector compares each frame block by block with the previous ones and only
+/// re-evaluates the sliding windows of the first hierarchy, which overlap changed blocks.
+/// Elsewhere, the windows accepted in earlier frames are carried forward. The later
+/// hierarchies run on all proposals as usual, which is cheap in comparison. If no block
+/// changed at all, the detections of the previous frame are returned.
+///
+/// Changes below the difference threshold are not lost: blocks are compared with their
+/// content at the time they were scanned, so slow changes add up until they are detected.
+/// Every [`refresh_interval`](StreamingConfigBuilder::refresh_interval) frames, and
+/// whenever the size of the frames changes, a frame is scanned as a whole.
+///
+/// Where a frame equals the remembered blocks, the detections are the same as those of a scan
+/// of the whole frame. With [`roll_angles`](crate::DetectorConfigBuilder::roll_angles) other than the default,
+/// every frame is scanned as a whole.
+pub struct StreamingDetector {
+    detector: FuStDetector,
+    config: StreamingConfig,
+    workspace: DetectorWorkspace,
+    gray_buf: Vec<u8>,
+    state: Option<StreamState>,
+    changed_fraction: f32,
+}
+
+/// What the detector remembers about the previous frames.
+struct StreamState {
+    width: u32,
+    height: u32,
+    /// Gray-scale pixels of each block, as of the frame in which it was last scanned.
+    frame: Vec<u8>,
+    /// Accepted windows of each pyramid level, in the order of their positions.
+    levels: Vec<Vec<ScannedWindow>>,
+    faces: Vec<FaceInfo>,
+    frames_since_refresh: u32,
+}
+
+impl StreamingDetector {
+    /// Create a streaming detector with the given detection and streaming configurations.
+    ///
+    /// Returns an error if the detection configuration is not valid,
+    /// see [`DetectorConfig::validate`].
+    pub fn new(
+        model: impl Into<Arc<Model>>,
+        config: DetectorConfig,
+        streaming_config: StreamingConfig,
+    ) -> Result<Self, Error> {
+        config.validate()?;
+        Ok(StreamingDetector {
+            detector: FuStDetector::with_config(model.into(), config),
+            config: streaming_config,
+            workspace: DetectorWorkspace::new(),
+            gray_buf: Vec::new(),
+            state: None,
+            changed_fraction: 1.0,
+        })
+    }
+
+    #[inline]
+    pub fn config(&self) -> &DetectorConfig {
+        &self.detector.config
+    }
+
+    #[inline]
+    pub fn streaming_config(&self) -> &StreamingConfig {
+        &self.config
+    }
+
+    /// Fraction of the area of the latest frame, which changed since the previous frames.
+    /// It is 1 if the frame was scanned as a whole.
+    #[inline]
+    pub fn changed_fraction(&self) -> f32 {
+        self.changed_fraction
+    }
+
+    /// Forget the previous frames, e.g. after a cut in the video.
+    /// The next frame is scanned as a whole.
+    pub fn reset(&mut self) {
+        self.state = None;
+    }
+
+    /// Detect faces on the next frame.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `image` is not a legal image, i.e. it has `width` or `height` equal to 0.
+    ///
+    /// See [`try_detect`](StreamingDetector::try_detect) for a non-panicking version.
+    pub fn detect(&mut self, image: &ImageData) -> Vec<FaceInfo> {
+        self.try_detect(image).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Detect faces on the next frame.
+    ///
+    /// Same as [`detect`](StreamingDetector::detect), but returns
+    /// [`Error::IllegalImage`] instead of panicking if `image` is not a legal image.
+    pub fn try_detect(&mut self, image: &ImageData) -> Result<Vec<FaceInfo>, Error> {
+        if !is_legal_image(image) {
+            return Err(Error::IllegalImage {
+                width: image.width(),
+                height: image.height(),
+                num_channels: image.num_channels(),
+            });
+        }
+
+        let whole_image = Rectangle::new(0, 0, image.width(), image.height());
+        if self.detector.config.searches_rotations() {
+            self.state = None;
+            self.changed_fraction = 1.0;
+            return Ok(self.detector.detect_in_regions_impl(
+                &mut self.workspace,
+                image,
+                &[whole_image],
+            ));
+        }
+
+        let mut gray_buf = mem::take(&mut self.gray_buf);
+        let gray = image.to_gray(&mut gray_buf);
+
+        let changed_blocks = match self.state {
+            Some(ref mut state)
+                if state.width == gray.width()
+                    && state.height == gray.height()
+                    && state.frames_since_refresh + 1 < self.config.refresh_interval =>
+            {
+                state.frames_since_refresh += 1;
+                Some(update_changed_blocks(state, &gray, &self.config))
+            }
+            _ => None,
+        };
+
+        let faces = match changed_blocks {
+            Some(blocks) => {
+                let changed_area: u64 = blocks.iter().map(|b| b.area()).sum();
+                self.changed_fraction = changed_area as f32 / whole_image.area() as f32;
+                if blocks.is_empty() {
+                    self.state.as_ref().unwrap().faces.clone()
+                } else {
+                    let mut state = self.state.take().unwrap();
+                    let faces = self.scan(&gray, Some(&blocks), &mut state.levels);
+                    state.faces = faces.clone();
+                    self.state = Some(state);
+                    faces
+                }
+            }
+            None => {
+                self.changed_fraction = 1.0;
+                let mut levels = Vec::new();
+                let faces = self.scan(&gray, None, &mut levels);
+                let mut frame = Vec::new();
+                gray.copy_to(&mut frame);
+                self.state = Some(StreamState {
+                    width: gray.width(),
+                    height: gray.height(),
+                    frame,
+                    levels,
+                    faces: faces.clone(),
+                    frames_since_refresh: 0,
+                });
+                faces
+            }
+        };
+
+        self.gray_buf = gray_buf;
+        Ok(faces)
+    }
+
+    /// Scans the windows of each pyramid level, which overlap the changed blocks (or all
+    /// windows, if `changed_blocks` is `None`), updates the accepted windows of the levels and
+    /// runs the later hierarchies on all of them.
+    fn scan(
+        &mut self,
+        image: &ImageData,
+        changed_blocks: Option<&[Rectangle]>,
+        levels: &mut Vec<Vec<ScannedWindow>>,
+    ) -> Vec<FaceInfo> {
+        let detector = &self.detector;
+        let whole_image = Rectangle::new(0, 0, image.width(), image.height());
+        let (min_scale, max_scale) = detector.pyramid_scale_range(image.width(), image.height());
+
+        let image_pyramid = &mut self.workspace.pyramid;
+        image_pyramid.set_max_scale(max_scale);
+        image_pyramid.set_min_scale(min_scale);
+        image_pyramid.set_scale_step(detector.config.pyramid_scale_factor);
+        image_pyramid.set_mode(detector.config.pyramid_mode);
+
+        let mut level = 0;
+        let mut pyramid_levels = image_pyramid.levels(image);
+        while let Some((image_scaled, scale_factor)) = pyramid_levels.next_level() {
+            if levels.len() <= level {
+                levels.push(Vec::new());
+            }
+            let windows = &mut levels[level];
+            level += 1;
+
+            // rounding of the scaled size may leave no room for a single window
+            if image_scaled.width() < K_WND_SIZE || image_scaled.height() < K_WND_SIZE {
+                continue;
+            }
+
+            let regions = match changed_blocks {
+                Some(blocks) => {
+                    let regions = level_regions(blocks, image, &image_scaled, &detector.config);
+                    windows.retain(|w| !regions.iter().any(|r| contains_window(r, w)));
+                    regions
+                }
+                None => {
+                    windows.clear();
+                    vec![Rectangle::new(
+                        0,
+                        0,
+                        image_scaled.width(),
+                        image_scaled.height(),
+                    )]
+                }
+            };
+
+            for region in &regions {
+                if region.width() < K_WND_SIZE || region.height() < K_WND_SIZE {
+                    continue;
+                }
+                let part = image_scaled.sub_image(
+                    region.x() as u32,
+                    region.y() as u32,
+                    region.width(),
+                    region.height(),
+                );
+                self.workspace.lab_boosted.compute(&part);
+                let rows = detector.scan_pyramid_level(
+                    &self.workspace.lab_boosted,
+                    &mut self.workspace.scan_rows,
+                    &part,
+                    (region.x() as u32, region.y() as u32),
+                    scale_factor,
+                    &whole_image,
+                );
+                for row in rows {
+                    windows.append(row);
+                }
+            }
+            // the same order as a scan of the whole level
+            windows.sort_by_key(|w| (w.y, w.x));
+        }
+
+        let workspace = &mut self.workspace;
+        workspace.reset_proposals(detector.model.get_hierarchy_size(0) as usize);
+        for window in levels.iter().flatten() {
+            workspace.proposals[window.branch].push(window.face.clone());
+        }
+
+        let mut faces = detector.refine_proposals(workspace, image);
+        detector.filter_faces(&mut faces);
+        detector.annotate(&mut faces);
+        faces
+    }
+}
+
+/// Compares the blocks of the frame with the remembered ones, stores the blocks
+/// which changed and returns them.
+fn update_changed_blocks(
+    state: &mut StreamState,
+    image: &ImageData,
+    config: &StreamingConfig,
+) -> Vec<Rectangle> {
+    let width = state.width as usize;
+    let block_size = config.block_size;
+    let mut changed = Vec::new();
+
+    for y in (0..state.height).step_by(block_size as usize) {
+        let block_height = block_size.min(state.height - y);
+        for x in (0..state.width).step_by(block_size as usize) {
+            let block_width = block_size.min(state.width - x);
+            let columns = x as usize..(x + block_width) as usize;
+
+            let mut diff = 0u64;
+            for row in y..y + block_height {
+                let previous = &state.frame[row as usize * width..][columns.clone()];
+                let current = &image.row(row)[columns.clone()];
+                for (&a, &b) in previous.iter().zip(current) {
+                    diff += u64::from(a.abs_diff(b));
+                }
+            }
+
+            let num_pixels = u64::from(block_width) * u64::from(block_height);
+            if diff as f64 > f64::from(config.diff_thresh) * num_pixels as f64 {
+                for row in y..y + block_height {
+                    state.frame[row as usize * width..][columns.clone()]
+                        .copy_from_slice(&image.row(row)[columns.clone()]);
+                }
+                changed.push(Rectangle::new(
+                    x as i32,
+                    y as i32,
+                    block_width,
+                    block_height,
+                ));
+            }
+        }
+    }
+    changed
+}
+
+/// Regions of a pyramid level, which contain all windows overlapping the changed blocks
+/// of the 1x image. The regions start on the grid of window positions and do not intersect,
+/// so that no window is scanned twice.
+fn level_regions(
+    blocks: &[Rectangle],
+    image: &ImageData,
+    image_scaled: &ImageData,
+    config: &DetectorConfig,
+) -> Vec<Rectangle> {
+    // a pixel of a bilinear level interpolates the two 1x pixels at or after its position,
+    // while an area-averaged one is computed from the previous level, whose pixels again
+    // reach beyond it, by less than f / (1 - f) pixels of the level in total
+    let spread = match config.pyramid_mode {
+        PyramidMode::Bilinear => 0,
+        PyramidMode::AreaAveraging => {
+            let f = f64::from(config.pyramid_scale_factor);
+            (f / (1.0 - f)).ceil() as u32 + 1
+        }
+    };
+    let map = |start: i32, end: i32, size_1x: u32, size: u32, step: u32| {
+        let ratio = f64::from(size_1x) / f64::from(size);
+        let start = ((f64::from(start - 1) / ratio).floor() as i64 - i64::from(K_WND_SIZE + spread)
+            + 1)
+        .max(0) as u32;
+        let end = if end as u32 + 1 >= size_1x {
+            size
+        } else {
+            ((f64::from(end) / ratio).ceil() as u32 + K_WND_SIZE + spread).min(size)
+        };
+        let start = start / step * step;
+        (start, end.max(start) - start)
+    };
+
+    merge_regions(
+        blocks.iter().map(|block| {
+            let (x, width) = map(
+                block.x(),
+                block.right(),
+                image.width(),
+                image_scaled.width(),
+                config.slide_window_step_x,
+            );
+            let (y, height) = map(
+                block.y(),
+                block.bottom(),
+                image.height(),
+                image_scaled.height(),
+                config.slide_window_step_y,
+            );
+            Rectangle::new(x as i32, y as i32, width, height)
+        }),
+        f64::INFINITY,
+    )
+}
+
+#[inline]
+fn contains_window(region: &Rectangle, window: &ScannedWindow) -> bool {
+    let (x, y) = (window.x as i32, window.y as i32);
+    region.x() <= x
+        && region.y() <= y
+        && x + K_WND_SIZE as i32 <= region.right()
+        && y + K_WND_SIZE as i32 <= region.bottom()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StreamingConfig, StreamingDetector};
+    use crate::{
+        create_detector_with_config, load_model, DetectorConfig, Error, FaceInfo, ImageData,
+        PyramidMode,
+    };
+
+    fn load_frame() -> (Vec<u8>, u32, u32) {
+        let image = image::open("assets/test/scientists.jpg")
+            .unwrap()
+            .crop_imm(0, 0, 700, 360)
+            .to_luma8();
+        let (width, height) = image.dimensions();
+        (image.into_raw(), width, height)
+    }
+
+    fn to_tuples(faces: &[FaceInfo]) -> Vec<(i32, i32, u32, f64)> {
+        faces
+            .iter()
+            .map(|f| (f.bbox().x(), f.bbox().y(), f.bbox().width(), f.score()))
+            .collect()
+    }
+
+    fn assert_same_as_full_detection(detector: &mut StreamingDetector, frame: &ImageData) {
+        let model = load_model("model/seeta_fd_frontal_v1.0.bin").unwrap();
+        let mut full_detector =
+            create_detector_with_config(model, detector.config().clone()).unwrap();
+        assert_eq!(
+            to_tuples(&full_detector.detect(frame)),
+            to_tuples(&detector.detect(frame))
+        );
+    }
+
+    #[test]
+    fn test_builder_rejects_illegal_values() {
+        assert_eq!(
+            Err(Error::IllegalRefreshInterval(0)),
+            StreamingConfig::builder().refresh_interval(0).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalBlockSize(0)),
+            StreamingConfig::builder().block_size(0).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalDiffThreshold(-1.0)),
+            StreamingConfig::builder().diff_thresh(-1.0).build()
+        );
+    }
+
+    #[test]
+    fn test_rescans_changed_blocks() {
+        rescans_changed_blocks(PyramidMode::Bilinear);
+    }
+
+    #[test]
+    fn test_rescans_changed_blocks_of_area_averaged_pyramid() {
+        rescans_changed_blocks(PyramidMode::AreaAveraging);
+    }
+
+    fn rescans_changed_blocks(pyramid_mode: PyramidMode) {
+        let model = load_model("model/seeta_fd_frontal_v1.0.bin").unwrap();
+        let config = DetectorConfig::builder()
+            .pyramid_mode(pyramid_mode)
+            .build()
+            .unwrap();
+        let mut detector =
+            StreamingDetector::new(model, config, StreamingConfig::default()).unwrap();
+
+        let (mut data, width, height) = load_frame();
+        let frame = ImageData::new(&data, width, height);
+        assert_same_as_full_detection(&mut detector, &frame);
+        assert_eq!(1.0, detector.changed_fraction());
+
+        let faces = detector.detect(&frame);
+        assert_eq!(0.0, detector.changed_fraction());
+        assert!(faces.len() >= 10);
+
+        // cover one of the faces
+        let face = *faces[0].bbox();
+        for y in face.y()..face.bottom() {
+            let row = y as usize * width as usize;
+            data[row + face.x() as usize..row + face.right() as usize].fill(128);
+        }
+        let frame = ImageData::new(&data, width, height);
+        assert_same_as_full_detection(&mut detector, &frame);
+        assert!(detector.changed_fraction() > 0.0 && detector.changed_fraction() < 0.05);
+        assert_eq!(faces.len() - 1, detector.detect(&frame).len());
+
+        // uncover it again
+        let (data, _, _) = load_frame();
+        let frame = ImageData::new(&data, width, height);
+        assert_same_as_full_detection(&mut detector, &frame);
+        assert!(detector.changed_fraction() > 0.0 && detector.changed_fraction() < 0.05);
+    }
+
+    #[test]
+    fn test_refresh_interval() {
+        let model = load_model("model/seeta_fd_frontal_v1.0.bin").unwrap();
+        let config = StreamingConfig::builder()
+            .refresh_interval(3)
+            .build()
+            .unwrap();
+        let mut detector =
+            StreamingDetector::new(model, DetectorConfig::default(), config).unwrap();
+
+        let (data, width, height) = load_frame();
+        let frame = ImageData::new(&data, width, height);
+        let mut changed = Vec::new();
+        for _ in 0..4 {
+            detector.detect(&frame);
+            changed.push(detector.changed_fraction());
+        }
+        detector.reset();
+        detector.detect(&frame);
+        changed.push(detector.changed_fraction());
+        assert_eq!(vec![1.0, 0.0, 0.0, 1.0, 1.0], changed);
+    }
+}
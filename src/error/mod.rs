@@ -0,0 +1,89 @@
+//! Errors of the detector.
+
+use std::error;
+use std::fmt;
+
+/// Errors returned by the fallible methods of [`Detector`](crate::Detector).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The input image can not be processed, i.e. it has a zero width or height.
+    IllegalImage {
+        width: u32,
+        height: u32,
+        num_channels: u32,
+    },
+    /// The sliding window size is less than the minimum supported size.
+    IllegalWindowSize(u32),
+    /// The horizontal or vertical step of the sliding window is zero.
+    IllegalSlideWindowStep { step_x: u32, step_y: u32 },
+    /// The minimum face size is less than the minimum supported size.
+    IllegalMinFaceSize(u32),
+    /// The image pyramid scale factor lies outside of the supported range.
+    IllegalScaleFactor(f32),
+    /// The score threshold is not positive.
+    IllegalScoreThreshold(f64),
+    /// The IoU threshold of non-maximum suppression lies outside of [0, 1].
+    IllegalIouThreshold(f32),
+    /// The sigma of Gaussian Soft-NMS is not positive.
+    IllegalSoftNmsSigma(f32),
+    /// The roll angles to search for are empty or not finite.
+    IllegalRollAngles(Vec<f32>),
+    /// The number of frames between whole-frame detections of a tracker is zero.
+    IllegalDetectionInterval(u32),
+    /// The search margin of a tracker is negative.
+    IllegalSearchMargin(f32),
+    /// The smoothing factor of a tracker lies outside of (0, 1].
+    IllegalSmoothingFactor(f32),
+    /// The number of frames between whole-frame scans of a streaming detector is zero.
+    IllegalRefreshInterval(u32),
+    /// The size of the blocks compared between frames is zero.
+    IllegalBlockSize(u32),
+    /// The difference threshold of the blocks compared between frames is negative.
+    IllegalDiffThreshold(f32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::IllegalImage {
+                width,
+                height,
+                num_channels,
+            } => write!(
+                f,
+                "Illegal image: width ({}), height ({}), num_channels ({})",
+                width, height, num_channels
+            ),
+            Error::IllegalWindowSize(wnd_size) => write!(f, "Illegal window size: {}", wnd_size),
+            Error::IllegalSlideWindowStep { step_x, step_y } => write!(
+                f,
+                "Illegal sliding window step: horizontal ({}), vertical ({})",
+                step_x, step_y
+            ),
+            Error::IllegalMinFaceSize(size) => write!(f, "Illegal min face size: {}", size),
+            Error::IllegalScaleFactor(factor) => write!(f, "Illegal scale factor: {}", factor),
+            Error::IllegalScoreThreshold(thresh) => write!(f, "Illegal threshold: {}", thresh),
+            Error::IllegalIouThreshold(thresh) => write!(f, "Illegal IoU threshold: {}", thresh),
+            Error::IllegalSoftNmsSigma(sigma) => write!(f, "Illegal Soft-NMS sigma: {}", sigma),
+            Error::IllegalRollAngles(ref angles) => {
+                write!(f, "Illegal roll angles: {:?}", angles)
+            }
+            Error::IllegalDetectionInterval(interval) => {
+                write!(f, "Illegal detection interval: {}", interval)
+            }
+            Error::IllegalSearchMargin(margin) => write!(f, "Illegal search margin: {}", margin),
+            Error::IllegalSmoothingFactor(factor) => {
+                write!(f, "Illegal smoothing factor: {}", factor)
+            }
+            Error::IllegalRefreshInterval(interval) => {
+                write!(f, "Illegal refresh interval: {}", interval)
+            }
+            Error::IllegalBlockSize(size) => write!(f, "Illegal block size: {}", size),
+            Error::IllegalDiffThreshold(thresh) => {
+                write!(f, "Illegal difference threshold: {}", thresh)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
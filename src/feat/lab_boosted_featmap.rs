@@ -40,7 +40,6 @@ pub struct LabBoostedFeatureMap {
 
 impl FeatureMap for LabBoostedFeatureMap {
     fn compute(&mut self, image: &ImageData) {
-        let input = image.data();
         let width = image.width();
         let height = image.height();
 
@@ -49,7 +48,7 @@ impl FeatureMap for LabBoostedFeatureMap {
         }
 
         self.reshape(width, height);
-        self.compute_integral_images(input);
+        self.compute_integral_images(image);
         self.compute_rect_sum();
         self.compute_feature_map();
     }
@@ -150,11 +149,18 @@ impl LabBoostedFeatureMap {
         self.square_int_img.resize(self.length, 0);
     }
 
-    fn compute_integral_images(&mut self, input: &[u8]) {
-        assert_eq!(input.len(), self.length);
+    fn compute_integral_images(&mut self, image: &ImageData) {
+        assert_eq!(image.width(), self.width);
+        assert_eq!(image.height(), self.height);
+
+        for (row, dest) in image
+            .rows()
+            .zip(self.int_img.chunks_exact_mut(self.width as usize))
+        {
+            math::copy_u8_to_i32(row, dest);
+        }
 
         unsafe {
-            math::copy_u8_to_i32(input, &mut self.int_img);
             math::square(&self.int_img, &mut self.square_int_img);
 
             LabBoostedFeatureMap::compute_integral(
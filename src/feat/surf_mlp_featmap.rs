@@ -40,7 +40,6 @@ pub struct SurfMlpFeatureMap {
 
 impl FeatureMap for SurfMlpFeatureMap {
     fn compute(&mut self, image: &ImageData) {
-        let input = image.data();
         let width = image.width();
         let height = image.height();
 
@@ -49,7 +48,7 @@ impl FeatureMap for SurfMlpFeatureMap {
         }
 
         self.reshape(width, height);
-        self.compute_gradient_images(input);
+        self.compute_gradient_images(image);
         self.compute_integral_images();
     }
 }
@@ -103,10 +102,16 @@ impl SurfMlpFeatureMap {
         self.img_buf.resize(self.length, 0);
     }
 
-    fn compute_gradient_images(&mut self, input: &[u8]) {
-        assert_eq!(input.len(), self.length);
+    fn compute_gradient_images(&mut self, image: &ImageData) {
+        assert_eq!(image.width(), self.width);
+        assert_eq!(image.height(), self.height);
 
-        math::copy_u8_to_i32(input, &mut self.img_buf);
+        for (row, dest) in image
+            .rows()
+            .zip(self.img_buf.chunks_exact_mut(self.width as usize))
+        {
+            math::copy_u8_to_i32(row, dest);
+        }
         self.compute_grad_x();
         self.compute_grad_y();
     }
@@ -215,7 +220,7 @@ impl SurfMlpFeatureMap {
         let mut dx_mask: i32;
         let mut dy_mask: i32;
         let mut cmp: u32;
-        let xor_bits: Vec<u32> = vec![0xffff_ffff, 0xffff_ffff, 0, 0];
+        let xor_bits: [u32; 4] = [0xffff_ffff, 0xffff_ffff, 0, 0];
 
         let mut src = self.int_img.as_mut_ptr();
         unsafe {
@@ -281,6 +286,8 @@ impl SurfMlpFeatureMap {
     }
 
     unsafe fn compute_feature_vector(&mut self, feature_id: usize, roi: Rectangle) {
+        const K_NUM_INT_CHANNEL: usize = FeaturePool::K_NUM_INT_CHANNEL as usize;
+
         let feature = self.feature_pool.get_feature(feature_id);
         let feature_vec = self.feature_vectors[feature_id].as_mut_ptr();
 
@@ -293,10 +300,10 @@ impl SurfMlpFeatureMap {
         let row_width: isize = (self.width as isize) * k_num_int_channel;
 
         let val = 0;
-        let mut cell_top_left: Vec<*const i32> = vec![&val; k_num_int_channel as usize];
-        let mut cell_top_right: Vec<*const i32> = vec![&val; k_num_int_channel as usize];
-        let mut cell_bottom_left: Vec<*const i32> = vec![&val; k_num_int_channel as usize];
-        let mut cell_bottom_right: Vec<*const i32> = vec![&val; k_num_int_channel as usize];
+        let mut cell_top_left: [*const i32; K_NUM_INT_CHANNEL] = [&val; K_NUM_INT_CHANNEL];
+        let mut cell_top_right: [*const i32; K_NUM_INT_CHANNEL] = [&val; K_NUM_INT_CHANNEL];
+        let mut cell_bottom_left: [*const i32; K_NUM_INT_CHANNEL] = [&val; K_NUM_INT_CHANNEL];
+        let mut cell_bottom_right: [*const i32; K_NUM_INT_CHANNEL] = [&val; K_NUM_INT_CHANNEL];
 
         let mut feature_value: *mut i32 = feature_vec;
         let int_img_ptr = self.int_img.as_ptr();
@@ -344,8 +351,8 @@ impl SurfMlpFeatureMap {
                 }
             }
             (0, _) => {
-                let mut tmp_cell_top_right: Vec<*const i32> =
-                    vec![&val; k_num_int_channel as usize];
+                let mut tmp_cell_top_right: [*const i32; K_NUM_INT_CHANNEL] =
+                    [&val; K_NUM_INT_CHANNEL];
 
                 offset = row_width * ((init_cell_y - 1) as isize) + cell_width - k_num_int_channel;
                 for i in 0..k_num_int_channel as usize {
@@ -374,8 +381,8 @@ impl SurfMlpFeatureMap {
                     .clone_from_slice(&tmp_cell_top_right[..k_num_int_channel as usize]);
             }
             (_, _) => {
-                let mut tmp_cell_top_right: Vec<*const i32> =
-                    vec![&val; k_num_int_channel as usize];
+                let mut tmp_cell_top_right: [*const i32; K_NUM_INT_CHANNEL] =
+                    [&val; K_NUM_INT_CHANNEL];
 
                 offset = row_width * ((init_cell_y - 1) as isize)
                     + (init_cell_x - 1) as isize * k_num_int_channel;
@@ -491,6 +498,11 @@ impl SurfMlpFeatureMap {
     pub fn get_feature_vector_dim(&self, feature_id: usize) -> usize {
         self.feature_pool.get_feature_vector_dim(feature_id)
     }
+
+    #[inline]
+    pub fn get_feature_count(&self) -> usize {
+        self.feature_pool.size()
+    }
 }
 
 struct FeaturePool {
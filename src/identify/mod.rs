@@ -0,0 +1,572 @@
+//! Face identification by comparing embeddings of faces.
+//!
+//! Similar to SeetaFace Identification, the face is cropped from the image, aligned by its
+//! landmarks if they are available, and passed through a convolutional network. The output
+//! of the network, normalized to unit length, is the embedding of the face: embeddings of the
+//! same person have a high cosine similarity.
+//!
+//! This is not a port of SeetaFace Identification. The model format supports a plain stack of
+//! convolution, ReLU, max pooling and fully connected layers and is specific to rustface,
+//! so VIPLFaceNet models (`seeta_fr_v1.0.bin`) can not be loaded.
+
+mod net;
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+
+use self::net::{Convolution, FullyConnected, Layer, Shape};
+use crate::alignment::Landmarks;
+use crate::common::{sample_bilinear, FaceInfo, ImageData};
+use crate::model::{ModelError, ModelErrorKind, ModelReader, MAX_LAYER_WEIGHTS};
+use crate::Error;
+
+/// Model for computing embeddings of faces.
+///
+/// The model is read from a little-endian binary stream in a format specific to rustface:
+///
+/// ```text
+/// i32 num_channels                   1 for gray-scale or 3 for RGB input
+/// i32 crop_size                      side of the aligned face crop in pixels
+/// f32 template[10]                   x and y of the five landmarks in the crop
+/// i32 num_layers
+/// num_layers times:
+///     i32 kind                       0 convolution, 1 ReLU, 2 max pooling, 3 fully connected
+///     convolution:
+///         i32 num_outputs, i32 kernel_size, i32 stride, i32 padding
+///         f32 weights[num_outputs * num_inputs * kernel_size * kernel_size]
+///         f32 biases[num_outputs]
+///     max pooling:
+///         i32 kernel_size, i32 stride
+///     fully connected:
+///         i32 num_outputs
+///         f32 weights[num_outputs * num_inputs]
+///         f32 biases[num_outputs]
+/// ```
+///
+/// The network reads the pixels of the crop in [0, 255], channel by channel. The output
+/// of the last layer is the (unnormalized) embedding.
+#[derive(Clone, Debug)]
+pub struct IdentificationModel {
+    num_channels: usize,
+    crop_size: u32,
+    template: [(f32, f32); 5],
+    layers: Vec<Layer>,
+    embedding_len: usize,
+}
+
+/// Embedding of a face, a feature vector of unit length.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Embedding {
+    values: Vec<f32>,
+}
+
+impl Embedding {
+    /// Create an embedding from a feature vector, e.g. one stored earlier,
+    /// normalizing it to unit length.
+    pub fn from_vec(mut values: Vec<f32>) -> Self {
+        let norm = values.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            for value in &mut values {
+                *value /= norm;
+            }
+        }
+        Embedding { values }
+    }
+
+    #[inline]
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// Cosine similarity to another embedding, in [-1, 1].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the embeddings have different lengths, i.e. were computed by different models.
+    #[inline]
+    pub fn similarity(&self, other: &Embedding) -> f32 {
+        cosine_similarity(&self.values, &other.values)
+    }
+}
+
+/// Cosine similarity of two vectors, 0 if one of them is zero.
+///
+/// # Panics
+///
+/// Panics if the vectors have different lengths.
+pub fn cosine_similarity(left: &[f32], right: &[f32]) -> f32 {
+    assert_eq!(
+        left.len(),
+        right.len(),
+        "Vectors of different lengths can not be compared"
+    );
+    let dot: f32 = left.iter().zip(right).map(|(l, r)| l * r).sum();
+    let norm_left = left.iter().map(|v| v * v).sum::<f32>().sqrt();
+    let norm_right = right.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm_left == 0.0 || norm_right == 0.0 {
+        return 0.0;
+    }
+    (dot / (norm_left * norm_right)).clamp(-1.0, 1.0)
+}
+
+/// Collection of labeled embeddings of known faces, searched by similarity.
+#[derive(Clone, Debug)]
+pub struct Gallery<T> {
+    entries: Vec<(T, Embedding)>,
+}
+
+impl<T> Default for Gallery<T> {
+    fn default() -> Self {
+        Gallery {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> Gallery<T> {
+    pub fn new() -> Self {
+        Gallery::default()
+    }
+
+    /// Add an embedding of a face with the given label. A label may have several embeddings.
+    pub fn add(&mut self, label: T, embedding: Embedding) {
+        self.entries.push((label, embedding));
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The `k` entries most similar to `query` with their similarities,
+    /// most similar first.
+    pub fn search(&self, query: &Embedding, k: usize) -> Vec<(&T, f32)> {
+        let mut matches: Vec<(&T, f32)> = self
+            .entries
+            .iter()
+            .map(|(label, embedding)| (label, query.similarity(embedding)))
+            .collect();
+        matches.sort_by(|x, y| y.1.partial_cmp(&x.1).unwrap_or(Ordering::Equal));
+        matches.truncate(k);
+        matches
+    }
+
+    /// The entry most similar to `query`, if its similarity is at least `min_similarity`.
+    pub fn best_match(&self, query: &Embedding, min_similarity: f32) -> Option<(&T, f32)> {
+        self.search(query, 1)
+            .into_iter()
+            .find(|&(_, similarity)| similarity >= min_similarity)
+    }
+}
+
+/// Similarity transform `(x, y) -> (a x - b y + tx, b x + a y + ty)`.
+#[derive(Copy, Clone, Debug)]
+struct Similarity {
+    a: f32,
+    b: f32,
+    tx: f32,
+    ty: f32,
+}
+
+impl Similarity {
+    /// Least squares estimate of the transform from `src` to `dst` points.
+    fn estimate(src: &[(f32, f32)], dst: &[(f32, f32)]) -> Self {
+        let n = src.len() as f32;
+        let mean = |points: &[(f32, f32)]| {
+            let (sx, sy) = points
+                .iter()
+                .fold((0.0, 0.0), |(sx, sy), &(x, y)| (sx + x, sy + y));
+            (sx / n, sy / n)
+        };
+        let (src_x, src_y) = mean(src);
+        let (dst_x, dst_y) = mean(dst);
+
+        let (mut dot, mut cross, mut norm) = (0.0, 0.0, 0.0);
+        for (&(px, py), &(qx, qy)) in src.iter().zip(dst) {
+            let (px, py) = (px - src_x, py - src_y);
+            let (qx, qy) = (qx - dst_x, qy - dst_y);
+            dot += px * qx + py * qy;
+            cross += px * qy - py * qx;
+            norm += px * px + py * py;
+        }
+        let (a, b) = if norm > 0.0 {
+            (dot / norm, cross / norm)
+        } else {
+            (1.0, 0.0)
+        };
+        Similarity {
+            a,
+            b,
+            tx: dst_x - (a * src_x - b * src_y),
+            ty: dst_y - (b * src_x + a * src_y),
+        }
+    }
+
+    #[inline]
+    fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            self.a * x - self.b * y + self.tx,
+            self.b * x + self.a * y + self.ty,
+        )
+    }
+}
+
+impl IdentificationModel {
+    /// Length of the embeddings computed by the model.
+    #[inline]
+    pub fn embedding_len(&self) -> usize {
+        self.embedding_len
+    }
+
+    /// Compute the embedding of a detected face.
+    ///
+    /// With the landmarks of a five-point alignment model, the crop is aligned by mapping
+    /// the landmarks onto the template of the model. Otherwise the crop covers the rotated
+    /// bounding box of the face, see [`FaceInfo::rotated_bbox`]. Parts of the crop outside of
+    /// the image repeat the edges of the image.
+    pub fn extract(
+        &self,
+        image: &ImageData,
+        face: &FaceInfo,
+        landmarks: Option<&Landmarks>,
+    ) -> Result<Embedding, Error> {
+        if image.width() == 0 || image.height() == 0 {
+            return Err(Error::IllegalImage {
+                width: image.width(),
+                height: image.height(),
+                num_channels: image.num_channels(),
+            });
+        }
+
+        let crop_size = self.crop_size as f32;
+        let transform = match landmarks {
+            Some(landmarks) if landmarks.points().len() == 5 => {
+                Similarity::estimate(&self.template, landmarks.points())
+            }
+            _ => {
+                let bbox = face.rotated_bbox();
+                let corners = bbox.corners();
+                Similarity::estimate(
+                    &[
+                        (0.0, 0.0),
+                        (crop_size, 0.0),
+                        (crop_size, crop_size),
+                        (0.0, crop_size),
+                    ],
+                    &corners,
+                )
+            }
+        };
+
+        let mut input = Vec::with_capacity(self.num_channels * (self.crop_size as usize).pow(2));
+        let mut buf = Vec::new();
+        for channel in 0..self.num_channels {
+            let plane = if self.num_channels == 1 {
+                image.to_gray(&mut buf)
+            } else {
+                image.channel(channel, &mut buf)
+            };
+            for v in 0..self.crop_size {
+                for u in 0..self.crop_size {
+                    let (x, y) = transform.apply(u as f32 + 0.5, v as f32 + 0.5);
+                    input.push(sample_bilinear(&plane, x, y));
+                }
+            }
+        }
+
+        let mut shape = Shape {
+            channels: self.num_channels,
+            height: self.crop_size as usize,
+            width: self.crop_size as usize,
+        };
+        let mut output = Vec::new();
+        for layer in &self.layers {
+            shape = layer.compute(&input, shape, &mut output);
+            std::mem::swap(&mut input, &mut output);
+        }
+
+        Ok(Embedding::from_vec(input))
+    }
+}
+
+/// Load identification model from a file.
+#[inline]
+pub fn load_identification_model(path: &str) -> Result<IdentificationModel, ModelError> {
+    let file = File::open(path).map_err(|e| ModelError::new(ModelErrorKind::Io(e), None))?;
+    read_identification_model(BufReader::new(file))
+}
+
+/// Load identification model from any stream or buffer.
+pub fn read_identification_model<R: io::Read>(buf: R) -> Result<IdentificationModel, ModelError> {
+    let mut reader = ModelReader::new(buf);
+    let invalid_structure =
+        |message: String| ModelError::new(ModelErrorKind::InvalidStructure(message), None);
+
+    let num_channels = reader.read_count("number of channels")?;
+    let crop_size = reader.read_count("crop size")?;
+    let mut template = [(0.0, 0.0); 5];
+    for point in &mut template {
+        *point = (reader.read_f32()?, reader.read_f32()?);
+    }
+    if num_channels != 1 && num_channels != 3 {
+        return Err(invalid_structure(format!(
+            "expected 1 or 3 channels, found {}",
+            num_channels
+        )));
+    }
+
+    let mut shape = Shape {
+        channels: num_channels,
+        height: crop_size,
+        width: crop_size,
+    };
+    let num_layers = reader.read_count("number of layers")?;
+    let mut layers = Vec::new();
+    for i in 0..num_layers {
+        let offset = reader.offset;
+        let layer = match reader.read_i32()? {
+            0 => {
+                let num_outputs = reader.read_count("number of outputs")?;
+                let kernel_size = reader.read_count("kernel size")?;
+                let stride = reader.read_count("stride")?;
+                let padding = reader.read_count("padding")?;
+                let num_weights = num_outputs
+                    .saturating_mul(shape.channels)
+                    .saturating_mul(kernel_size.saturating_mul(kernel_size));
+                Layer::Convolution(Convolution {
+                    num_outputs,
+                    kernel_size,
+                    stride,
+                    padding,
+                    weights: reader.read_f32s(
+                        "number of layer weights",
+                        num_weights,
+                        MAX_LAYER_WEIGHTS,
+                    )?,
+                    biases: reader.read_f32s("number of outputs", num_outputs, num_outputs)?,
+                })
+            }
+            1 => Layer::Relu,
+            2 => Layer::MaxPooling {
+                kernel_size: reader.read_count("kernel size")?,
+                stride: reader.read_count("stride")?,
+            },
+            3 => {
+                let num_outputs = reader.read_count("number of outputs")?;
+                Layer::FullyConnected(FullyConnected {
+                    num_outputs,
+                    weights: reader.read_f32s(
+                        "number of layer weights",
+                        num_outputs.saturating_mul(shape.len()),
+                        MAX_LAYER_WEIGHTS,
+                    )?,
+                    biases: reader.read_f32s("number of outputs", num_outputs, num_outputs)?,
+                })
+            }
+            kind => {
+                return Err(ModelError::new(
+                    ModelErrorKind::UnknownLayerKind(kind),
+                    Some(offset),
+                ))
+            }
+        };
+        shape = layer
+            .output_shape(shape)
+            .map_err(|message| invalid_structure(format!("layer {}: {}", i, message)))?;
+        layers.push(layer);
+    }
+    reader.read_end()?;
+
+    if shape.len() == 0 {
+        return Err(invalid_structure("the network has no outputs".to_string()));
+    }
+
+    Ok(IdentificationModel {
+        num_channels,
+        crop_size: crop_size as u32,
+        template,
+        layers,
+        embedding_len: shape.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cosine_similarity, read_identification_model, Embedding, Gallery, Similarity};
+    use crate::alignment::Landmarks;
+    use crate::common::{FaceInfo, ImageData, Rectangle};
+    use crate::model::ModelErrorKind;
+
+    /// A gray-scale model with an 8x8 crop: a 3x3 convolution with two outputs, ReLU,
+    /// 2x2 max pooling and a fully connected layer with four outputs.
+    fn model_data() -> Vec<u8> {
+        fn push_i32(data: &mut Vec<u8>, values: &[i32]) {
+            for value in values {
+                data.extend(value.to_le_bytes());
+            }
+        }
+        fn push_f32(data: &mut Vec<u8>, values: impl Iterator<Item = f32>) {
+            for value in values {
+                data.extend(value.to_le_bytes());
+            }
+        }
+
+        let mut data = Vec::new();
+        push_i32(&mut data, &[1, 8]);
+        push_f32(
+            &mut data,
+            [2.0, 3.0, 6.0, 3.0, 4.0, 5.0, 2.5, 6.5, 5.5, 6.5].into_iter(),
+        );
+        push_i32(&mut data, &[4]);
+        push_i32(&mut data, &[0, 2, 3, 1, 1]);
+        push_f32(&mut data, (0..18).map(|i| (i as f32).sin()));
+        push_f32(&mut data, [0.0, 0.1].into_iter());
+        push_i32(&mut data, &[1]);
+        push_i32(&mut data, &[2, 2, 2]);
+        push_i32(&mut data, &[3, 4]);
+        push_f32(&mut data, (0..4 * 32).map(|i| (i as f32 * 0.7).cos()));
+        push_f32(&mut data, [0.0; 4].into_iter());
+        data
+    }
+
+    fn gradient_image(width: u32, height: u32, horizontal: bool) -> Vec<u8> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = (i % width, i / width);
+                (if horizontal { x * 8 } else { y * 8 }) as u8
+            })
+            .collect()
+    }
+
+    fn face(bbox: Rectangle) -> FaceInfo {
+        let mut face = FaceInfo::new();
+        *face.bbox_mut() = bbox;
+        face
+    }
+
+    #[test]
+    fn test_extract() {
+        let model = read_identification_model(&model_data()[..]).unwrap();
+        assert_eq!(4, model.embedding_len());
+
+        let horizontal = gradient_image(32, 32, true);
+        let vertical = gradient_image(32, 32, false);
+        let face = face(Rectangle::new(4, 4, 16, 16));
+
+        let first = model
+            .extract(&ImageData::new(&horizontal, 32, 32), &face, None)
+            .unwrap();
+        let norm = first.values().iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-5);
+        let again = model
+            .extract(&ImageData::new(&horizontal, 32, 32), &face, None)
+            .unwrap();
+        assert!((first.similarity(&again) - 1.0).abs() < 1e-5);
+
+        let other = model
+            .extract(&ImageData::new(&vertical, 32, 32), &face, None)
+            .unwrap();
+        assert!(first.similarity(&other) < 0.99);
+    }
+
+    #[test]
+    fn test_extract_with_landmarks() {
+        let model = read_identification_model(&model_data()[..]).unwrap();
+        let image = gradient_image(32, 32, true);
+        let image = ImageData::new(&image, 32, 32);
+        let face = face(Rectangle::new(4, 4, 16, 16));
+
+        // the template scaled by 2 and shifted by (4, 4) covers the same crop as the face box
+        let template = [(2.0, 3.0), (6.0, 3.0), (4.0, 5.0), (2.5, 6.5), (5.5, 6.5)];
+        let points = template
+            .iter()
+            .map(|&(x, y)| (2.0 * x + 4.0, 2.0 * y + 4.0));
+        let landmarks = Landmarks::new(points.collect());
+
+        let from_box = model.extract(&image, &face, None).unwrap();
+        let from_landmarks = model.extract(&image, &face, Some(&landmarks)).unwrap();
+        assert!((from_box.similarity(&from_landmarks) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_read_model_with_unknown_layer() {
+        let mut data = model_data();
+        // the kind of the first layer follows the header of 13 values
+        data[4 * 13] = 7;
+        let err = read_identification_model(&data[..]).err().unwrap();
+        assert!(matches!(err.kind(), ModelErrorKind::UnknownLayerKind(7)));
+        assert_eq!(Some(4 * 13), err.offset());
+    }
+
+    #[test]
+    fn test_read_truncated_model() {
+        let data = model_data();
+        let err = read_identification_model(&data[..data.len() - 4])
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind(), ModelErrorKind::Truncated));
+    }
+
+    #[test]
+    fn test_read_model_with_empty_convolution() {
+        let mut data = model_data();
+        // a convolution without outputs, followed by a fully connected layer without weights
+        data.truncate(4 * 12);
+        for value in [2, 0, 0, 3, 1, 1, 3, 4, 0, 0, 0, 0] {
+            data.extend(i32::to_le_bytes(value));
+        }
+        let err = read_identification_model(&data[..]).err().unwrap();
+        match err.kind() {
+            ModelErrorKind::InvalidStructure(message) => {
+                assert!(message.contains("no outputs"), "{}", message)
+            }
+            kind => panic!("Unexpected error kind: {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_similarity_estimate() {
+        let src = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)];
+        // rotation by 90 degrees, scaling by 2 and translation by (3, 4)
+        let dst = [(3.0, 4.0), (3.0, 6.0), (1.0, 4.0)];
+        let transform = Similarity::estimate(&src, &dst);
+        for (&(x, y), &expected) in src.iter().zip(&dst) {
+            let (tx, ty) = transform.apply(x, y);
+            assert!((tx - expected.0).abs() < 1e-5 && (ty - expected.1).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert_eq!(1.0, cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]));
+        assert_eq!(0.0, cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]));
+        assert_eq!(-1.0, cosine_similarity(&[1.0, 1.0], &[-1.0, -1.0]));
+        assert_eq!(0.0, cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]));
+    }
+
+    #[test]
+    fn test_gallery_search() {
+        let mut gallery = Gallery::new();
+        gallery.add("a", Embedding::from_vec(vec![1.0, 0.0]));
+        gallery.add("b", Embedding::from_vec(vec![0.0, 1.0]));
+        gallery.add("c", Embedding::from_vec(vec![1.0, 1.0]));
+        assert_eq!(3, gallery.len());
+
+        let query = Embedding::from_vec(vec![1.0, 0.2]);
+        let labels: Vec<&str> = gallery.search(&query, 2).iter().map(|m| *m.0).collect();
+        assert_eq!(vec!["a", "c"], labels);
+        assert_eq!(Some(&"a"), gallery.best_match(&query, 0.9).map(|m| m.0));
+        assert_eq!(
+            None,
+            gallery.best_match(&Embedding::from_vec(vec![-1.0, -1.0]), 0.0)
+        );
+    }
+}
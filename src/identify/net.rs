@@ -0,0 +1,267 @@
+use crate::math;
+
+/// Shape of a tensor of `channels` planes of `height` rows and `width` columns,
+/// stored plane by plane and row by row.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) struct Shape {
+    pub channels: usize,
+    pub height: usize,
+    pub width: usize,
+}
+
+impl Shape {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.channels * self.height * self.width
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Convolution {
+    pub num_outputs: usize,
+    pub kernel_size: usize,
+    pub stride: usize,
+    pub padding: usize,
+    /// Weights of each output channel, ordered by input channel, kernel row and kernel column.
+    pub weights: Vec<f32>,
+    pub biases: Vec<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct FullyConnected {
+    pub num_outputs: usize,
+    pub weights: Vec<f32>,
+    pub biases: Vec<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum Layer {
+    Convolution(Convolution),
+    Relu,
+    MaxPooling { kernel_size: usize, stride: usize },
+    FullyConnected(FullyConnected),
+}
+
+impl Layer {
+    /// Shape of the output for an input of the given shape, or an error message,
+    /// if the layer can not process such an input.
+    pub fn output_shape(&self, input: Shape) -> Result<Shape, String> {
+        match *self {
+            Layer::Convolution(ref conv) => {
+                if conv.kernel_size == 0 || conv.stride == 0 {
+                    return Err("convolution has a zero kernel size or stride".to_string());
+                }
+                if conv.num_outputs == 0 {
+                    return Err("convolution has no outputs".to_string());
+                }
+                let expected = conv.num_outputs * input.channels * conv.kernel_size.pow(2);
+                if conv.weights.len() != expected || conv.biases.len() != conv.num_outputs {
+                    return Err(format!(
+                        "convolution has {} weights, expected {}",
+                        conv.weights.len(),
+                        expected
+                    ));
+                }
+                let height = input.height + 2 * conv.padding;
+                let width = input.width + 2 * conv.padding;
+                if height < conv.kernel_size || width < conv.kernel_size {
+                    return Err(format!(
+                        "convolution kernel of size {} exceeds the padded {}x{} input",
+                        conv.kernel_size, width, height
+                    ));
+                }
+                Ok(Shape {
+                    channels: conv.num_outputs,
+                    height: (height - conv.kernel_size) / conv.stride + 1,
+                    width: (width - conv.kernel_size) / conv.stride + 1,
+                })
+            }
+            Layer::Relu => Ok(input),
+            Layer::MaxPooling {
+                kernel_size,
+                stride,
+            } => {
+                if kernel_size == 0 || stride == 0 {
+                    return Err("max pooling has a zero kernel size or stride".to_string());
+                }
+                if input.height < kernel_size || input.width < kernel_size {
+                    return Err(format!(
+                        "max pooling kernel of size {} exceeds the {}x{} input",
+                        kernel_size, input.width, input.height
+                    ));
+                }
+                Ok(Shape {
+                    channels: input.channels,
+                    height: (input.height - kernel_size) / stride + 1,
+                    width: (input.width - kernel_size) / stride + 1,
+                })
+            }
+            Layer::FullyConnected(ref fc) => {
+                if fc.num_outputs == 0 {
+                    return Err("fully connected layer has no outputs".to_string());
+                }
+                let expected = fc.num_outputs * input.len();
+                if fc.weights.len() != expected || fc.biases.len() != fc.num_outputs {
+                    return Err(format!(
+                        "fully connected layer has {} weights, expected {}",
+                        fc.weights.len(),
+                        expected
+                    ));
+                }
+                Ok(Shape {
+                    channels: fc.num_outputs,
+                    height: 1,
+                    width: 1,
+                })
+            }
+        }
+    }
+
+    /// Compute the output of the layer into `output`. The shapes must have been
+    /// checked with [`Layer::output_shape`].
+    pub fn compute(&self, input: &[f32], shape: Shape, output: &mut Vec<f32>) -> Shape {
+        let out_shape = self
+            .output_shape(shape)
+            .expect("Layer shapes are checked when the model is read");
+        output.clear();
+        output.resize(out_shape.len(), 0.0);
+
+        match *self {
+            Layer::Convolution(ref conv) => {
+                let kernel_len = shape.channels * conv.kernel_size * conv.kernel_size;
+                for (o, out_plane) in output
+                    .chunks_exact_mut(out_shape.height * out_shape.width)
+                    .enumerate()
+                {
+                    let weights = &conv.weights[o * kernel_len..(o + 1) * kernel_len];
+                    for oy in 0..out_shape.height {
+                        for ox in 0..out_shape.width {
+                            let mut sum = conv.biases[o];
+                            for c in 0..shape.channels {
+                                let plane = &input[c * shape.height * shape.width..];
+                                for ky in 0..conv.kernel_size {
+                                    let y =
+                                        (oy * conv.stride + ky) as isize - conv.padding as isize;
+                                    if y < 0 || y >= shape.height as isize {
+                                        continue;
+                                    }
+                                    let row = &plane[y as usize * shape.width..];
+                                    let w_row = &weights[(c * conv.kernel_size + ky)
+                                        * conv.kernel_size
+                                        ..(c * conv.kernel_size + ky + 1) * conv.kernel_size];
+                                    for (kx, w) in w_row.iter().enumerate() {
+                                        let x = (ox * conv.stride + kx) as isize
+                                            - conv.padding as isize;
+                                        if x >= 0 && x < shape.width as isize {
+                                            sum += w * row[x as usize];
+                                        }
+                                    }
+                                }
+                            }
+                            out_plane[oy * out_shape.width + ox] = sum;
+                        }
+                    }
+                }
+            }
+            Layer::Relu => {
+                for (out, &value) in output.iter_mut().zip(input) {
+                    *out = value.max(0.0);
+                }
+            }
+            Layer::MaxPooling {
+                kernel_size,
+                stride,
+            } => {
+                for c in 0..shape.channels {
+                    let plane = &input[c * shape.height * shape.width..];
+                    for oy in 0..out_shape.height {
+                        for ox in 0..out_shape.width {
+                            let mut max = f32::NEG_INFINITY;
+                            for ky in 0..kernel_size {
+                                let row = &plane[(oy * stride + ky) * shape.width..];
+                                for kx in 0..kernel_size {
+                                    max = max.max(row[ox * stride + kx]);
+                                }
+                            }
+                            output[(c * out_shape.height + oy) * out_shape.width + ox] = max;
+                        }
+                    }
+                }
+            }
+            Layer::FullyConnected(ref fc) => {
+                for ((out, weights), bias) in output
+                    .iter_mut()
+                    .zip(fc.weights.chunks_exact(shape.len()))
+                    .zip(&fc.biases)
+                {
+                    *out = math::vector_inner_product(weights, input) + bias;
+                }
+            }
+        }
+
+        out_shape
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Convolution, FullyConnected, Layer, Shape};
+
+    fn shape(channels: usize, height: usize, width: usize) -> Shape {
+        Shape {
+            channels,
+            height,
+            width,
+        }
+    }
+
+    #[test]
+    fn test_convolution() {
+        // sum of the 3x3 neighborhood, zero padded
+        let conv = Layer::Convolution(Convolution {
+            num_outputs: 1,
+            kernel_size: 3,
+            stride: 1,
+            padding: 1,
+            weights: vec![1.0; 9],
+            biases: vec![0.5],
+        });
+        let input = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let mut output = Vec::new();
+        let out_shape = conv.compute(&input, shape(1, 2, 3), &mut output);
+        assert_eq!(shape(1, 2, 3), out_shape);
+        assert_eq!(vec![12.5, 21.5, 16.5, 12.5, 21.5, 16.5], output);
+    }
+
+    #[test]
+    fn test_max_pooling_and_relu() {
+        let input = [-1.0, -2.0, -3.0, -4.0, 5.0, -6.0, -7.0, -8.0];
+        let mut output = Vec::new();
+        let pool = Layer::MaxPooling {
+            kernel_size: 2,
+            stride: 2,
+        };
+        let out_shape = pool.compute(&input, shape(2, 2, 2), &mut output);
+        assert_eq!(shape(2, 1, 1), out_shape);
+        assert_eq!(vec![-1.0, 5.0], output);
+
+        let mut relu_output = Vec::new();
+        Layer::Relu.compute(&output, out_shape, &mut relu_output);
+        assert_eq!(vec![0.0, 5.0], relu_output);
+    }
+
+    #[test]
+    fn test_fully_connected_checks_weights() {
+        let fc = Layer::FullyConnected(FullyConnected {
+            num_outputs: 2,
+            weights: vec![1.0, 0.0, 0.0, 1.0],
+            biases: vec![0.0, 1.0],
+        });
+        assert!(fc.output_shape(shape(1, 1, 2)).is_ok());
+        assert!(fc.output_shape(shape(1, 1, 3)).is_err());
+
+        let mut output = Vec::new();
+        fc.compute(&[3.0, 4.0], shape(2, 1, 1), &mut output);
+        assert_eq!(vec![3.0, 5.0], output);
+    }
+}
@@ -16,54 +16,253 @@
 // You should have received a copy of the BSD 2-Clause License along with the software.
 // If not, see < https://opensource.org/licenses/BSD-2-Clause>.
 
+pub mod alignment;
+pub mod calibration;
 mod classifier;
 mod common;
 mod detector;
+mod error;
 mod feat;
+pub mod identify;
 pub mod math;
 pub mod model;
+mod tracker;
 
+pub use crate::alignment::{load_alignment_model, read_alignment_model, AlignmentModel, Landmarks};
+pub use crate::calibration::{load_calibration, read_calibration, Calibration, CalibrationError};
 pub use crate::common::FaceInfo;
 pub use crate::common::ImageData;
+pub use crate::common::PixelFormat;
+pub use crate::common::Provenance;
+pub use crate::common::PyramidMode;
 pub use crate::common::Rectangle;
-pub use crate::model::{load_model, read_model, Model};
+pub use crate::common::RotatedRectangle;
+pub use crate::common::YuvFormat;
+pub use crate::error::Error;
+pub use crate::identify::{
+    load_identification_model, read_identification_model, Embedding, Gallery, IdentificationModel,
+};
+pub use crate::model::{load_model, read_model, Model, ModelError};
+pub use crate::tracker::{FaceTracker, Track, TrackEvent, TrackerConfig, TrackerConfigBuilder};
+
+pub use crate::detector::{
+    DetectorConfig, DetectorConfigBuilder, DetectorWorkspace, NmsStrategy, StreamingConfig,
+    StreamingConfigBuilder, StreamingDetector,
+};
 
 use crate::detector::FuStDetector;
-use std::io;
+use std::sync::Arc;
 
 /// Create a face detector, based on a file with model description.
-pub fn create_detector(path_to_model: &str) -> Result<Box<dyn Detector>, io::Error> {
+pub fn create_detector(path_to_model: &str) -> Result<Box<dyn Detector>, ModelError> {
     let model = load_model(path_to_model)?;
     Ok(create_detector_with_model(model))
 }
 
 /// Create a face detector, based on the provided model.
-pub fn create_detector_with_model(model: Model) -> Box<dyn Detector> {
-    Box::new(FuStDetector::new(model))
+///
+/// The model can be passed either by value or as an `Arc<Model>`,
+/// in which case it is shared with other detectors instead of being copied.
+pub fn create_detector_with_model(model: impl Into<Arc<Model>>) -> Box<dyn Detector> {
+    Box::new(FuStDetector::new(model.into()))
+}
+
+/// Create a face detector, based on the provided model and configuration.
+///
+/// Returns an error if the configuration is not valid, see [`DetectorConfig::validate`].
+pub fn create_detector_with_config(
+    model: impl Into<Arc<Model>>,
+    config: DetectorConfig,
+) -> Result<Box<dyn Detector>, Error> {
+    config.validate()?;
+    Ok(Box::new(FuStDetector::with_config(model.into(), config)))
 }
 
-pub trait Detector {
+/// Face detector.
+///
+/// Detectors are `Send` and `Sync`: a single detector can be shared between threads,
+/// each of them calling [`detect_with_workspace`](Detector::detect_with_workspace)
+/// with a [`DetectorWorkspace`] of its own.
+pub trait Detector: Send + Sync {
     /// Detect faces on input image.
     ///
-    /// (1) The input image should be gray-scale, i.e. `num_channels` set to 1.
-    /// (2) Currently this function does not give the Euler angles, which are
-    ///     left with invalid values.
+    /// (1) Color images are converted to gray-scale internally, see [`ImageData::to_gray`].
+    /// (2) The pose of the faces is only estimated coarsely: each face has a yaw bin, see
+    ///     [`FaceInfo::yaw_bin`], and the yaw angle is available if the model provides
+    ///     the views of its branches, see [`FaceInfo::yaw`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `image` is not a legal image, i.e. it has `width` or `height` equal to 0.
+    ///
+    /// See [`try_detect`](Detector::try_detect) for a non-panicking version.
+    fn detect(&mut self, image: &ImageData) -> Vec<FaceInfo> {
+        self.try_detect(image).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Detect faces on input image.
+    ///
+    /// Same as [`detect`](Detector::detect), but returns
+    /// [`Error::IllegalImage`] instead of panicking if `image` is not a legal image.
+    fn try_detect(&mut self, image: &ImageData) -> Result<Vec<FaceInfo>, Error>;
+
+    /// Detect faces on input image, using `workspace` for intermediate buffers.
+    ///
+    /// Same as [`detect`](Detector::detect), but does not modify the detector,
+    /// so it can be called concurrently from multiple threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `image` is not a legal image, i.e. it has `width` or `height` equal to 0.
+    fn detect_with_workspace(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+    ) -> Vec<FaceInfo> {
+        self.try_detect_with_workspace(workspace, image)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Detect faces on input image, using `workspace` for intermediate buffers.
+    ///
+    /// Same as [`detect_with_workspace`](Detector::detect_with_workspace), but returns
+    /// [`Error::IllegalImage`] instead of panicking if `image` is not a legal image.
+    fn try_detect_with_workspace(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+    ) -> Result<Vec<FaceInfo>, Error>;
+
+    /// Detect faces in the given regions of the input image.
+    ///
+    /// Only the regions, expanded by the margin (see [`set_region_margin`](Detector::set_region_margin)),
+    /// are scanned for faces, so this is much faster than [`detect`](Detector::detect) if the
+    /// regions are small compared to the image. Parts of regions outside of the image are ignored.
+    /// Overlapping regions are merged into their bounding rectangle, unless it is much larger
+    /// than the regions. Otherwise each region is scanned on its own and a face found in several
+    /// regions is reported once, with its best score. The returned faces are in the coordinates
+    /// of the full image.
     ///
     /// # Panics
     ///
-    /// Panics if `image` is not a legal image, e.g. it
-    /// - is not gray-scale (`num_channels` is not equal to 1)
-    /// - has `width` or `height` equal to 0
-    fn detect(&mut self, image: &ImageData) -> Vec<FaceInfo>;
+    /// Panics if `image` is not a legal image, i.e. it has `width` or `height` equal to 0.
+    fn detect_in_regions(&mut self, image: &ImageData, regions: &[Rectangle]) -> Vec<FaceInfo> {
+        self.try_detect_in_regions(image, regions)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Detect faces in the given regions of the input image.
+    ///
+    /// Same as [`detect_in_regions`](Detector::detect_in_regions), but returns
+    /// [`Error::IllegalImage`] instead of panicking if `image` is not a legal image.
+    fn try_detect_in_regions(
+        &mut self,
+        image: &ImageData,
+        regions: &[Rectangle],
+    ) -> Result<Vec<FaceInfo>, Error>;
+
+    /// Detect faces in the given regions of the input image, using `workspace` for
+    /// intermediate buffers.
+    ///
+    /// Same as [`try_detect_in_regions`](Detector::try_detect_in_regions), but does not
+    /// modify the detector, so it can be called concurrently from multiple threads.
+    fn try_detect_in_regions_with_workspace(
+        &self,
+        workspace: &mut DetectorWorkspace,
+        image: &ImageData,
+        regions: &[Rectangle],
+    ) -> Result<Vec<FaceInfo>, Error>;
+
+    /// Detect faces on each of the input images.
+    ///
+    /// When the `rayon` feature is enabled, images are processed in parallel
+    /// in the current Rayon thread pool. Otherwise images are processed sequentially.
+    /// The results are returned in the same order as the input images.
+    ///
+    /// Each thread uses a [`DetectorWorkspace`] of its own, which it keeps between batches
+    /// in thread-local storage. The buffers of a workspace grow to the largest image
+    /// processed by the thread and are only released when the thread exits, so the threads
+    /// of the global Rayon pool, or the calling thread without the `rayon` feature, retain
+    /// them for the lifetime of the process. To release them, run the batches in a pool of
+    /// your own with `try_detect_batch_in_pool` and
+    /// drop the pool, or use [`detect_with_workspace`](Detector::detect_with_workspace)
+    /// with workspaces you own.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the images is not a legal image, i.e. it has `width` or `height` equal to 0.
+    fn detect_batch(&self, images: &[ImageData]) -> Vec<Vec<FaceInfo>> {
+        self.try_detect_batch(images)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Detect faces on each of the input images.
+    ///
+    /// Same as [`detect_batch`](Detector::detect_batch), but returns
+    /// [`Error::IllegalImage`] instead of panicking if any of the images is not a legal image.
+    fn try_detect_batch(&self, images: &[ImageData]) -> Result<Vec<Vec<FaceInfo>>, Error> {
+        detector::detect_batch(self, images)
+    }
+
+    /// Detect faces on each of the input images, using the threads of `pool`.
+    ///
+    /// Same as [`try_detect_batch`](Detector::try_detect_batch), but runs in the given
+    /// Rayon thread pool, e.g. to limit the number of threads. The pool should be kept
+    /// between batches, so that its threads re-use their workspaces. Dropping the pool
+    /// releases the workspaces of its threads, once they have exited.
+    #[cfg(feature = "rayon")]
+    fn try_detect_batch_in_pool(
+        &self,
+        images: &[ImageData],
+        pool: &rayon::ThreadPool,
+    ) -> Result<Vec<Vec<FaceInfo>>, Error> {
+        detector::detect_batch_in_pool(self, images, pool)
+    }
+
+    /// Current configuration of the detector.
+    ///
+    /// The configuration reflects all changes made with the setters.
+    fn config(&self) -> &DetectorConfig;
+
+    /// Replace the configuration of the detector.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configuration is not valid, see [`DetectorConfig::validate`].
+    fn set_config(&mut self, config: DetectorConfig) {
+        self.try_set_config(config)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Replace the configuration of the detector.
+    ///
+    /// Returns an error and keeps the current configuration, if `config` is not valid,
+    /// see [`DetectorConfig::validate`].
+    fn try_set_config(&mut self, config: DetectorConfig) -> Result<(), Error>;
 
     /// Set the size of the sliding window.
     ///
-    /// The minimum size is constrained as no smaller than 20.
+    /// This has no effect: detection always uses the 40x40 window, which the cascade
+    /// classifiers of the model are trained for. Use [`set_min_face_size`](Detector::set_min_face_size)
+    /// and [`set_max_face_size`](Detector::set_max_face_size) to choose the sizes of faces to find.
     ///
     /// # Panics
     ///
     /// Panics if `wnd_size` is less than 20.
-    fn set_window_size(&mut self, wnd_size: u32);
+    #[deprecated(note = "the window size is fixed to 40, use `set_min_face_size` instead")]
+    fn set_window_size(&mut self, wnd_size: u32) {
+        #[allow(deprecated)]
+        self.try_set_window_size(wnd_size)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Set the size of the sliding window.
+    ///
+    /// This has no effect, see [`set_window_size`](Detector::set_window_size).
+    ///
+    /// Returns [`Error::IllegalWindowSize`] if `wnd_size` is less than 20.
+    #[deprecated(note = "the window size is fixed to 40, use `try_set_min_face_size` instead")]
+    fn try_set_window_size(&mut self, wnd_size: u32) -> Result<(), Error>;
 
     /// Set the sliding window step in horizontal and vertical directions.
     ///
@@ -72,17 +271,35 @@ pub trait Detector {
     ///
     /// # Panics
     ///
-    /// Panics if `step_x` or `step_y` is less than or equal to 0.
-    fn set_slide_window_step(&mut self, step_x: u32, step_y: u32);
+    /// Panics if `step_x` or `step_y` is equal to 0.
+    fn set_slide_window_step(&mut self, step_x: u32, step_y: u32) {
+        self.try_set_slide_window_step(step_x, step_y)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Set the sliding window step in horizontal and vertical directions.
+    ///
+    /// Returns [`Error::IllegalSlideWindowStep`] if `step_x` or `step_y` is equal to 0.
+    fn try_set_slide_window_step(&mut self, step_x: u32, step_y: u32) -> Result<(), Error>;
 
     /// Set the minimum size of faces to detect.
     ///
-    /// The minimum size is constrained as no smaller than 20.
+    /// The minimum size is constrained as no smaller than 20. The default is 40, the size of
+    /// the sliding window. Smaller faces are found by upsampling the image, so detection
+    /// takes considerably longer (several times as long for a minimum size of 20).
     ///
     /// # Panics
     ///
     /// Panics if `min_face_size` is less than 20.
-    fn set_min_face_size(&mut self, min_face_size: u32);
+    fn set_min_face_size(&mut self, min_face_size: u32) {
+        self.try_set_min_face_size(min_face_size)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Set the minimum size of faces to detect.
+    ///
+    /// Returns [`Error::IllegalMinFaceSize`] if `min_face_size` is less than 20.
+    fn try_set_min_face_size(&mut self, min_face_size: u32) -> Result<(), Error>;
 
     /// Set the maximum size of faces to detect.
     ///
@@ -90,6 +307,13 @@ pub trait Detector {
     /// user specified size, image width, image height.
     fn set_max_face_size(&mut self, max_face_size: u32);
 
+    /// Set the margin in pixels, by which regions are expanded on each side in
+    /// [`detect_in_regions`](Detector::detect_in_regions).
+    ///
+    /// A margin allows to find faces, which are only partially covered by a region.
+    /// The default margin is 0.
+    fn set_region_margin(&mut self, margin: u32);
+
     /// Set the factor between adjacent scales of image pyramid.
     ///
     /// The value of the factor lies in (0.1, 0.99). For example, when it is set as 0.5,
@@ -98,7 +322,16 @@ pub trait Detector {
     /// # Panics
     ///
     /// Panics if `scale_factor` is less than 0.01 or greater than 0.99
-    fn set_pyramid_scale_factor(&mut self, scale_factor: f32);
+    fn set_pyramid_scale_factor(&mut self, scale_factor: f32) {
+        self.try_set_pyramid_scale_factor(scale_factor)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Set the factor between adjacent scales of image pyramid.
+    ///
+    /// Returns [`Error::IllegalScaleFactor`] if `scale_factor` is less than 0.01
+    /// or greater than 0.99.
+    fn try_set_pyramid_scale_factor(&mut self, scale_factor: f32) -> Result<(), Error>;
 
     /// Set the score threshold of detected faces.
     ///
@@ -112,5 +345,13 @@ pub trait Detector {
     /// # Panics
     ///
     /// Panics if `thresh` is less than or equal to 0.
-    fn set_score_thresh(&mut self, thresh: f64);
+    fn set_score_thresh(&mut self, thresh: f64) {
+        self.try_set_score_thresh(thresh)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Set the score threshold of detected faces.
+    ///
+    /// Returns [`Error::IllegalScoreThreshold`] if `thresh` is less than or equal to 0.
+    fn try_set_score_thresh(&mut self, thresh: f64) -> Result<(), Error>;
 }
@@ -30,6 +30,9 @@ pub fn square(src: &[i32], dest: &mut [u32]) {
     }
 }
 
+/// # Safety
+///
+/// `src` and `dest` must be valid for reading and writing `length` elements respectively.
 pub unsafe fn abs(src: *const i32, dest: *mut i32, length: usize) {
     for i in 0..length as isize {
         let value = *src.offset(i);
@@ -37,12 +40,20 @@ pub unsafe fn abs(src: *const i32, dest: *mut i32, length: usize) {
     }
 }
 
+/// # Safety
+///
+/// `left` and `right` must be valid for reading and `dest` must be valid for writing
+/// `length` elements.
 pub unsafe fn vector_add(left: *const i32, right: *const i32, dest: *mut i32, length: usize) {
     for i in 0..length as isize {
         *dest.offset(i) = *left.offset(i) + *right.offset(i);
     }
 }
 
+/// # Safety
+///
+/// `left` and `right` must be valid for reading and `dest` must be valid for writing
+/// `length` elements.
 pub unsafe fn vector_sub(left: *const i32, right: *const i32, dest: *mut i32, length: usize) {
     for i in 0..length as isize {
         *dest.offset(i) = *left.offset(i) - *right.offset(i);
@@ -16,11 +16,16 @@
 // You should have received a copy of the BSD 2-Clause License along with the software.
 // If not, see < https://opensource.org/licenses/BSD-2-Clause>.
 
+use std::cmp;
+use std::error;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::BufReader;
 
+use crate::calibration::Calibration;
 use crate::classifier::{Classifier, ClassifierKind, LabBoostedClassifier, SurfMlpClassifier};
+use crate::feat::SurfMlpFeatureMap;
 use byteorder::{LittleEndian, ReadBytesExt};
 
 #[derive(Clone)]
@@ -29,6 +34,8 @@ pub struct Model {
     wnd_src_id: Vec<Vec<i32>>,
     hierarchy_sizes: Vec<i32>,
     num_stages: Vec<i32>,
+    calibration: Option<Calibration>,
+    branch_yaws: Option<Vec<f32>>,
 }
 
 impl Model {
@@ -56,83 +63,355 @@ impl Model {
     pub fn get_hierarchy_size(&self, hierarchy_index: usize) -> i32 {
         self.hierarchy_sizes[hierarchy_index]
     }
+
+    /// Attach a calibration, which detectors use to compute
+    /// [`FaceInfo::confidence`](crate::FaceInfo::confidence) of detections.
+    ///
+    /// The calibration is only meaningful for the detector configuration it was fitted with.
+    pub fn with_calibration(mut self, calibration: Calibration) -> Self {
+        self.calibration = Some(calibration);
+        self
+    }
+
+    /// The calibration attached to the model, if any.
+    #[inline]
+    pub fn calibration(&self) -> Option<&Calibration> {
+        self.calibration.as_ref()
+    }
+
+    /// Attach the yaw angles (in degrees) of the views, which the branches of the
+    /// first hierarchy were trained on, so that detectors can report a coarse
+    /// [`FaceInfo::yaw`](crate::FaceInfo::yaw) of detections.
+    ///
+    /// The views depend on how the model was trained, so they are not part of the model file.
+    /// Returns an error, if the number of angles does not match the number of branches.
+    pub fn with_branch_yaws(mut self, yaws: Vec<f32>) -> Result<Self, ModelError> {
+        let num_branches = self
+            .hierarchy_sizes
+            .first()
+            .map_or(0, |&size| size as usize);
+        if yaws.len() != num_branches {
+            let message = format!(
+                "expected {} branch yaw angles, found {}",
+                num_branches,
+                yaws.len()
+            );
+            return Err(ModelError::new(
+                ModelErrorKind::InvalidStructure(message),
+                None,
+            ));
+        }
+        self.branch_yaws = Some(yaws);
+        Ok(self)
+    }
+
+    /// The yaw angle (in degrees) of the view of a branch of the first hierarchy,
+    /// if known.
+    #[inline]
+    pub fn branch_yaw(&self, branch: usize) -> Option<f32> {
+        self.branch_yaws.as_ref().map(|yaws| yaws[branch])
+    }
+
+    /// Check that the cascade structure of the model is consistent,
+    /// so that it can be used for detection.
+    ///
+    /// This is done automatically by [`load_model`] and [`read_model`].
+    pub fn validate(&self) -> Result<(), ModelError> {
+        self.validate_structure()
+            .map_err(|message| ModelError::new(ModelErrorKind::InvalidStructure(message), None))
+    }
+
+    fn validate_structure(&self) -> Result<(), String> {
+        let first_hierarchy_size = match self.hierarchy_sizes.first() {
+            Some(&size) if size > 0 => size,
+            _ => return Err("first hierarchy is empty".to_string()),
+        };
+
+        let num_cascades = self.hierarchy_sizes.iter().sum::<i32>() as usize;
+        if self.num_stages.len() != num_cascades || self.wnd_src_id.len() != num_cascades {
+            return Err(format!(
+                "expected {} cascades, found {} stage counts and {} window source lists",
+                num_cascades,
+                self.num_stages.len(),
+                self.wnd_src_id.len()
+            ));
+        }
+
+        let total_num_stages = self.num_stages.iter().sum::<i32>() as usize;
+        if self.classifiers.len() != total_num_stages {
+            return Err(format!(
+                "number of classifiers ({}) does not match the total number of stages ({})",
+                self.classifiers.len(),
+                total_num_stages
+            ));
+        }
+
+        let surf_mlp_feature_map = SurfMlpFeatureMap::new();
+        let mut cls_idx = 0;
+        let mut model_idx = 0;
+
+        for (i, &hierarchy_size) in self.hierarchy_sizes.iter().enumerate() {
+            // proposals of all hierarchies are kept in buffers sized by the first hierarchy
+            if hierarchy_size > first_hierarchy_size {
+                return Err(format!(
+                    "hierarchy {} has {} cascades, which is more than in the first hierarchy ({})",
+                    i, hierarchy_size, first_hierarchy_size
+                ));
+            }
+
+            for _ in 0..hierarchy_size {
+                let num_stage = self.num_stages[cls_idx] as usize;
+                let wnd_src = &self.wnd_src_id[cls_idx];
+
+                if i == 0 {
+                    if num_stage != 1 {
+                        return Err(format!(
+                            "cascade {} of the first hierarchy has {} stages, expected 1",
+                            cls_idx, num_stage
+                        ));
+                    }
+                    if !wnd_src.is_empty() {
+                        return Err(format!(
+                            "cascade {} of the first hierarchy has window sources",
+                            cls_idx
+                        ));
+                    }
+                } else {
+                    if wnd_src.is_empty() {
+                        return Err(format!("cascade {} has no window sources", cls_idx));
+                    }
+                    let num_sources = self.hierarchy_sizes[i - 1];
+                    if let Some(&index) = wnd_src.iter().find(|&&k| k < 0 || k >= num_sources) {
+                        return Err(format!(
+                            "cascade {} refers to window source {}, but the previous hierarchy has {} cascades",
+                            cls_idx, index, num_sources
+                        ));
+                    }
+                }
+
+                for classifier in &self.classifiers[model_idx..model_idx + num_stage] {
+                    match (i, classifier) {
+                        (0, Classifier::LabBoosted(c)) => c.validate(),
+                        (0, _) => {
+                            Err("first hierarchy must use LAB boosted classifiers".to_string())
+                        }
+                        (_, Classifier::SurfMlp(c)) => c.validate(&surf_mlp_feature_map),
+                        (_, _) => Err(format!("hierarchy {} must use SURF MLP classifiers", i)),
+                    }
+                    .map_err(|message| format!("classifier {}: {}", model_idx, message))?;
+                    model_idx += 1;
+                }
+
+                cls_idx += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Error that occurred while loading a model.
+#[derive(Debug)]
+pub struct ModelError {
+    kind: ModelErrorKind,
+    offset: Option<u64>,
+}
+
+/// The reason why a model could not be loaded.
+#[derive(Debug)]
+pub enum ModelErrorKind {
+    /// The underlying stream or file could not be read.
+    Io(io::Error),
+    /// The stream ended before the model was fully read.
+    Truncated,
+    /// The stream contains an unknown classifier kind id.
+    UnknownClassifierKind(i32),
+    /// The stream contains an unknown layer kind id.
+    UnknownLayerKind(i32),
+    /// A count or dimension is negative or too large to be plausible.
+    InvalidCount { field: &'static str, value: i64 },
+    /// A window source refers to a non-existing classifier of the previous hierarchy.
+    InvalidWindowSource { index: i32, num_sources: i32 },
+    /// The stream contains more data after the end of the model.
+    TrailingBytes,
+    /// The model was read successfully, but its cascade structure is inconsistent.
+    InvalidStructure(String),
+}
+
+impl ModelError {
+    #[inline]
+    pub(crate) fn new(kind: ModelErrorKind, offset: Option<u64>) -> Self {
+        ModelError { kind, offset }
+    }
+
+    /// The reason of the error.
+    #[inline]
+    pub fn kind(&self) -> &ModelErrorKind {
+        &self.kind
+    }
+
+    /// Offset in bytes from the start of the stream, at which parsing failed.
+    ///
+    /// Is `None` if the error is not related to a specific position in the stream,
+    /// e.g. when the model file could not be opened or its structure is inconsistent.
+    #[inline]
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ModelErrorKind::Io(ref e) => write!(f, "I/O error: {}", e)?,
+            ModelErrorKind::Truncated => write!(f, "Unexpected end of model data")?,
+            ModelErrorKind::UnknownClassifierKind(id) => {
+                write!(f, "Unexpected classifier kind id: {}", id)?
+            }
+            ModelErrorKind::UnknownLayerKind(id) => write!(f, "Unexpected layer kind id: {}", id)?,
+            ModelErrorKind::InvalidCount { field, value } => {
+                write!(f, "Illegal {}: {}", field, value)?
+            }
+            ModelErrorKind::InvalidWindowSource { index, num_sources } => write!(
+                f,
+                "Illegal window source index: {} (number of sources: {})",
+                index, num_sources
+            )?,
+            ModelErrorKind::TrailingBytes => write!(f, "Unexpected data after the end of model")?,
+            ModelErrorKind::InvalidStructure(ref message) => {
+                write!(f, "Inconsistent model structure: {}", message)?
+            }
+        }
+        match self.offset {
+            Some(offset) => write!(f, " (at byte offset {})", offset),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for ModelError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.kind {
+            ModelErrorKind::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 /// Load model from a file.
 #[inline]
-pub fn load_model(path: &str) -> Result<Model, io::Error> {
-    read_model(BufReader::new(File::open(path)?))
+pub fn load_model(path: &str) -> Result<Model, ModelError> {
+    let file = File::open(path).map_err(|e| ModelError::new(ModelErrorKind::Io(e), None))?;
+    read_model(BufReader::new(file))
 }
 
 /// Load model from any stream or buffer
 #[inline]
-pub fn read_model<R: io::Read>(buf: R) -> Result<Model, io::Error> {
-    ModelReader::new(buf).read()
+pub fn read_model<R: io::Read>(buf: R) -> Result<Model, ModelError> {
+    let model = ModelReader::new(buf).read()?;
+    model.validate()?;
+    Ok(model)
 }
 
-struct ModelReader<R: io::Read> {
+/// Upper bound for any count or dimension read from the model.
+/// The bundled model does not use values larger than a few hundreds.
+pub(crate) const MAX_COUNT: i32 = 1 << 20;
+
+/// Upper bound for the number of elements to pre-allocate, based on a count read from the model.
+/// Larger buffers grow as the data is actually read, so that a corrupted count
+/// in a truncated stream can not make the reader allocate huge amounts of memory.
+pub(crate) const MAX_PREALLOCATED: usize = 1 << 12;
+
+/// Upper bound for the number of weights of a single layer of a neural network.
+pub(crate) const MAX_LAYER_WEIGHTS: usize = 1 << 26;
+
+/// Reader of little-endian model data, which tracks the offset for error reporting.
+pub(crate) struct ModelReader<R: io::Read> {
     reader: R,
+    pub(crate) offset: u64,
 }
 
 impl<R: io::Read> ModelReader<R> {
     #[inline]
-    fn new(reader: R) -> Self {
-        ModelReader { reader }
+    pub(crate) fn new(reader: R) -> Self {
+        ModelReader { reader, offset: 0 }
     }
 
-    pub fn read(mut self) -> Result<Model, io::Error> {
-        let num_hierarchy = self.read_i32()? as usize;
+    pub fn read(mut self) -> Result<Model, ModelError> {
+        let num_hierarchy = self.read_count("number of hierarchies")?;
         let mut classifiers = Vec::new();
-        let mut hierarchy_sizes = Vec::with_capacity(num_hierarchy);
+        let mut hierarchy_sizes = Vec::with_capacity(cmp::min(num_hierarchy, MAX_PREALLOCATED));
         let mut num_stages = Vec::new();
         let mut wnd_src_id = Vec::new();
 
-        for _ in 0..num_hierarchy {
-            let hierarchy_size = self.read_i32()?;
-            hierarchy_sizes.push(hierarchy_size);
+        for i in 0..num_hierarchy {
+            let hierarchy_size = self.read_count("hierarchy size")?;
+            hierarchy_sizes.push(hierarchy_size as i32);
 
             for _ in 0..hierarchy_size {
-                let num_stage = self.read_i32()?;
-                num_stages.push(num_stage);
+                let num_stage = self.read_count("number of stages")?;
+                num_stages.push(num_stage as i32);
 
                 for _ in 0..num_stage {
+                    let offset = self.offset;
                     let classifier_kind_id = self.read_i32()?;
-                    let classifier_kind = ClassifierKind::from(classifier_kind_id);
-
-                    match classifier_kind {
-                        Some(ref classifier_kind) => {
-                            classifiers.push(self.create_classifier(classifier_kind)?);
-                        }
-                        None => panic!("Unexpected classifier kind id: {}", classifier_kind_id),
-                    };
+                    let classifier_kind =
+                        ClassifierKind::from(classifier_kind_id).ok_or_else(|| {
+                            ModelError::new(
+                                ModelErrorKind::UnknownClassifierKind(classifier_kind_id),
+                                Some(offset),
+                            )
+                        })?;
+                    classifiers.push(self.create_classifier(&classifier_kind)?);
                 }
 
+                // window sources refer to the classifiers of the previous hierarchy
+                let num_sources = if i > 0 { hierarchy_sizes[i - 1] } else { 0 };
+                // the first hierarchy has no window sources, which is denoted by a negative count
+                let offset = self.offset;
                 let num_wnd_src = self.read_i32()?;
-                let mut num_wnd_vec;
-                if num_wnd_src > 0 {
-                    num_wnd_vec = Vec::with_capacity(num_wnd_src as usize);
-                    for _ in 0..num_wnd_src {
-                        num_wnd_vec.push(self.read_i32()?);
+                if num_wnd_src > MAX_COUNT {
+                    return Err(ModelError::new(
+                        ModelErrorKind::InvalidCount {
+                            field: "number of window sources",
+                            value: i64::from(num_wnd_src),
+                        },
+                        Some(offset),
+                    ));
+                }
+                let num_wnd_src = cmp::max(num_wnd_src, 0) as usize;
+                let mut num_wnd_vec = Vec::with_capacity(cmp::min(num_wnd_src, MAX_PREALLOCATED));
+                for _ in 0..num_wnd_src {
+                    let offset = self.offset;
+                    let index = self.read_i32()?;
+                    if index < 0 || index >= num_sources {
+                        return Err(ModelError::new(
+                            ModelErrorKind::InvalidWindowSource { index, num_sources },
+                            Some(offset),
+                        ));
                     }
-                } else {
-                    num_wnd_vec = Vec::new();
+                    num_wnd_vec.push(index);
                 }
                 wnd_src_id.push(num_wnd_vec);
             }
         }
 
+        self.read_end()?;
+
         Ok(Model {
             classifiers,
             wnd_src_id,
             hierarchy_sizes,
             num_stages,
+            calibration: None,
+            branch_yaws: None,
         })
     }
 
     fn create_classifier(
         &mut self,
         classifier_kind: &ClassifierKind,
-    ) -> Result<Classifier, io::Error> {
+    ) -> Result<Classifier, ModelError> {
         match *classifier_kind {
             ClassifierKind::LabBoosted => {
                 let mut classifier = LabBoostedClassifier::new();
@@ -150,9 +429,9 @@ impl<R: io::Read> ModelReader<R> {
     fn read_lab_boosted_model(
         &mut self,
         classifier: &mut LabBoostedClassifier,
-    ) -> Result<(), io::Error> {
-        let num_base_classifier = self.read_i32()?;
-        let num_bin = self.read_i32()?;
+    ) -> Result<(), ModelError> {
+        let num_base_classifier = self.read_count("number of base classifiers")?;
+        let num_bin = self.read_count("number of bins")?;
 
         for _ in 0..num_base_classifier {
             let x = self.read_i32()?;
@@ -160,25 +439,29 @@ impl<R: io::Read> ModelReader<R> {
             classifier.add_feature(x, y);
         }
 
-        let mut thresh: Vec<f32> = Vec::with_capacity(num_base_classifier as usize);
+        let mut thresh: Vec<f32> =
+            Vec::with_capacity(cmp::min(num_base_classifier, MAX_PREALLOCATED));
         for _ in 0..num_base_classifier {
             thresh.push(self.read_f32()?);
         }
 
-        for i in 0..num_base_classifier {
-            let mut weights: Vec<f32> = Vec::with_capacity(num_bin as usize + 1);
-            for _ in 0..weights.capacity() {
+        for thresh in thresh {
+            let mut weights: Vec<f32> = Vec::with_capacity(cmp::min(num_bin + 1, MAX_PREALLOCATED));
+            for _ in 0..=num_bin {
                 weights.push(self.read_f32()?);
             }
-            classifier.add_base_classifier(weights, thresh[i as usize]);
+            classifier.add_base_classifier(weights, thresh);
         }
 
         Ok(())
     }
 
-    fn read_surf_mlp_model(&mut self, classifier: &mut SurfMlpClassifier) -> Result<(), io::Error> {
-        let num_layer = self.read_i32()?;
-        let num_feat = self.read_i32()?;
+    fn read_surf_mlp_model(
+        &mut self,
+        classifier: &mut SurfMlpClassifier,
+    ) -> Result<(), ModelError> {
+        let num_layer = self.read_count("number of layers")?;
+        let num_feat = self.read_count("number of features")?;
 
         for _ in 0..num_feat {
             classifier.add_feature_id(self.read_i32()?);
@@ -186,30 +469,37 @@ impl<R: io::Read> ModelReader<R> {
 
         classifier.set_threshold(self.read_f32()?);
 
-        let mut input_dim = self.read_i32()?;
+        let mut input_dim = self.read_count("layer input dimension")?;
         for i in 1..num_layer {
-            let output_dim = self.read_i32()?;
+            let offset = self.offset;
+            let output_dim = self.read_count("layer output dimension")?;
 
             let weights_count = input_dim * output_dim;
-            let mut weights: Vec<f32> = Vec::with_capacity(weights_count as usize);
+            if weights_count > MAX_COUNT as usize {
+                return Err(ModelError::new(
+                    ModelErrorKind::InvalidCount {
+                        field: "number of layer weights",
+                        value: weights_count as i64,
+                    },
+                    Some(offset),
+                ));
+            }
+
+            let mut weights: Vec<f32> =
+                Vec::with_capacity(cmp::min(weights_count, MAX_PREALLOCATED));
             for _ in 0..weights_count {
                 weights.push(self.read_f32()?);
             }
 
-            let mut biases: Vec<f32> = Vec::with_capacity(output_dim as usize);
+            let mut biases: Vec<f32> = Vec::with_capacity(cmp::min(output_dim, MAX_PREALLOCATED));
             for _ in 0..output_dim {
                 biases.push(self.read_f32()?);
             }
 
             if i == num_layer - 1 {
-                classifier.add_output_layer(
-                    input_dim as usize,
-                    output_dim as usize,
-                    weights,
-                    biases,
-                );
+                classifier.add_output_layer(input_dim, output_dim, weights, biases);
             } else {
-                classifier.add_layer(input_dim as usize, output_dim as usize, weights, biases);
+                classifier.add_layer(input_dim, output_dim, weights, biases);
             }
 
             input_dim = output_dim;
@@ -218,13 +508,250 @@ impl<R: io::Read> ModelReader<R> {
         Ok(())
     }
 
+    /// Read a non-negative count, that is not larger than `MAX_COUNT`.
+    pub(crate) fn read_count(&mut self, field: &'static str) -> Result<usize, ModelError> {
+        let offset = self.offset;
+        let value = self.read_i32()?;
+        if !(0..=MAX_COUNT).contains(&value) {
+            return Err(ModelError::new(
+                ModelErrorKind::InvalidCount {
+                    field,
+                    value: i64::from(value),
+                },
+                Some(offset),
+            ));
+        }
+        Ok(value as usize)
+    }
+
+    /// Read `count` floats, where `count` was read from the model as `field`
+    /// and must not exceed `max_count`.
+    pub(crate) fn read_f32s(
+        &mut self,
+        field: &'static str,
+        count: usize,
+        max_count: usize,
+    ) -> Result<Vec<f32>, ModelError> {
+        if count > max_count {
+            return Err(ModelError::new(
+                ModelErrorKind::InvalidCount {
+                    field,
+                    value: count as i64,
+                },
+                Some(self.offset),
+            ));
+        }
+        let mut values = Vec::with_capacity(cmp::min(count, MAX_PREALLOCATED));
+        for _ in 0..count {
+            values.push(self.read_f32()?);
+        }
+        Ok(values)
+    }
+
+    pub(crate) fn read_end(&mut self) -> Result<(), ModelError> {
+        let mut buf = [0u8; 1];
+        loop {
+            return match self.reader.read(&mut buf) {
+                Ok(0) => Ok(()),
+                Ok(_) => Err(ModelError::new(
+                    ModelErrorKind::TrailingBytes,
+                    Some(self.offset),
+                )),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(self.error(e)),
+            };
+        }
+    }
+
     #[inline]
-    fn read_i32(&mut self) -> Result<i32, io::Error> {
-        self.reader.read_i32::<LittleEndian>()
+    pub(crate) fn read_i32(&mut self) -> Result<i32, ModelError> {
+        let value = self
+            .reader
+            .read_i32::<LittleEndian>()
+            .map_err(|e| self.error(e))?;
+        self.offset += 4;
+        Ok(value)
     }
 
     #[inline]
-    fn read_f32(&mut self) -> Result<f32, io::Error> {
-        self.reader.read_f32::<LittleEndian>()
+    pub(crate) fn read_f32(&mut self) -> Result<f32, ModelError> {
+        let value = self
+            .reader
+            .read_f32::<LittleEndian>()
+            .map_err(|e| self.error(e))?;
+        self.offset += 4;
+        Ok(value)
+    }
+
+    fn error(&self, e: io::Error) -> ModelError {
+        let kind = if e.kind() == io::ErrorKind::UnexpectedEof {
+            ModelErrorKind::Truncated
+        } else {
+            ModelErrorKind::Io(e)
+        };
+        ModelError::new(kind, Some(self.offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_model, ModelErrorKind};
+    use std::fs;
+
+    const MODEL_PATH: &str = "model/seeta_fd_frontal_v1.0.bin";
+
+    fn to_bytes(values: &[i32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    /// LAB boosted classifier with 10 base classifiers and zero weights.
+    fn lab_boosted_classifier() -> Vec<i32> {
+        let mut values = vec![0, 10, 255];
+        values.resize(values.len() + 10 * 2 + 10 + 10 * 256, 0);
+        values
+    }
+
+    /// SURF MLP classifier with a single feature and zero weights.
+    fn surf_mlp_classifier(hidden_dim: i32, output_dim: i32) -> Vec<i32> {
+        let mut values = vec![1, 3, 1, 1, 0, 32, hidden_dim];
+        values.resize(values.len() + (32 * hidden_dim + hidden_dim) as usize, 0);
+        values.push(output_dim);
+        values.resize(
+            values.len() + (hidden_dim * output_dim + output_dim) as usize,
+            0,
+        );
+        values
+    }
+
+    fn two_hierarchy_model(first: Vec<i32>, second: Vec<i32>, wnd_src: &[i32]) -> Vec<u8> {
+        let mut values = vec![2, 1, 1];
+        values.extend(first);
+        values.extend(&[-1, 1, 1]);
+        values.extend(second);
+        values.push(wnd_src.len() as i32);
+        values.extend(wnd_src);
+        to_bytes(&values)
+    }
+
+    fn assert_invalid_structure(data: &[u8], expected_message: &str) {
+        let err = read_model(data).err().unwrap();
+        match err.kind() {
+            ModelErrorKind::InvalidStructure(message) => {
+                assert!(message.contains(expected_message), "{}", message)
+            }
+            kind => panic!("Unexpected error kind: {:?}", kind),
+        }
+        assert_eq!(None, err.offset());
+    }
+
+    #[test]
+    fn test_read_bundled_model() {
+        let model = read_model(&fs::read(MODEL_PATH).unwrap()[..]).unwrap();
+        assert_eq!(3, model.get_hierarchy_count());
+    }
+
+    #[test]
+    fn test_with_branch_yaws() {
+        let model = read_model(&fs::read(MODEL_PATH).unwrap()[..]).unwrap();
+        assert_eq!(None, model.branch_yaw(0));
+
+        let err = model.clone().with_branch_yaws(vec![0.0]).err().unwrap();
+        assert!(matches!(err.kind(), ModelErrorKind::InvalidStructure(_)));
+
+        let model = model.with_branch_yaws(vec![-30.0, 0.0, 30.0]).unwrap();
+        assert_eq!(Some(30.0), model.branch_yaw(2));
+    }
+
+    #[test]
+    fn test_read_truncated_model() {
+        let data = fs::read(MODEL_PATH).unwrap();
+        let err = read_model(&data[..1000]).err().unwrap();
+        assert!(matches!(err.kind(), ModelErrorKind::Truncated));
+        assert_eq!(Some(1000), err.offset());
+    }
+
+    #[test]
+    fn test_read_model_with_trailing_bytes() {
+        let mut data = fs::read(MODEL_PATH).unwrap();
+        let len = data.len() as u64;
+        data.push(0);
+        let err = read_model(&data[..]).err().unwrap();
+        assert!(matches!(err.kind(), ModelErrorKind::TrailingBytes));
+        assert_eq!(Some(len), err.offset());
+    }
+
+    #[test]
+    fn test_read_model_with_unknown_classifier_kind() {
+        let data = to_bytes(&[1, 1, 1, 7]);
+        let err = read_model(&data[..]).err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            ModelErrorKind::UnknownClassifierKind(7)
+        ));
+        assert_eq!(Some(12), err.offset());
+    }
+
+    #[test]
+    fn test_read_model_with_negative_count() {
+        let data = to_bytes(&[1, -5]);
+        let err = read_model(&data[..]).err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            ModelErrorKind::InvalidCount { value: -5, .. }
+        ));
+        assert_eq!(Some(4), err.offset());
+    }
+
+    #[test]
+    fn test_read_model_with_implausible_count() {
+        let data = to_bytes(&[i32::MAX]);
+        let err = read_model(&data[..]).err().unwrap();
+        assert!(matches!(err.kind(), ModelErrorKind::InvalidCount { .. }));
+        assert_eq!(Some(0), err.offset());
+    }
+
+    #[test]
+    fn test_read_model_with_invalid_window_source() {
+        // two hierarchies with a single classifier without stages each,
+        // the second one refers to a non-existing classifier of the first hierarchy
+        let data = to_bytes(&[2, 1, 0, -1, 1, 0, 1, 3]);
+        let err = read_model(&data[..]).err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            ModelErrorKind::InvalidWindowSource {
+                index: 3,
+                num_sources: 1
+            }
+        ));
+        assert_eq!(Some(28), err.offset());
+    }
+
+    #[test]
+    fn test_validate_consistent_model() {
+        let data = two_hierarchy_model(lab_boosted_classifier(), surf_mlp_classifier(8, 4), &[0]);
+        assert!(read_model(&data[..]).is_ok());
+    }
+
+    #[test]
+    fn test_validate_empty_model() {
+        assert_invalid_structure(&to_bytes(&[0]), "first hierarchy is empty");
+    }
+
+    #[test]
+    fn test_validate_first_hierarchy_classifier_kind() {
+        let data = two_hierarchy_model(surf_mlp_classifier(8, 4), surf_mlp_classifier(8, 4), &[0]);
+        assert_invalid_structure(&data, "first hierarchy must use LAB boosted classifiers");
+    }
+
+    #[test]
+    fn test_validate_missing_window_sources() {
+        let data = two_hierarchy_model(lab_boosted_classifier(), surf_mlp_classifier(8, 4), &[]);
+        assert_invalid_structure(&data, "has no window sources");
+    }
+
+    #[test]
+    fn test_validate_mlp_output_dimension() {
+        let data = two_hierarchy_model(lab_boosted_classifier(), surf_mlp_classifier(8, 2), &[0]);
+        assert_invalid_structure(&data, "MLP produces 2 output(s), 4 are required");
     }
 }
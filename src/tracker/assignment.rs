@@ -0,0 +1,125 @@
+//! Assignment of tracks to detections with minimal total cost.
+
+/// Assign rows to columns of a cost matrix, so that the total cost is minimal
+/// (Hungarian algorithm with potentials, O(n² m) for n <= m).
+///
+/// `costs` has a row of `num_cols` finite costs for each row. Returns the assigned column
+/// of each row. If there are more rows than columns, some rows remain unassigned.
+pub(super) fn min_cost_assignment(costs: &[Vec<f32>], num_cols: usize) -> Vec<Option<usize>> {
+    let num_rows = costs.len();
+    if num_rows == 0 || num_cols == 0 {
+        return vec![None; num_rows];
+    }
+    if num_rows > num_cols {
+        let transposed: Vec<Vec<f32>> = (0..num_cols)
+            .map(|j| costs.iter().map(|row| row[j]).collect())
+            .collect();
+        let mut assignment = vec![None; num_rows];
+        for (j, i) in min_cost_assignment(&transposed, num_rows)
+            .into_iter()
+            .enumerate()
+        {
+            if let Some(i) = i {
+                assignment[i] = Some(j);
+            }
+        }
+        return assignment;
+    }
+
+    // rows and columns are numbered from 1, column 0 is a virtual column for the row
+    // which is being assigned
+    let mut row_potential = vec![0.0f64; num_rows + 1];
+    let mut col_potential = vec![0.0f64; num_cols + 1];
+    let mut col_row = vec![0usize; num_cols + 1];
+    let mut prev_col = vec![0usize; num_cols + 1];
+
+    for row in 1..=num_rows {
+        col_row[0] = row;
+        let mut col = 0;
+        let mut min_slack = vec![f64::INFINITY; num_cols + 1];
+        let mut visited = vec![false; num_cols + 1];
+
+        // find an augmenting path to a free column
+        loop {
+            visited[col] = true;
+            let current_row = col_row[col];
+            let mut delta = f64::INFINITY;
+            let mut next_col = 0;
+            for j in 1..=num_cols {
+                if visited[j] {
+                    continue;
+                }
+                let slack = f64::from(costs[current_row - 1][j - 1])
+                    - row_potential[current_row]
+                    - col_potential[j];
+                if slack < min_slack[j] {
+                    min_slack[j] = slack;
+                    prev_col[j] = col;
+                }
+                if min_slack[j] < delta {
+                    delta = min_slack[j];
+                    next_col = j;
+                }
+            }
+            for (j, &is_visited) in visited.iter().enumerate() {
+                if is_visited {
+                    row_potential[col_row[j]] += delta;
+                    col_potential[j] -= delta;
+                } else {
+                    min_slack[j] -= delta;
+                }
+            }
+            col = next_col;
+            if col_row[col] == 0 {
+                break;
+            }
+        }
+
+        // flip the assignments along the path
+        while col != 0 {
+            let prev = prev_col[col];
+            col_row[col] = col_row[prev];
+            col = prev;
+        }
+    }
+
+    let mut assignment = vec![None; num_rows];
+    for (j, &row) in col_row.iter().enumerate().skip(1) {
+        if row != 0 {
+            assignment[row - 1] = Some(j - 1);
+        }
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::min_cost_assignment;
+
+    #[test]
+    fn test_square_assignment() {
+        let costs = vec![
+            vec![4.0, 1.0, 3.0],
+            vec![2.0, 0.0, 5.0],
+            vec![3.0, 2.0, 2.0],
+        ];
+        // greedy would assign row 1 to column 1, which is not optimal
+        assert_eq!(
+            vec![Some(1), Some(0), Some(2)],
+            min_cost_assignment(&costs, 3)
+        );
+    }
+
+    #[test]
+    fn test_rectangular_assignment() {
+        let costs = vec![vec![1.0, 0.0, 5.0], vec![0.5, 0.1, 5.0]];
+        assert_eq!(vec![Some(1), Some(0)], min_cost_assignment(&costs, 3));
+
+        let transposed = vec![vec![1.0, 0.5], vec![0.0, 0.1], vec![5.0, 5.0]];
+        assert_eq!(
+            vec![Some(1), Some(0), None],
+            min_cost_assignment(&transposed, 2)
+        );
+        assert_eq!(vec![None, None], min_cost_assignment(&[vec![], vec![]], 0));
+    }
+}
@@ -0,0 +1,482 @@
+//! Tracking of detected faces across the frames of a video with stable ids.
+
+mod assignment;
+
+use self::assignment::min_cost_assignment;
+
+use crate::common::{FaceInfo, ImageData, Rectangle};
+use crate::{Detector, Error};
+
+/// Settings of a [`FaceTracker`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackerConfig {
+    detection_interval: u32,
+    search_margin: f32,
+    iou_thresh: f32,
+    smoothing: f32,
+    max_missed_frames: u32,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        TrackerConfig {
+            detection_interval: 5,
+            search_margin: 0.5,
+            iou_thresh: 0.3,
+            smoothing: 0.5,
+            max_missed_frames: 5,
+        }
+    }
+}
+
+impl TrackerConfig {
+    /// Create a builder, starting from the default configuration.
+    pub fn builder() -> TrackerConfigBuilder {
+        TrackerConfigBuilder {
+            config: TrackerConfig::default(),
+        }
+    }
+
+    /// Number of frames between detections on the whole frame.
+    pub fn detection_interval(&self) -> u32 {
+        self.detection_interval
+    }
+
+    /// Margin around a track, relative to its size, which is searched for the face
+    /// in frames between whole-frame detections.
+    pub fn search_margin(&self) -> f32 {
+        self.search_margin
+    }
+
+    /// Minimum IoU of a detection with the predicted box of a track to continue the track.
+    pub fn iou_thresh(&self) -> f32 {
+        self.iou_thresh
+    }
+
+    /// Weight of a new detection in the filtered box of a track.
+    pub fn smoothing(&self) -> f32 {
+        self.smoothing
+    }
+
+    /// Number of consecutive frames without a detection, after which a track dies.
+    pub fn max_missed_frames(&self) -> u32 {
+        self.max_missed_frames
+    }
+
+    /// Check that all settings lie in their legal ranges.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.detection_interval == 0 {
+            return Err(Error::IllegalDetectionInterval(self.detection_interval));
+        }
+        if !(self.search_margin >= 0.0 && self.search_margin.is_finite()) {
+            return Err(Error::IllegalSearchMargin(self.search_margin));
+        }
+        if !(0.0..=1.0).contains(&self.iou_thresh) {
+            return Err(Error::IllegalIouThreshold(self.iou_thresh));
+        }
+        if !(self.smoothing > 0.0 && self.smoothing <= 1.0) {
+            return Err(Error::IllegalSmoothingFactor(self.smoothing));
+        }
+        Ok(())
+    }
+}
+
+/// Builder of a [`TrackerConfig`].
+///
+/// Settings, which are not set explicitly, keep their default values.
+#[derive(Clone, Debug)]
+pub struct TrackerConfigBuilder {
+    config: TrackerConfig,
+}
+
+impl TrackerConfigBuilder {
+    /// Set the number of frames between detections on the whole frame, at least 1 (the default
+    /// is 5). In the frames between, faces are only searched around the existing tracks,
+    /// so new faces are found with a delay of up to this many frames.
+    pub fn detection_interval(mut self, interval: u32) -> Self {
+        self.config.detection_interval = interval;
+        self
+    }
+
+    /// Set the margin around a track, relative to its size, which is searched for the face
+    /// in frames between whole-frame detections, non-negative (the default is 0.5).
+    /// Faces, which move faster, are lost until the next whole-frame detection.
+    pub fn search_margin(mut self, margin: f32) -> Self {
+        self.config.search_margin = margin;
+        self
+    }
+
+    /// Set the minimum IoU of a detection with the predicted box of a track
+    /// to continue the track, in [0, 1] (the default is 0.3).
+    pub fn iou_thresh(mut self, thresh: f32) -> Self {
+        self.config.iou_thresh = thresh;
+        self
+    }
+
+    /// Set the weight of a new detection in the filtered box of a track, in (0, 1]
+    /// (the default is 0.5). Smaller weights reduce jitter, but make the box follow changes
+    /// of the speed of a face more slowly; 1 disables smoothing.
+    ///
+    /// The box moves with the estimated velocity of the face (an alpha-beta filter),
+    /// which is corrected by each detection with the weight `smoothing² / (2 - smoothing)`.
+    pub fn smoothing(mut self, smoothing: f32) -> Self {
+        self.config.smoothing = smoothing;
+        self
+    }
+
+    /// Set the number of consecutive frames without a detection, after which a track dies
+    /// (the default is 5).
+    pub fn max_missed_frames(mut self, frames: u32) -> Self {
+        self.config.max_missed_frames = frames;
+        self
+    }
+
+    /// Validate the settings and create the configuration.
+    pub fn build(self) -> Result<TrackerConfig, Error> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// A face followed across frames.
+#[derive(Clone, Debug)]
+pub struct Track {
+    id: u64,
+    center: (f32, f32),
+    /// Movement of the center in pixels per frame.
+    velocity: (f32, f32),
+    size: (f32, f32),
+    face: FaceInfo,
+    age: u32,
+    missed_frames: u32,
+}
+
+impl Track {
+    fn new(id: u64, face: FaceInfo) -> Self {
+        let bbox = face.bbox();
+        Track {
+            id,
+            center: bbox.center(),
+            velocity: (0.0, 0.0),
+            size: (bbox.width() as f32, bbox.height() as f32),
+            face,
+            age: 0,
+            missed_frames: 0,
+        }
+    }
+
+    /// Identifier of the track, unique within its tracker.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Filtered bounding box of the face.
+    ///
+    /// If the face was not detected in the latest frame, the box is predicted from
+    /// the velocity of the face.
+    pub fn bbox(&self) -> Rectangle {
+        let (width, height) = (self.size.0.round(), self.size.1.round());
+        Rectangle::new(
+            (self.center.0 - width / 2.0).round() as i32,
+            (self.center.1 - height / 2.0).round() as i32,
+            width as u32,
+            height as u32,
+        )
+    }
+
+    /// The latest detection of the face, without smoothing.
+    #[inline]
+    pub fn face(&self) -> &FaceInfo {
+        &self.face
+    }
+
+    /// Number of frames since the track was born.
+    #[inline]
+    pub fn age(&self) -> u32 {
+        self.age
+    }
+
+    /// Number of consecutive frames, in which the face was not detected.
+    #[inline]
+    pub fn missed_frames(&self) -> u32 {
+        self.missed_frames
+    }
+
+    /// Estimated velocity of the center of the face in pixels per frame.
+    #[inline]
+    pub fn velocity(&self) -> (f32, f32) {
+        self.velocity
+    }
+
+    /// Move the box to the predicted position in the next frame.
+    fn predict(&mut self) {
+        self.center.0 += self.velocity.0;
+        self.center.1 += self.velocity.1;
+    }
+
+    /// Correct the predicted box and the velocity with a detection of the face.
+    fn update(&mut self, face: FaceInfo, smoothing: f32) {
+        let bbox = face.bbox();
+        let (center_x, center_y) = bbox.center();
+        let (width, height) = (bbox.width() as f32, bbox.height() as f32);
+        let residual = (center_x - self.center.0, center_y - self.center.1);
+        let velocity_gain = smoothing * smoothing / (2.0 - smoothing);
+        self.center.0 += smoothing * residual.0;
+        self.center.1 += smoothing * residual.1;
+        self.velocity.0 += velocity_gain * residual.0;
+        self.velocity.1 += velocity_gain * residual.1;
+        let smooth = |old: f32, new: f32| old + smoothing * (new - old);
+        self.size = (smooth(self.size.0, width), smooth(self.size.1, height));
+        self.face = face;
+        self.missed_frames = 0;
+    }
+}
+
+/// Change of the set of tracks of a [`FaceTracker`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TrackEvent {
+    /// A new face was found and is tracked with the given id.
+    Born(u64),
+    /// The face of the track with the given id was not found for too many frames.
+    Died(u64),
+}
+
+/// Tracker of faces in a sequence of video frames.
+///
+/// Every [`detection_interval`](TrackerConfigBuilder::detection_interval) frames, faces are
+/// detected on the whole frame. In between, they are only searched in the neighborhood
+/// of the existing tracks, which is much cheaper. Detections are associated with the boxes of
+/// the tracks, predicted from the velocities of the faces, by maximizing the total IoU of the
+/// pairs, so that each face keeps the id of its track.
+pub struct FaceTracker {
+    detector: Box<dyn Detector>,
+    config: TrackerConfig,
+    tracks: Vec<Track>,
+    next_id: u64,
+    frame_index: u64,
+}
+
+impl FaceTracker {
+    pub fn new(detector: Box<dyn Detector>, config: TrackerConfig) -> Self {
+        FaceTracker {
+            detector,
+            config,
+            tracks: Vec::new(),
+            next_id: 0,
+            frame_index: 0,
+        }
+    }
+
+    /// The tracks alive after the latest frame.
+    #[inline]
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    #[inline]
+    pub fn config(&self) -> &TrackerConfig {
+        &self.config
+    }
+
+    /// The wrapped detector.
+    #[inline]
+    pub fn detector_mut(&mut self) -> &mut dyn Detector {
+        &mut *self.detector
+    }
+
+    /// Forget all tracks, e.g. after a cut in the video. The next frame is
+    /// detected as a whole.
+    pub fn reset(&mut self) {
+        self.tracks.clear();
+        self.frame_index = 0;
+    }
+
+    /// Process the next frame and return the births and deaths of tracks.
+    pub fn update(&mut self, image: &ImageData) -> Result<Vec<TrackEvent>, Error> {
+        let full_detection = self.frame_index % u64::from(self.config.detection_interval) == 0;
+        self.frame_index += 1;
+
+        for track in &mut self.tracks {
+            track.predict();
+        }
+
+        let faces = if full_detection {
+            self.detector.try_detect(image)?
+        } else {
+            let regions: Vec<Rectangle> = self
+                .tracks
+                .iter()
+                .map(|track| {
+                    let bbox = track.bbox();
+                    let margin = self.config.search_margin * bbox.width().max(bbox.height()) as f32;
+                    bbox.expand(margin.round() as u32)
+                })
+                .collect();
+            self.detector.try_detect_in_regions(image, &regions)?
+        };
+
+        let costs: Vec<Vec<f32>> = self
+            .tracks
+            .iter()
+            .map(|track| {
+                let bbox = track.bbox();
+                faces
+                    .iter()
+                    .map(|face| 1.0 - bbox.iou(face.bbox()))
+                    .collect()
+            })
+            .collect();
+        let assignment = min_cost_assignment(&costs, faces.len());
+
+        let mut faces: Vec<Option<FaceInfo>> = faces.into_iter().map(Some).collect();
+        for (track, (costs, assigned)) in self.tracks.iter_mut().zip(costs.iter().zip(assignment)) {
+            track.age += 1;
+            match assigned {
+                Some(j) if 1.0 - costs[j] >= self.config.iou_thresh => {
+                    track.update(faces[j].take().unwrap(), self.config.smoothing);
+                }
+                _ => track.missed_frames += 1,
+            }
+        }
+
+        let mut events = Vec::new();
+        let max_missed_frames = self.config.max_missed_frames;
+        self.tracks.retain(|track| {
+            let alive = track.missed_frames <= max_missed_frames;
+            if !alive {
+                events.push(TrackEvent::Died(track.id));
+            }
+            alive
+        });
+
+        // faces found around existing tracks are duplicates rather than new faces
+        if full_detection {
+            for face in faces.into_iter().flatten() {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.tracks.push(Track::new(id, face));
+                events.push(TrackEvent::Born(id));
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaceTracker, Track, TrackEvent, TrackerConfig};
+    use crate::{create_detector, Error, FaceInfo, ImageData, Rectangle};
+
+    fn create_tracker(config: TrackerConfig) -> FaceTracker {
+        FaceTracker::new(
+            create_detector("model/seeta_fd_frontal_v1.0.bin").unwrap(),
+            config,
+        )
+    }
+
+    #[test]
+    fn test_builder_rejects_illegal_values() {
+        assert_eq!(
+            Err(Error::IllegalDetectionInterval(0)),
+            TrackerConfig::builder().detection_interval(0).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalSearchMargin(-1.0)),
+            TrackerConfig::builder().search_margin(-1.0).build()
+        );
+        assert_eq!(
+            Err(Error::IllegalSmoothingFactor(0.0)),
+            TrackerConfig::builder().smoothing(0.0).build()
+        );
+        assert_eq!(
+            Ok(TrackerConfig::default()),
+            TrackerConfig::builder().build()
+        );
+    }
+
+    #[test]
+    fn test_track_predicts_fast_faces() {
+        let face_at = |x: i32| {
+            let mut face = FaceInfo::new();
+            *face.bbox_mut() = Rectangle::new(x, 100, 60, 60);
+            face
+        };
+
+        // the face moves to the right by 20 pixels per frame
+        let mut track = Track::new(0, face_at(0));
+        let mut min_iou = 1.0f32;
+        for frame in 1..20 {
+            track.predict();
+            let face = face_at(20 * frame);
+            min_iou = min_iou.min(track.bbox().iou(face.bbox()));
+            track.update(face, 0.5);
+        }
+        // a box without velocity would lag behind by 40 pixels (IoU 0.2)
+        assert!(min_iou >= 0.3, "IoU {}", min_iou);
+        assert!((track.velocity().0 - 20.0).abs() < 0.5);
+        assert!(track.velocity().1.abs() < 1e-6);
+
+        track.predict();
+        let bbox = track.bbox();
+        assert!((bbox.x() - 400).abs() <= 1, "{:?}", bbox);
+    }
+
+    #[test]
+    fn test_track_moving_faces() {
+        let image = image::open("assets/test/scientists.jpg")
+            .unwrap()
+            .to_luma8();
+        let (width, height) = image.dimensions();
+        let image = ImageData::new(&image, width, height);
+
+        let config = TrackerConfig::builder()
+            .detection_interval(3)
+            .max_missed_frames(1)
+            .build()
+            .unwrap();
+        let mut tracker = create_tracker(config);
+
+        // all faces are born in the first frame
+        let events = tracker.update(&image.sub_image(0, 0, 700, 360)).unwrap();
+        let ids: Vec<u64> = tracker.tracks().iter().map(|t| t.id()).collect();
+        assert!(!ids.is_empty());
+        assert_eq!(
+            ids.iter()
+                .map(|&id| TrackEvent::Born(id))
+                .collect::<Vec<_>>(),
+            events
+        );
+
+        // the faces move to the left by 3 pixels per frame and keep their tracks,
+        // the whole-frame detection in the last frame may find further faces
+        for frame in 1..4 {
+            let events = tracker
+                .update(&image.sub_image(3 * frame, 0, 700, 360))
+                .unwrap();
+            assert!(events.iter().all(|e| matches!(e, TrackEvent::Born(_))));
+            let tracks = &tracker.tracks()[..ids.len()];
+            for track in tracks {
+                assert_eq!(0, track.missed_frames());
+                assert_eq!(frame, track.age());
+            }
+            let frame_ids: Vec<u64> = tracks.iter().map(|t| t.id()).collect();
+            assert_eq!(ids, frame_ids);
+        }
+
+        // the faces disappear and their tracks die after two frames
+        let ids: Vec<u64> = tracker.tracks().iter().map(|t| t.id()).collect();
+        let blank = vec![128; 700 * 360];
+        let blank = ImageData::new(&blank, 700, 360);
+        assert_eq!(Vec::<TrackEvent>::new(), tracker.update(&blank).unwrap());
+        let events = tracker.update(&blank).unwrap();
+        assert_eq!(
+            ids.iter()
+                .map(|&id| TrackEvent::Died(id))
+                .collect::<Vec<_>>(),
+            events
+        );
+        assert!(tracker.tracks().is_empty());
+    }
+}
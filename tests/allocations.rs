@@ -0,0 +1,120 @@
+//! Checks that repeated single-threaded detection re-uses the buffers of its workspace.
+//!
+//! With more threads, the number of window workspaces in use at the same time depends on
+//! the scheduling, so the pool of window workspaces may still grow after the first image.
+//!
+//! The counting allocator replaces the global allocator of the whole test binary,
+//! so this file holds a single test, which does not run concurrently with others.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use rustface::{
+    create_detector_with_config, load_model, DetectorConfig, DetectorWorkspace, FaceInfo,
+    ImageData, PyramidMode,
+};
+
+/// Allocator, which counts allocations of all threads while it is enabled.
+struct CountingAllocator;
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+fn record_allocation() {
+    if COUNTING.load(Ordering::SeqCst) {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    ALLOCATIONS.store(0, Ordering::SeqCst);
+    COUNTING.store(true, Ordering::SeqCst);
+    let result = f();
+    COUNTING.store(false, Ordering::SeqCst);
+    (result, ALLOCATIONS.load(Ordering::SeqCst))
+}
+
+fn to_tuples(faces: &[FaceInfo]) -> Vec<(i32, i32, u32, u32, f64)> {
+    faces
+        .iter()
+        .map(|face| {
+            let bbox = face.bbox();
+            (
+                bbox.x(),
+                bbox.y(),
+                bbox.width(),
+                bbox.height(),
+                face.score(),
+            )
+        })
+        .collect()
+}
+
+fn assert_reused_workspace_does_not_allocate(mode: PyramidMode) {
+    let model = load_model("model/seeta_fd_frontal_v1.0.bin").unwrap();
+    let config = DetectorConfig::builder()
+        .min_face_size(20)
+        .pyramid_mode(mode)
+        .build()
+        .unwrap();
+    let detector = create_detector_with_config(model, config).unwrap();
+
+    let image = image::open("assets/test/scientists.jpg")
+        .unwrap()
+        .to_luma8();
+    let (width, height) = image.dimensions();
+    let image = ImageData::new(&image, width, height).sub_image(0, 0, 700, 360);
+
+    let run = || {
+        let mut workspace = DetectorWorkspace::new();
+        // the first image allocates the buffers for its size
+        let expected = detector.detect_with_workspace(&mut workspace, &image);
+        assert!(!expected.is_empty());
+
+        for _ in 0..3 {
+            // the returned faces are the only allocation
+            let (faces, allocations) =
+                count_allocations(|| detector.detect_with_workspace(&mut workspace, &image));
+            assert!(allocations <= 1, "{} allocations", allocations);
+            assert_eq!(to_tuples(&expected), to_tuples(&faces));
+        }
+    };
+
+    // Jobs, which enter a pool from outside, are queued in blocks allocated by the pool,
+    // and a single thread checks out the same window workspaces in each detection.
+    #[cfg(feature = "rayon")]
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap()
+        .install(run);
+
+    #[cfg(not(feature = "rayon"))]
+    run();
+}
+
+#[test]
+fn test_reused_workspace_does_not_allocate() {
+    for mode in [PyramidMode::Bilinear, PyramidMode::AreaAveraging] {
+        assert_reused_workspace_does_not_allocate(mode);
+    }
+}